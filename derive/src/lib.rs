@@ -0,0 +1,324 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse::{ParseStream, Parser},
+    parse_macro_input,
+    punctuated::Punctuated,
+    Attribute, Field, Fields, Ident, ItemEnum, Meta, Token, Type,
+};
+
+// Turns an enum of the requests a node takes into its Payload type, adding the
+// `FooOk` reply of each request marked with `#[ok]`, and a `<Enum>Handler`
+// trait with one method per request and a `dispatch` calling them:
+//
+//     #[payload]
+//     #[derive(Debug, Clone)]
+//     enum Payload {
+//         #[ok(echo: String)]
+//         Echo { echo: String },
+//         #[ok]
+//         Topology { topology: HashMap<String, Vec<String>> },
+//         Gossip { seen: HashSet<usize> },
+//     }
+//
+// `fn echo(&mut self, src, output, echo: String) -> anyhow::Result<String>`
+// returns the fields of `EchoOk`, which `dispatch` sends back to `src`.
+// Requests without `#[ok]` get no reply. Requests a node doesn't implement a
+// handler for are answered with a `not-supported` error by main_loop, other
+// messages it has no handler for (eg: gossip without a msg_id) are ignored.
+#[proc_macro_attribute]
+pub fn payload(args: TokenStream, item: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "#[payload] takes no arguments",
+        )
+        .into_compile_error()
+        .into();
+    }
+    let item = parse_macro_input!(item as ItemEnum);
+    expand(item)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct Request {
+    variant: Ident,
+    method: Ident,
+    fields: Vec<(Ident, Type)>,
+    // Fields of the reply, if the request gets one
+    reply: Option<Vec<Field>>,
+}
+
+fn expand(mut item: ItemEnum) -> syn::Result<TokenStream2> {
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &item.generics,
+            "#[payload] enums can't be generic",
+        ));
+    }
+
+    let mut requests = Vec::new();
+    for variant in &mut item.variants {
+        let fields = match &variant.fields {
+            Fields::Named(fields) => fields
+                .named
+                .iter()
+                .map(|f| (f.ident.clone().expect("named field"), f.ty.clone()))
+                .collect(),
+            Fields::Unit => Vec::new(),
+            Fields::Unnamed(_) => {
+                return Err(syn::Error::new_spanned(
+                    &variant.fields,
+                    "payload variants need named fields, they become the message body",
+                ))
+            }
+        };
+
+        let mut reply = None;
+        let mut attrs = Vec::new();
+        for attr in variant.attrs.drain(..) {
+            if attr.path().is_ident("ok") {
+                reply = Some(reply_fields(&attr)?);
+            } else {
+                attrs.push(attr);
+            }
+        }
+        variant.attrs = attrs;
+
+        requests.push(Request {
+            method: format_ident!("{}", snake_case(&variant.ident.to_string())),
+            variant: variant.ident.clone(),
+            fields,
+            reply,
+        });
+    }
+
+    let name = &item.ident;
+    let vis = &item.vis;
+    let attrs = &item.attrs;
+    let variants = item.variants.iter();
+    let replies = requests.iter().filter_map(|r| {
+        let fields = r.reply.as_ref()?;
+        let ok = format_ident!("{}Ok", r.variant);
+        Some(match fields.is_empty() {
+            true => quote! { #ok },
+            false => quote! { #ok { #(#fields),* } },
+        })
+    });
+
+    let handler = format_ident!("{}Handler", name);
+    let methods = requests.iter().map(|r| {
+        let method = &r.method;
+        let args = r.fields.iter().map(|(field, ty)| quote! { #field: #ty });
+        let returns = match r.reply.as_deref() {
+            None | Some([]) => quote! { () },
+            Some([field]) => {
+                let ty = &field.ty;
+                quote! { #ty }
+            }
+            Some(fields) => {
+                let types = fields.iter().map(|f| &f.ty);
+                quote! { (#(#types),*) }
+            }
+        };
+        let text = format!("{} is not supported", r.method);
+        quote! {
+            #[allow(unused_variables, clippy::too_many_arguments)]
+            fn #method(
+                &mut self,
+                src: &str,
+                output: &mut ::distributed_systems::Output,
+                #(#args),*
+            ) -> ::anyhow::Result<#returns> {
+                Err(::distributed_systems::MaelstromError::new(
+                    ::distributed_systems::ErrorCode::NotSupported,
+                    #text,
+                )
+                .into())
+            }
+        }
+    });
+
+    let arms = requests.iter().map(|r| {
+        let variant = &r.variant;
+        let method = &r.method;
+        let fields: Vec<_> = r.fields.iter().map(|(field, _)| field).collect();
+        let call = quote! {
+            match self.#method(&src, output, #(#fields),*) {
+                Ok(returned) => returned,
+                Err(e) if request.is_none() && unsupported(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        };
+
+        let Some(reply) = &r.reply else {
+            return quote! {
+                #name::#variant { #(#fields),* } => {
+                    #call;
+                    Ok(())
+                }
+            };
+        };
+        let ok = format_ident!("{}Ok", variant);
+        let names: Vec<_> = reply.iter().map(|f| &f.ident).collect();
+        let bind = match names.as_slice() {
+            [] => quote! { () },
+            [name] => quote! { #name },
+            names => quote! { (#(#names),*) },
+        };
+        quote! {
+            #name::#variant { #(#fields),* } => {
+                let #bind = #call;
+                ::distributed_systems::Message {
+                    src: dst,
+                    dst: src,
+                    body: ::distributed_systems::Body {
+                        id: self.next_msg_id(),
+                        in_reply_to: request,
+                        clock: ::core::default::Default::default(),
+                        payload: #name::#ok { #(#names),* },
+                    },
+                }
+                .send(&mut *output)
+                .context(concat!("reply to ", stringify!(#method)))
+            }
+        }
+    });
+    let ignored = requests.iter().filter(|r| r.reply.is_some()).map(|r| {
+        let ok = format_ident!("{}Ok", r.variant);
+        quote! { #name::#ok { .. } => Ok(()) }
+    });
+
+    Ok(quote! {
+        #(#attrs)*
+        #[derive(::serde::Serialize, ::serde::Deserialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        #vis enum #name {
+            #(#variants,)*
+            #(#replies,)*
+        }
+
+        #vis trait #handler {
+            // msg_id of the next reply, replies go without one by default
+            fn next_msg_id(&mut self) -> Option<usize> {
+                None
+            }
+
+            #(#methods)*
+
+            // Hands `msg` to the handler of its type and sends the reply, if
+            // the request gets one. Replies to our own requests are ignored.
+            #[allow(unused_variables)]
+            fn dispatch(
+                &mut self,
+                msg: ::distributed_systems::Message<#name>,
+                output: &mut ::distributed_systems::Output,
+            ) -> ::anyhow::Result<()> {
+                use ::anyhow::Context;
+
+                let ::distributed_systems::Message { src, dst, body } = msg;
+                let request = body.id;
+                // Nobody is waiting to hear a message that isn't a request went unhandled
+                let unsupported = |e: &::anyhow::Error| {
+                    e.downcast_ref::<::distributed_systems::MaelstromError>()
+                        .is_some_and(|e| e.code == ::distributed_systems::ErrorCode::NotSupported)
+                };
+                match body.payload {
+                    #(#arms)*
+                    #(#ignored,)*
+                }
+            }
+        }
+    })
+}
+
+// `#[ok]` or `#[ok(field: Type, ...)]`
+fn reply_fields(attr: &Attribute) -> syn::Result<Vec<Field>> {
+    match &attr.meta {
+        Meta::Path(_) => Ok(Vec::new()),
+        Meta::List(list) => {
+            let parser = |input: ParseStream| {
+                Punctuated::<Field, Token![,]>::parse_terminated_with(input, Field::parse_named)
+            };
+            Ok(parser.parse2(list.tokens.clone())?.into_iter().collect())
+        }
+        Meta::NameValue(_) => Err(syn::Error::new_spanned(
+            attr,
+            "expected #[ok] or #[ok(field: Type, ...)]",
+        )),
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use syn::parse_quote;
+
+    fn error(item: ItemEnum) -> String {
+        expand(item).expect_err("expansion fails").to_string()
+    }
+
+    #[test]
+    fn expansion_adds_replies_and_a_handler_trait() {
+        let expanded = expand(parse_quote! {
+            enum Payload {
+                #[ok(echo: String)]
+                Echo { echo: String },
+                #[ok]
+                Read,
+                Gossip { seen: Vec<usize> },
+            }
+        })
+        .expect("expands");
+        let file: syn::File = syn::parse2(expanded).expect("expansion parses");
+
+        let syn::Item::Enum(payload) = &file.items[0] else {
+            panic!("payload enum comes first");
+        };
+        let variants: Vec<_> = payload
+            .variants
+            .iter()
+            .map(|v| v.ident.to_string())
+            .collect();
+        assert_eq!(variants, ["Echo", "Read", "Gossip", "EchoOk", "ReadOk"]);
+        assert!(payload.variants.iter().all(|v| v.attrs.is_empty()));
+
+        let syn::Item::Trait(handler) = &file.items[1] else {
+            panic!("handler trait comes second");
+        };
+        assert_eq!(handler.ident, "PayloadHandler");
+        let methods: Vec<_> = handler
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                syn::TraitItem::Fn(f) => Some(f.sig.ident.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            methods,
+            ["next_msg_id", "echo", "read", "gossip", "dispatch"]
+        );
+    }
+
+    #[test]
+    fn unsupported_enums_are_rejected() {
+        assert!(error(parse_quote! { enum P<T> { A { a: T } } }).contains("can't be generic"));
+        assert!(error(parse_quote! { enum P { A(usize) } }).contains("named fields"));
+        assert!(error(parse_quote! { enum P { #[ok = "a"] A } }).contains("expected #[ok]"));
+    }
+}
@@ -0,0 +1,515 @@
+use crate::{
+    clock::{self, NodeClock},
+    connect, error,
+    journal::{Journal, Replay, Replayed},
+    metrics, next_input,
+    queue::Popped,
+    reply_not_supported, step_failed,
+    trace::{self, NodeTracer},
+    transport_from_env, AsyncKvClient, Backpressure, Clocks, Connection, Event, Inbox, Init, Input,
+    KvService, Message, Next, Output, Reader, RpcClient, SharedOutput, Transport,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    future::Future,
+    sync::{mpsc::Sender, Arc, Mutex},
+    time::Duration,
+};
+use tokio::task::JoinSet;
+
+// Handle given to async nodes for everything that talks to the outside world
+pub struct AsyncContext<Payload, InjectedPayload = ()> {
+    node_id: String,
+    output: SharedOutput,
+    rpc: RpcClient,
+    inject: Sender<Event<Payload, InjectedPayload>>,
+}
+
+// Derive would needlessly require the payloads to be Clone
+impl<Payload, InjectedPayload> Clone for AsyncContext<Payload, InjectedPayload> {
+    fn clone(&self) -> Self {
+        Self {
+            node_id: self.node_id.clone(),
+            output: self.output.clone(),
+            rpc: self.rpc.clone(),
+            inject: self.inject.clone(),
+        }
+    }
+}
+
+impl<Payload, InjectedPayload> AsyncContext<Payload, InjectedPayload> {
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn output(&self) -> &SharedOutput {
+        &self.output
+    }
+
+    pub fn rpc(&self) -> &RpcClient {
+        &self.rpc
+    }
+
+    pub fn send<P>(&self, msg: &Message<P>) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        self.output.send(msg)
+    }
+
+    pub fn inject(&self, event: Event<Payload, InjectedPayload>) -> anyhow::Result<()> {
+        self.inject
+            .send(event)
+            .map_err(|_| anyhow::anyhow!("event loop has shut down"))
+    }
+
+    // Sends a request and awaits its reply without holding up other handlers
+    pub async fn call<P, R>(
+        &self,
+        dst: impl Into<String>,
+        payload: P,
+        timeout: Duration,
+    ) -> anyhow::Result<Message<R>>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let pending = self
+            .output
+            .with(|output| self.rpc.call_async(dst, payload, output))?;
+        pending.wait(timeout).await
+    }
+
+    pub fn kv(&self, service: KvService) -> AsyncKvClient {
+        AsyncKvClient::new(self.rpc.clone(), service, self.output.clone())
+    }
+}
+
+// Async counterpart of `Node`: every event is handled on its own task, so a
+// handler awaiting a reply or a timer doesn't stop the node from making progress.
+// State shared between handlers needs interior mutability.
+pub trait AsyncNode<S, Payload, InjectedPayload = ()>: Sized + Send + Sync + 'static {
+    fn from_init(
+        state: S,
+        init: Init,
+        ctx: AsyncContext<Payload, InjectedPayload>,
+    ) -> anyhow::Result<Self>;
+
+    fn step(
+        self: Arc<Self>,
+        input: Event<Payload, InjectedPayload>,
+        ctx: AsyncContext<Payload, InjectedPayload>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    // See `Node::clocks`
+    fn clocks(&self) -> Option<Clocks> {
+        None
+    }
+
+    // Bounds the events waiting for a handler, and the handlers in flight
+    fn backpressure(&self) -> Option<Backpressure> {
+        None
+    }
+
+    // See `Node::on_unknown`, handled on its own task like any event
+    fn on_unknown(
+        self: Arc<Self>,
+        raw: Value,
+        ctx: AsyncContext<Payload, InjectedPayload>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        let result = ctx
+            .output()
+            .with(|output| reply_not_supported(&raw, output));
+        async { result }
+    }
+
+    // Runs once after `Event::EOF` has been stepped, right before the loop returns
+    fn on_shutdown(
+        self: Arc<Self>,
+        _ctx: AsyncContext<Payload, InjectedPayload>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+// Same protocol as `main_loop`, driven by tokio. Call it from `#[tokio::main]`.
+pub async fn async_main_loop<S, N, P, IP>(init_state: S) -> anyhow::Result<()>
+where
+    S: 'static,
+    P: Serialize + DeserializeOwned + Send + 'static,
+    N: AsyncNode<S, P, IP>,
+    IP: Serialize + DeserializeOwned + Send + 'static,
+{
+    if let Ok(path) = std::env::var("NODE_REPLAY") {
+        let realtime = std::env::var("NODE_REPLAY_REALTIME").is_ok_and(|v| v == "1");
+        return replay::<S, N, P, IP>(init_state, &path, realtime).await;
+    }
+    async_main_loop_with::<S, N, P, IP>(init_state, transport_from_env()?).await
+}
+
+// Replays a journal like main_loop does (see `journal::replay`), running one
+// handler at a time in the order their events were journaled
+async fn replay<S, N, P, IP>(init_state: S, path: &str, realtime: bool) -> anyhow::Result<()>
+where
+    S: 'static,
+    P: Serialize + DeserializeOwned + Send + 'static,
+    N: AsyncNode<S, P, IP>,
+    IP: Serialize + DeserializeOwned + Send + 'static,
+{
+    let (init, replay) = Replay::<P, IP>::open(path, realtime)?;
+    let tracer = NodeTracer::new(&init.node_id);
+    let output = SharedOutput::new(std::io::stdout());
+    let (inject_tx, inject_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || for _ in inject_rx {});
+
+    let rpc = RpcClient::new(init.node_id.clone());
+    let ctx = AsyncContext {
+        node_id: init.node_id.clone(),
+        output: output.clone(),
+        rpc: rpc.clone(),
+        inject: inject_tx,
+    };
+    let node: Arc<N> = Arc::new(
+        AsyncNode::from_init(init_state, init, ctx.clone())
+            .context("node initialization failed")?,
+    );
+    let clock = clock::NodeClock::new(&ctx.node_id, node.clocks());
+    let replay = Arc::new(Mutex::new(replay));
+    Replay::serve(&replay, rpc);
+
+    let mut replayed = 0;
+    loop {
+        // Not held while stepping, waiting on a reply reads ahead
+        let next = replay.lock().expect("replay poisoned").next()?;
+        let Some(next) = next else {
+            break;
+        };
+        replayed += 1;
+        let eof = match next {
+            Replayed::Event(event) => {
+                let eof = matches!(event, Event::EOF);
+                let step = step::<S, N, P, IP>(node.clone(), event, ctx.clone());
+                entered(clock.clone(), tracer.clone(), step).await?;
+                eof
+            }
+            Replayed::Unknown(raw) => {
+                let unknown = unknown::<S, N, P, IP>(node.clone(), raw, ctx.clone());
+                entered(clock.clone(), tracer.clone(), unknown).await?;
+                false
+            }
+        };
+        {
+            let _tracer = trace::enter(Some(tracer.clone()));
+            output.flush().context("flush output")?;
+        }
+        if eof {
+            break;
+        }
+    }
+
+    entered(clock, tracer.clone(), node.on_shutdown(ctx))
+        .await
+        .context("node shutdown failed")?;
+    // Nothing is awaited from here on
+    let _tracer = trace::enter(Some(tracer));
+    output.flush().context("flush output")?;
+    trace::info(
+        "replayed",
+        serde_json::json!({"journal": path, "events": replayed}),
+    );
+    Ok(())
+}
+
+// async_main_loop over any transport. Input is read and queued the way
+// main_loop does it, handlers run on tasks as their events come off the queue.
+pub async fn async_main_loop_with<S, N, P, IP>(
+    init_state: S,
+    transport: Box<dyn Transport>,
+) -> anyhow::Result<()>
+where
+    S: 'static,
+    P: Serialize + DeserializeOwned + Send + 'static,
+    N: AsyncNode<S, P, IP>,
+    IP: Serialize + Send + 'static,
+{
+    let Connection {
+        input,
+        output,
+        codec,
+        metrics,
+        tracer,
+        init,
+        init_reply,
+    } = connect(transport)?;
+    let output = SharedOutput::from(
+        Output::new(output)
+            .with_codec(codec)
+            .with_metrics(metrics.clone()),
+    );
+    let journal = Journal::from_env(&init)?.map(Arc::new);
+
+    let (inject_tx, inject_rx) = std::sync::mpsc::channel();
+    let rpc = RpcClient::new(init.node_id.clone());
+    metrics.watch_rpc(rpc.clone());
+    let ctx = AsyncContext {
+        node_id: init.node_id.clone(),
+        output: output.clone(),
+        rpc: rpc.clone(),
+        inject: inject_tx,
+    };
+
+    let node: Arc<N> = Arc::new(
+        AsyncNode::from_init(init_state, init, ctx.clone())
+            .context("node initialization failed")?,
+    );
+
+    {
+        let _tracer = trace::enter(Some(tracer.clone()));
+        output
+            .send(&init_reply)
+            .context("Serialize response to init")?;
+    }
+    let clock = clock::NodeClock::new(&init_reply.src, node.clocks());
+
+    // Handlers in flight count as queued, so backpressure bounds them too
+    let backpressure = node.backpressure();
+    let in_flight = backpressure.map_or(usize::MAX, |b| b.capacity.max(1));
+    let reader = Reader {
+        input,
+        codec,
+        metrics: metrics.clone(),
+        tracer: tracer.clone(),
+    };
+    let inbox = Inbox::start(
+        reader,
+        Some(rpc.clone()),
+        journal.clone(),
+        backpressure,
+        inject_rx,
+    );
+
+    // The queue is waited on by a thread of its own, as tokio's blocking pool
+    // would keep the runtime from shutting down while it waits
+    let (popped_tx, mut popped_rx) = tokio::sync::mpsc::channel(1);
+    let queue = inbox.queue.clone();
+    std::thread::spawn(move || loop {
+        let popped = queue.pop();
+        let eof = matches!(popped, Popped::Event(Input::Event(Event::EOF)));
+        if popped_tx.blocking_send(popped).is_err() || eof {
+            break;
+        }
+    });
+
+    let mut tasks = JoinSet::new();
+    loop {
+        tokio::select! {
+            Some(popped) = popped_rx.recv(), if tasks.len() < in_flight => {
+                let next = output.with(|output| {
+                    let _tracer = trace::enter(Some(tracer.clone()));
+                    next_input(popped, journal.as_deref(), output)
+                })?;
+                match next {
+                    Some(Next::Event(Event::EOF)) => break,
+                    Some(Next::Event(event)) => {
+                        metrics.enqueued();
+                        let step = step::<S, N, P, IP>(node.clone(), event, ctx.clone());
+                        tasks.spawn(entered(clock.clone(), tracer.clone(), step));
+                    }
+                    Some(Next::Unknown(raw)) => {
+                        metrics.enqueued();
+                        let unknown = unknown::<S, N, P, IP>(node.clone(), raw, ctx.clone());
+                        tasks.spawn(entered(clock.clone(), tracer.clone(), unknown));
+                    }
+                    None => {}
+                }
+            }
+            Some(done) = tasks.join_next() => {
+                metrics.dequeued();
+                done.context("handler task panicked")??;
+            }
+            else => break,
+        }
+    }
+
+    // Shutdown sequence: no more replies can arrive, so fail outstanding requests
+    // rather than have handlers sit out their timeouts, and let them finish
+    rpc.fail_pending();
+    while let Some(done) = tasks.join_next().await {
+        metrics.dequeued();
+        done.context("handler task panicked")??;
+    }
+
+    entered(clock, tracer.clone(), async {
+        step::<S, N, P, IP>(node.clone(), Event::EOF, ctx.clone()).await?;
+        node.on_shutdown(ctx.clone())
+            .await
+            .context("node shutdown failed")
+    })
+    .await?;
+    // Nothing is awaited from here on
+    let _tracer = trace::enter(Some(tracer));
+    output.flush().context("flush output")?;
+    trace::info("metrics", metrics.snapshot());
+
+    inbox.join()
+}
+
+async fn step<S, N, P, IP>(
+    node: Arc<N>,
+    event: Event<P, IP>,
+    ctx: AsyncContext<P, IP>,
+) -> anyhow::Result<()>
+where
+    N: AsyncNode<S, P, IP>,
+{
+    let request = event.request_envelope();
+    let message = matches!(event, Event::Message(_));
+
+    let metrics = ctx.output.with(|output| output.metrics().cloned());
+    let handler = metrics::handling(metrics.as_ref(), request.as_ref());
+    let (result, replied) =
+        error::handling_async(request.as_ref(), node.step(event, ctx.clone())).await;
+    handler.done();
+    if let Err(e) = result {
+        if let Some(reply) = step_failed(e, request, replied, message)? {
+            ctx.send(&reply).context("reply with error")?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn unknown<S, N, P, IP>(
+    node: Arc<N>,
+    raw: Value,
+    ctx: AsyncContext<P, IP>,
+) -> anyhow::Result<()>
+where
+    N: AsyncNode<S, P, IP>,
+{
+    node.on_unknown(raw, ctx)
+        .await
+        .context("Node on_unknown failed")
+}
+
+// Runs `f` as a task of the node `clock` and `tracer` belong to
+async fn entered<T>(clock: Option<NodeClock>, tracer: NodeTracer, f: impl Future<Output = T>) -> T {
+    clock::scope(clock, trace::scope(Some(tracer), f)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ChannelTransport;
+
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::io::{BufRead, Write};
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Payload {
+        Slow,
+        SlowOk { answer: usize },
+        Fast,
+        FastOk,
+        Ask,
+        AskOk { answer: usize },
+    }
+
+    // Answers `slow` with what it asks a service, `fast` right away
+    struct Asking;
+
+    impl AsyncNode<(), Payload> for Asking {
+        fn from_init(_state: (), _init: Init, _ctx: AsyncContext<Payload>) -> anyhow::Result<Self> {
+            Ok(Asking)
+        }
+
+        async fn step(
+            self: Arc<Self>,
+            input: Event<Payload>,
+            ctx: AsyncContext<Payload>,
+        ) -> anyhow::Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            let mut reply = input.into_reply(None);
+            reply.body.payload = match reply.body.payload {
+                Payload::Slow => {
+                    let asked: Message<Payload> = ctx
+                        .call("svc", Payload::Ask, Duration::from_secs(10))
+                        .await?;
+                    let Payload::AskOk { answer } = asked.body.payload else {
+                        anyhow::bail!("unexpected answer {:?}", asked.body.payload);
+                    };
+                    Payload::SlowOk { answer }
+                }
+                Payload::Fast => Payload::FastOk,
+                _ => return Ok(()),
+            };
+            ctx.send(&reply)
+        }
+    }
+
+    fn read(input: &mut impl BufRead) -> Value {
+        let mut line = String::new();
+        input.read_line(&mut line).expect("read from node");
+        serde_json::from_str(&line).expect("node wrote a message")
+    }
+
+    #[test]
+    fn handlers_awaiting_replies_dont_hold_up_others() {
+        let (node_end, client_end) = ChannelTransport::pair();
+        let node = std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .expect("start runtime")
+                .block_on(async_main_loop_with::<(), Asking, Payload, ()>(
+                    (),
+                    Box::new(node_end),
+                ))
+        });
+
+        let (mut input, mut output) = client_end.into_parts();
+        for msg in [
+            json!({"src": "c1", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "slow", "msg_id": 2}}),
+            json!({"src": "c1", "dest": "n1", "body": {"type": "fast", "msg_id": 3}}),
+        ] {
+            writeln!(output, "{}", msg).expect("write to node");
+        }
+        assert_eq!(read(&mut input)["body"]["type"], "init_ok");
+
+        // The slow handler waits for the service, the fast one gets through meanwhile
+        let (mut ask, mut fast) = (None, None);
+        while ask.is_none() || fast.is_none() {
+            let msg = read(&mut input);
+            match msg["body"]["type"].as_str() {
+                Some("ask") => ask = Some(msg),
+                Some("fast_ok") => fast = Some(msg),
+                _ => panic!("unexpected message {}", msg),
+            }
+        }
+        assert_eq!(fast.expect("fast_ok")["body"]["in_reply_to"], 3);
+
+        let ask = ask.expect("ask");
+        let answer = json!({
+            "src": "svc",
+            "dest": "n1",
+            "body": {"type": "ask_ok", "in_reply_to": ask["body"]["msg_id"], "answer": 42},
+        });
+        writeln!(output, "{}", answer).expect("write to node");
+
+        let slow = read(&mut input);
+        assert_eq!(slow["body"]["type"], "slow_ok");
+        assert_eq!(slow["body"]["in_reply_to"], 2);
+        assert_eq!(slow["body"]["answer"], 42);
+
+        drop(output);
+        node.join()
+            .expect("node panicked")
+            .expect("node shut down cleanly");
+    }
+}
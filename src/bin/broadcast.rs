@@ -1,35 +1,32 @@
 use distributed_systems::*;
 
 use anyhow::Context;
-use rand::prelude::*;
+use rand::{prelude::*, rngs::StdRng};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
-    io::StdoutLock,
+    collections::{BTreeSet, HashMap},
     time::Duration,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
+#[payload]
+#[derive(Debug, Clone)]
 enum Payload {
+    #[ok]
     Broadcast {
         message: usize,
     },
-    BroadcastOk,
+    #[ok(messages: BTreeSet<usize>)]
     Read,
-    ReadOk {
-        messages: HashSet<usize>,
-    },
+    #[ok]
     Topology {
         topology: HashMap<String, Vec<String>>,
     },
-    TopologyOk,
     Gossip {
-        seen: HashSet<usize>,
+        seen: BTreeSet<usize>,
     },
 }
 
+#[derive(Serialize, Deserialize, Clone)]
 enum InjectedPayload {
     Gossip,
 }
@@ -37,45 +34,55 @@ enum InjectedPayload {
 struct BroadcastNode {
     node: String,
     id: usize,
-    messages: HashSet<usize>,
-    known: HashMap<String, HashSet<usize>>,
+    messages: BTreeSet<usize>,
+    known: HashMap<String, BTreeSet<usize>>,
     neighborhood: Vec<String>,
+    rng: StdRng,
+    timers: Timers<Payload, InjectedPayload>,
 }
 
-impl Node<(), Payload, InjectedPayload> for BroadcastNode {
+// The state is the seed gossip picks the extra messages with, so a simulated
+// run replays from the simulator's seed
+impl Node<u64, Payload, InjectedPayload> for BroadcastNode {
     fn from_init(
-        _state: (),
+        seed: u64,
         init: Init,
         tx: std::sync::mpsc::Sender<Event<Payload, InjectedPayload>>,
     ) -> anyhow::Result<Self> {
-        std::thread::spawn(move || {
-            // generate gossip events
-            // TODO: handle EOF signal
-            loop {
-                std::thread::sleep(Duration::from_millis(300));
-                if let Err(_) = tx.send(Event::Injected(InjectedPayload::Gossip)) {
-                    break;
-                }
-            }
-        });
+        let mut timers = Timers::new(tx);
+        timers.periodic(
+            "gossip",
+            Duration::from_millis(300),
+            Duration::from_millis(30),
+            InjectedPayload::Gossip,
+        );
+
+        // Every node draws from a stream of its own
+        let index = init
+            .node_ids
+            .iter()
+            .position(|nid| *nid == init.node_id)
+            .unwrap_or_default();
 
         Ok(Self {
             node: init.node_id,
             id: 1,
-            messages: HashSet::new(),
+            messages: BTreeSet::new(),
             known: init
                 .node_ids
                 .into_iter()
-                .map(|nid| (nid, HashSet::new()))
+                .map(|nid| (nid, BTreeSet::new()))
                 .collect(),
             neighborhood: Vec::new(),
+            rng: StdRng::seed_from_u64(seed.wrapping_add(index as u64)),
+            timers,
         })
     }
 
     fn step(
         &mut self,
         input: Event<Payload, InjectedPayload>,
-        output: &mut StdoutLock,
+        output: &mut Output,
     ) -> anyhow::Result<()> {
         match input {
             Event::EOF => {}
@@ -83,7 +90,7 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
                 InjectedPayload::Gossip => {
                     for n in &self.neighborhood {
                         let known_to_n = &self.known[n];
-                        let (already_known, mut notify_of): (HashSet<_>, HashSet<_>) = self
+                        let (already_known, mut notify_of): (BTreeSet<_>, BTreeSet<_>) = self
                             .messages
                             .iter()
                             .copied()
@@ -95,8 +102,7 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
                         // extra stuff each time.
                         // we cap the number of extraneous `m`s we include to be at most 10% of the
                         // number of `m`s` we _have_ to include to avoid excessive overhead.
-                        let mut rng = rand::thread_rng();
-
+                        let rng = &mut self.rng;
                         let additional_cap = (10 * notify_of.len() / 100) as u32;
                         notify_of.extend(already_known.iter().filter(|_| {
                             rng.gen_ratio(
@@ -111,6 +117,7 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
                             body: Body {
                                 id: None,
                                 in_reply_to: None,
+                                clock: Default::default(),
                                 payload: Payload::Gossip { seen: notify_of },
                             },
                         }
@@ -120,43 +127,164 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
                 }
             },
 
-            Event::Message(input) => {
-                let mut reply = input.into_reply(Some(&mut self.id));
-
-                match reply.body.payload {
-                    Payload::Gossip { seen } => {
-                        self.known
-                            .get_mut(&reply.dst)
-                            .expect("got gossip from unknown node")
-                            .extend(seen.iter().copied());
-                        self.messages.extend(seen);
-                    }
-                    Payload::Broadcast { message } => {
-                        self.messages.insert(message);
-                        reply.body.payload = Payload::BroadcastOk;
-                        reply.send(&mut *output).context("reply to broadcast")?;
-                    }
-                    Payload::Read => {
-                        reply.body.payload = Payload::ReadOk {
-                            messages: self.messages.clone(),
-                        };
-                        reply.send(&mut *output).context("reply to read")?;
-                    }
-                    Payload::Topology { mut topology } => {
-                        self.neighborhood = topology
-                            .remove(&self.node)
-                            .unwrap_or_else(|| panic!("no topology given for node {}", self.node));
-                        reply.body.payload = Payload::TopologyOk;
-                        reply.send(&mut *output).context("reply to topology")?;
-                    }
-                    Payload::BroadcastOk | Payload::ReadOk { .. } | Payload::TopologyOk => {}
-                }
-            }
+            Event::Message(input) => self.dispatch(input, output)?,
         }
         Ok(())
     }
+
+    // Each gossip round writes to every neighbor at once
+    fn buffering(&self) -> Option<Buffering> {
+        Some(Buffering::default())
+    }
+
+    // A missed gossip round is made up for by the next one
+    fn backpressure(&self) -> Option<Backpressure> {
+        Some(Backpressure {
+            capacity: 1024,
+            overflow: Overflow::DropInjected,
+        })
+    }
+
+    fn on_shutdown(&mut self, _output: &mut Output) -> anyhow::Result<()> {
+        self.timers.shutdown();
+        Ok(())
+    }
+}
+
+impl PayloadHandler for BroadcastNode {
+    fn next_msg_id(&mut self) -> Option<usize> {
+        self.id += 1;
+        Some(self.id - 1)
+    }
+
+    fn broadcast(
+        &mut self,
+        _src: &str,
+        _output: &mut Output,
+        message: usize,
+    ) -> anyhow::Result<()> {
+        self.messages.insert(message);
+        Ok(())
+    }
+
+    fn read(&mut self, _src: &str, _output: &mut Output) -> anyhow::Result<BTreeSet<usize>> {
+        Ok(self.messages.clone())
+    }
+
+    fn topology(
+        &mut self,
+        _src: &str,
+        _output: &mut Output,
+        mut topology: HashMap<String, Vec<String>>,
+    ) -> anyhow::Result<()> {
+        self.neighborhood = topology.remove(&self.node).ok_or_else(|| {
+            MaelstromError::new(
+                ErrorCode::MalformedRequest,
+                format!("no topology given for node {}", self.node),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn gossip(
+        &mut self,
+        src: &str,
+        _output: &mut Output,
+        seen: BTreeSet<usize>,
+    ) -> anyhow::Result<()> {
+        self.known
+            .get_mut(src)
+            .ok_or_else(|| {
+                MaelstromError::new(
+                    ErrorCode::NodeNotFound,
+                    format!("got gossip from {}, which isn't in the cluster", src),
+                )
+            })?
+            .extend(seen.iter().copied());
+        self.messages.extend(seen);
+        Ok(())
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, BroadcastNode, _, _>(())
+    main_loop::<_, BroadcastNode, _, _>(rand::random::<u64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{json, Value};
+
+    type Cluster = Simulator<u64, BroadcastNode, Payload, InjectedPayload>;
+
+    fn read(sim: &mut Cluster, node: &str) -> anyhow::Result<BTreeSet<usize>> {
+        let msg_id = sim.request("c1", node, json!({"type": "read"}))?;
+        sim.run_for(Duration::from_millis(50))?;
+        let reply = sim
+            .take_reply::<Value>("c1", msg_id)?
+            .context("read went unanswered")?;
+        Ok(serde_json::from_value(
+            reply.body.payload["messages"].clone(),
+        )?)
+    }
+
+    const NODES: [&str; 4] = ["n0", "n1", "n2", "n3"];
+
+    // A ring of four nodes gossiping every 100ms
+    fn ring(seed: u64) -> anyhow::Result<Cluster> {
+        let mut sim = Cluster::new(seed, &NODES, seed)?;
+        let topology = json!({
+            "n0": ["n1", "n3"],
+            "n1": ["n0", "n2"],
+            "n2": ["n1", "n3"],
+            "n3": ["n2", "n0"],
+        });
+        for node in NODES {
+            sim.request(
+                "c0",
+                node,
+                json!({"type": "topology", "topology": topology}),
+            )?;
+            sim.inject_every(node, Duration::from_millis(100), InjectedPayload::Gossip);
+        }
+        Ok(sim)
+    }
+
+    #[test]
+    fn broadcasts_converge_once_a_partition_heals() -> anyhow::Result<()> {
+        let mut sim = ring(7)?;
+        sim.partition(&[&["n0", "n1"], &["n2", "n3"]]);
+        sim.request("c1", "n0", json!({"type": "broadcast", "message": 1}))?;
+        sim.request("c1", "n2", json!({"type": "broadcast", "message": 2}))?;
+        sim.run_for(Duration::from_secs(2))?;
+        assert_eq!(read(&mut sim, "n1")?, BTreeSet::from([1]));
+        assert_eq!(read(&mut sim, "n3")?, BTreeSet::from([2]));
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(2))?;
+        for node in NODES {
+            assert_eq!(read(&mut sim, node)?, BTreeSet::from([1, 2]), "at {}", node);
+        }
+        assert!(sim.stats().partitioned > 0);
+        sim.shutdown()
+    }
+
+    #[test]
+    fn gossip_replays_from_the_seed() -> anyhow::Result<()> {
+        let run = |seed| -> anyhow::Result<_> {
+            let mut sim = ring(seed)?.with_trace();
+            sim.set_faults(Faults::none().drop(0.1).duplicate(0.1));
+            for message in 0..20 {
+                let node = NODES[message % NODES.len()];
+                sim.request("c1", node, json!({"type": "broadcast", "message": message}))?;
+            }
+            sim.run_for(Duration::from_secs(1))?;
+            let run = (sim.stats(), sim.trace().cloned());
+            sim.shutdown()?;
+            Ok(run)
+        };
+        assert_eq!(run(11)?, run(11)?);
+        Ok(())
+    }
 }
@@ -0,0 +1,79 @@
+use distributed_systems::*;
+
+use anyhow::Context;
+use serde_json::json;
+use std::process::{Command, Stdio};
+
+const USAGE: &str = "usage: cluster config <node count> [base port] > cluster.json\n       \
+cluster run <cluster.json> <node binary>";
+
+// Launches every node of a standalone cluster on this machine, each with
+// CLUSTER_CONFIG and NODE_ID set so main_loop joins the cluster instead of
+// waiting for Maelstrom. Clients then talk to the nodes' client ports.
+//
+// cargo run --bin cluster -- config 5 > cluster.json
+// cargo run --bin cluster -- run cluster.json target/debug/broadcast
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["config", count, rest @ ..] => {
+            let count: usize = count.parse().context("parse node count")?;
+            let base: u16 = match rest.first() {
+                Some(port) => port.parse().context("parse base port")?,
+                None => 7000,
+            };
+            let nodes: serde_json::Map<String, serde_json::Value> = (0..count)
+                .map(|i| {
+                    let port = |offset: usize| base as usize + offset + i;
+                    let addresses = json!({
+                        "peer": format!("127.0.0.1:{}", port(0)),
+                        "client": format!("127.0.0.1:{}", port(1000)),
+                    });
+                    (format!("n{}", i), addresses)
+                })
+                .collect();
+            let config = json!({ "nodes": nodes });
+            println!("{}", serde_json::to_string_pretty(&config)?);
+            Ok(())
+        }
+        ["run", config, bin] => run(config, bin),
+        _ => anyhow::bail!(USAGE),
+    }
+}
+
+fn run(config_path: &str, bin: &str) -> anyhow::Result<()> {
+    let config = ClusterConfig::load(config_path)?;
+
+    let mut children = Vec::new();
+    for (node_id, addresses) in &config.nodes {
+        let child = Command::new(bin)
+            .env("CLUSTER_CONFIG", config_path)
+            .env("NODE_ID", node_id)
+            .stdin(Stdio::null())
+            .spawn()
+            .with_context(|| format!("spawn {} as {}", bin, node_id))?;
+        trace::info(
+            "node started",
+            json!({"node": node_id, "client": addresses.client}),
+        );
+        children.push((node_id, child));
+    }
+
+    for (node_id, mut child) in children {
+        let status = child
+            .wait()
+            .with_context(|| format!("wait for {}", node_id))?;
+        let fields = json!({"node": node_id, "status": status.to_string()});
+        if status.success() {
+            trace::info("node exited", fields);
+        } else {
+            trace::error("node exited with an error", fields);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use distributed_systems::*;
+
+use anyhow::Context;
+use std::io::{BufReader, Write};
+
+const USAGE: &str = "usage: diagram <trace or log file, - for stdin> [output.svg]";
+
+// Draws the Lamport diagram of a run, like the ones in topologies/, from a
+// trace written by `workload --trace` or `Trace::write`, or from the stderr
+// of nodes run with NODE_LOG=trace NODE_LOG_FORMAT=json
+//
+// cargo run --bin workload -- echo target/debug/echo --trace echo.jsonl
+// cargo run --bin diagram -- echo.jsonl echo-msgs.svg
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let input = args.next().context(USAGE)?;
+    let output = args.next();
+
+    let trace = match input.as_str() {
+        "-" => Trace::read(std::io::stdin().lock())?,
+        path => {
+            let file = std::fs::File::open(path).with_context(|| format!("open {}", path))?;
+            Trace::read(BufReader::new(file))?
+        }
+    };
+    anyhow::ensure!(!trace.is_empty(), "no messages in {}", input);
+
+    let svg = trace.to_svg();
+    match output {
+        Some(path) => std::fs::write(&path, svg).with_context(|| format!("write {}", path))?,
+        None => std::io::stdout()
+            .write_all(svg.as_bytes())
+            .context("write diagram")?,
+    }
+    Ok(())
+}
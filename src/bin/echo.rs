@@ -1,15 +1,10 @@
 use distributed_systems::*;
 
-use anyhow::Context;
-use serde::{Deserialize, Serialize};
-use std::io::{StdoutLock, Write};
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
+#[payload]
+#[derive(Debug, Clone)]
 enum Payload {
+    #[ok(echo: String)]
     Echo { echo: String },
-    EchoOk { echo: String },
 }
 
 // State Machine for the ECHO Node of the Distributed System
@@ -30,25 +25,23 @@ impl Node<(), Payload> for EchoNode {
         Ok(EchoNode { id: 1 })
     }
 
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
-        };
-
-        let mut reply = input.into_reply(Some(&mut self.id));
-
-        match reply.body.payload {
-            Payload::Echo { echo } => {
-                reply.body.payload = Payload::EchoOk { echo };
+    fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+        match input {
+            Event::Message(input) => self.dispatch(input, output),
+            Event::EOF => Ok(()),
+            Event::Injected(..) => panic!("got injected event when there's no event injection"),
+        }
+    }
+}
 
-                serde_json::to_writer(&mut *output, &reply)
-                    .context("serialize response to init")?;
-                output.write_all(b"\n").context("write trailing newline")?;
-            }
-            Payload::EchoOk { .. } => {}
-        };
+impl PayloadHandler for EchoNode {
+    fn next_msg_id(&mut self) -> Option<usize> {
+        self.id += 1;
+        Some(self.id - 1)
+    }
 
-        Ok(())
+    fn echo(&mut self, _src: &str, _output: &mut Output, echo: String) -> anyhow::Result<String> {
+        Ok(echo)
     }
 }
 
@@ -1,3 +1,4 @@
+use distributed_systems::trace;
 use std::net::UdpSocket;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -5,7 +6,10 @@ fn main() -> std::io::Result<()> {
     let socket = UdpSocket::bind("127.0.0.1:5005")?;
     let counter = AtomicUsize::new(0);
 
-    println!("Server listening on 127.0.0.1:5005");
+    trace::info(
+        "server listening",
+        serde_json::json!({"addr": "127.0.0.1:5005"}),
+    );
 
     loop {
         let mut buf = [0u8; 1];
@@ -23,7 +27,7 @@ fn main() -> std::io::Result<()> {
         };
 
         let response = count.to_be_bytes();
-        socket.send_to(&response, &src)?;
+        socket.send_to(&response, src)?;
     }
 }
 
@@ -1,11 +1,7 @@
-#![allow(unused)]
-
 use distributed_systems::*;
 
 use anyhow::Context;
-use core::panic;
 use serde::{Deserialize, Serialize};
-use std::{io::StdoutLock, net::UdpSocket, sync::atomic::Ordering, usize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
@@ -17,9 +13,13 @@ enum Payload {
     ReadOk { value: usize },
 }
 
+// The counter lives in Maelstrom's seq-kv service under a single key,
+// so every node sees the same value without talking to the others
+const COUNTER_KEY: &str = "counter";
+
 struct GrowCounterNode {
-    node: String,
-    id: usize,
+    rpc: RpcClient,
+    kv: KvClient,
 }
 
 impl Node<(), Payload> for GrowCounterNode {
@@ -31,16 +31,13 @@ impl Node<(), Payload> for GrowCounterNode {
     where
         Self: Sized,
     {
-        Ok(GrowCounterNode {
-            id: 1,
-            node: init.node_id,
-        })
-    }
+        let rpc = RpcClient::new(init.node_id);
+        let kv = KvClient::new(rpc.clone(), KvService::Seq);
 
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.connect("127.0.0.1:5005")?;
+        Ok(GrowCounterNode { rpc, kv })
+    }
 
+    fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
         match input {
             Event::EOF => {}
             Event::Injected(..) => {
@@ -48,19 +45,15 @@ impl Node<(), Payload> for GrowCounterNode {
             }
 
             Event::Message(input) => {
-                let mut reply = input.into_reply(Some(&mut self.id));
+                // Numbered along with our kv requests, whose replies come back to us
+                let mut reply = input.into_reply(None);
+                reply.body.id = Some(self.rpc.next_msg_id());
 
                 match reply.body.payload {
                     Payload::Add { delta } => {
-                        // Atomically increment the counter
-                        // This is not global to all nodes and only within the node
-                        // let mut counter = GLOBAL_COUNTER.lock().unwrap();
-                        // counter.fetch_add(delta, Ordering::SeqCst);
-                        let req = [delta as u8];
-                        socket.send(&req)?;
-
-                        // let mut buf = [0u8; 8];
-                        // socket.recv(&mut buf)?;
+                        self.kv
+                            .update(COUNTER_KEY, 0, &mut *output, |value: usize| value + delta)
+                            .context("add to counter")?;
 
                         reply.body.payload = Payload::AddOk;
                         reply
@@ -69,21 +62,14 @@ impl Node<(), Payload> for GrowCounterNode {
                     }
 
                     Payload::Read => {
-                        // Automatically read the current value of the counter
-                        // let counter = GLOBAL_COUNTER.lock().unwrap();
-                        // let current_value = counter.load(Ordering::SeqCst);
-
-                        let req = [0u8; 1];
-                        socket.send(&req)?;
-
-                        let mut buf = [0u8; 8];
-                        socket.recv(&mut buf)?;
-
-                        let current_value = usize::from_be_bytes(buf);
-
-                        reply.body.payload = Payload::ReadOk {
-                            value: current_value,
-                        };
+                        // seq-kv reads may be stale, writing the value back through a cas
+                        // only succeeds once we've observed the latest one
+                        let value = self
+                            .kv
+                            .update(COUNTER_KEY, 0, &mut *output, |value: usize| value)
+                            .context("read counter")?;
+
+                        reply.body.payload = Payload::ReadOk { value };
                         reply
                             .send(&mut *output)
                             .context("reply to grow counter read")?;
@@ -96,8 +82,41 @@ impl Node<(), Payload> for GrowCounterNode {
 
         Ok(())
     }
+
+    fn rpc(&self) -> Option<RpcClient> {
+        Some(self.rpc.clone())
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     main_loop::<_, GrowCounterNode, _, _>(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn concurrent_adds_on_seq_kv_all_count() -> anyhow::Result<()> {
+        let nodes: Vec<String> = ["n0", "n1", "n2"].map(String::from).to_vec();
+        let mut sim = Simulator::<(), GrowCounterNode, Payload>::new(5, &["n0", "n1", "n2"], ())?;
+        let generator = Generator::new(Workload::Counter, 5, &nodes);
+        let options = WorkloadOptions {
+            rate: 100.0,
+            concurrency: 6,
+            time_limit: Duration::from_secs(5),
+            ..WorkloadOptions::default()
+        };
+
+        let history = sim.run_workload(Driver::new(generator, &nodes, options))?;
+        let results = checker::check_workload(
+            Workload::Counter,
+            checker::ConsistencyModel::Serializable,
+            &history,
+        );
+        assert!(results.valid, "{}", results);
+        Ok(())
+    }
+}
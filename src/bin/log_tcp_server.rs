@@ -1,3 +1,4 @@
+use distributed_systems::trace;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::{Read, Write};
@@ -13,6 +14,7 @@ enum ServerRequest {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum ServerResponse {
     StoreResponseOk {
         offset: usize,
@@ -42,15 +44,14 @@ fn handle_client(
 
     let response: ServerResponse = match request {
         ServerRequest::StoreMessage { key, message } => {
-            let entry = log_msgs.entry(key.clone()).or_insert_with(|| Vec::new());
+            let entry = log_msgs.entry(key.clone()).or_default();
             let new_offset = match entry.iter().map(|v| v[0]).max() {
                 Some(max_offset) => max_offset + 1,
                 None => {
                     let num_start = key.find(char::is_numeric).unwrap();
                     let num_str = &key[num_start..];
                     let num: usize = num_str.parse().unwrap();
-                    let offset = num * 1000;
-                    offset
+                    num * 1000
                 }
             };
 
@@ -107,7 +108,10 @@ fn main() -> std::io::Result<()> {
     let mut log_msgs: HashMap<String, Vec<Vec<usize>>> = HashMap::new();
     let mut committed_msgs: HashMap<String, usize> = HashMap::new();
 
-    println!("TCP Server listening on 127.0.0.1:5141");
+    trace::info(
+        "server listening",
+        serde_json::json!({"addr": "127.0.0.1:5141"}),
+    );
 
     for stream in listener.incoming() {
         match stream {
@@ -116,11 +120,13 @@ fn main() -> std::io::Result<()> {
                 let log_msgs = &mut log_msgs;
                 let committed_msgs = &mut committed_msgs;
                 handle_client(stream, log_msgs, committed_msgs)?;
-                let duration = start_time.elapsed();
-                println!("Time consumed by request: {:?}", duration);
+                trace::debug(
+                    "request handled",
+                    serde_json::json!({"latency_ms": start_time.elapsed().as_secs_f64() * 1000.0}),
+                );
             }
             Err(e) => {
-                println!("Error occurred: {}", e);
+                trace::error("accept failed", serde_json::json!({"error": e.to_string()}));
             }
         }
     }
@@ -1,3 +1,4 @@
+use distributed_systems::trace;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::UdpSocket;
@@ -12,6 +13,7 @@ enum ServerRequest {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum ServerResponse {
     StoreResponseOk {
         offset: usize,
@@ -30,7 +32,10 @@ fn main() -> std::io::Result<()> {
     let mut log_msgs: HashMap<String, Vec<Vec<usize>>> = HashMap::new();
     let mut committed_msgs: HashMap<String, usize> = HashMap::new();
 
-    println!("Server listening on 127.0.0.1:5140");
+    trace::info(
+        "server listening",
+        serde_json::json!({"addr": "127.0.0.1:5140"}),
+    );
 
     loop {
         let mut buf = [0u8; 4096];
@@ -45,15 +50,14 @@ fn main() -> std::io::Result<()> {
 
         let response: ServerResponse = match request {
             ServerRequest::StoreMessage { key, message } => {
-                let entry = log_msgs.entry(key.clone()).or_insert_with(|| Vec::new());
+                let entry = log_msgs.entry(key.clone()).or_default();
                 let new_offset = match entry.iter().map(|v| v[0]).max() {
                     Some(max_offset) => max_offset + 1,
                     None => {
                         let num_start = key.find(char::is_numeric).unwrap();
                         let num_str = &key[num_start..];
                         let num: usize = num_str.parse().unwrap();
-                        let offset = num * 1000;
-                        offset
+                        num * 1000
                     }
                 };
 
@@ -100,10 +104,14 @@ fn main() -> std::io::Result<()> {
         };
 
         let response_bytes = serde_json::to_vec(&response).expect("Failed to serialize response");
-        socket.send_to(&response_bytes, &src)?;
+        socket.send_to(&response_bytes, src)?;
 
-        let duration = start_time.elapsed();
-        println!("Response sent: {:?}", response);
-        println!("Time consumed by request: {:?}", duration)
+        trace::debug(
+            "response sent",
+            serde_json::json!({
+                "response": format!("{:?}", response),
+                "latency_ms": start_time.elapsed().as_secs_f64() * 1000.0,
+            }),
+        );
     }
 }
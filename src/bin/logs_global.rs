@@ -7,9 +7,8 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fmt::Debug,
-    io::{self, StdoutLock},
+    io::{self},
     net::UdpSocket,
-    usize,
 };
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -52,6 +51,7 @@ enum ServerRequest {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum ServerResponse {
     StoreResponseOk {
         offset: usize,
@@ -85,9 +85,11 @@ impl Node<(), Payload> for KafkaNode {
         })
     }
 
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
+    fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::EOF => return Ok(()),
+            Event::Injected(..) => panic!("got injected event when there's no event injection"),
         };
 
         let socket = UdpSocket::bind("0.0.0.0:0")?;
@@ -175,15 +177,13 @@ impl KafkaNode {
         match response {
             Ok(res) => Ok(res),
             Err(e) => {
-                // Print the raw bytes and string representation on error
-                let raw_response = String::from_utf8_lossy(&buf[..amt]);
-                println!(
-                    "Failed to deserialize response: {:?} 
-                    | Raw Response bytes: {:?} 
-                    | Raw Response String: {:?}",
-                    e,
-                    &buf[..amt],
-                    raw_response,
+                // Log the raw response on error, stdout belongs to Maelstrom
+                trace::error(
+                    "failed to deserialize response",
+                    serde_json::json!({
+                        "error": e.to_string(),
+                        "raw": String::from_utf8_lossy(&buf[..amt]),
+                    }),
                 );
                 Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidData,
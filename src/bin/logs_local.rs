@@ -4,7 +4,7 @@ use distributed_systems::*;
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::{borrow::Cow, collections::HashMap, fmt::Debug, io::StdoutLock, usize};
+use std::{borrow::Cow, collections::HashMap, fmt::Debug};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
@@ -61,27 +61,25 @@ impl Node<(), Payload> for KafkaNode {
         })
     }
 
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
+    fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::EOF => return Ok(()),
+            Event::Injected(..) => panic!("got injected event when there's no event injection"),
         };
 
         let mut reply = input.into_reply(Some(&mut self.id));
 
         match reply.body.payload {
             Payload::Send { key, message } => {
-                let entry = self
-                    .log_msgs
-                    .entry(key.clone())
-                    .or_insert_with(|| Vec::new());
+                let entry = self.log_msgs.entry(key.clone()).or_default();
                 let new_offset = match entry.iter().map(|v| v[0]).max() {
                     Some(max_offset) => max_offset + 1,
                     None => {
                         let num_start = key.find(char::is_numeric).unwrap();
                         let num_str = &key[num_start..];
                         let num: usize = num_str.parse().unwrap();
-                        let offset = num * 1000;
-                        offset
+                        num * 1000
                     }
                 };
 
@@ -98,9 +96,10 @@ impl Node<(), Payload> for KafkaNode {
                     .iter()
                     .filter_map(|(key, vecs)| {
                         if let Some(&start_offset) = offsets.get(key) {
+                            // Polls start at the offset asked for, inclusive
                             let fvecs: Vec<Vec<usize>> = vecs
                                 .iter()
-                                .filter(|v| v[0] > start_offset)
+                                .filter(|v| v[0] >= start_offset)
                                 .cloned()
                                 .collect();
 
@@ -150,3 +149,32 @@ impl Node<(), Payload> for KafkaNode {
 fn main() -> anyhow::Result<()> {
     main_loop::<_, KafkaNode, _, _>(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn kafka_offsets_are_handed_out_and_polled_in_order() -> anyhow::Result<()> {
+        let nodes = ["n0".to_string()];
+        let mut sim = Simulator::<(), KafkaNode, Payload>::new(3, &["n0"], ())?;
+        let generator = Generator::new(Workload::Kafka, 3, &nodes).with_key_count(3);
+        let options = WorkloadOptions {
+            rate: 100.0,
+            concurrency: 4,
+            time_limit: Duration::from_secs(5),
+            ..WorkloadOptions::default()
+        };
+
+        let history = sim.run_workload(Driver::new(generator, &nodes, options))?;
+        let results = checker::check_workload(
+            Workload::Kafka,
+            checker::ConsistencyModel::Serializable,
+            &history,
+        );
+        assert!(results.valid, "{}", results);
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+use anyhow::Context;
+use std::process::{Command, Stdio};
+
+const USAGE: &str = "usage: replay <node binary> <journal> [--realtime]";
+
+// Reruns a node binary against a journal it wrote with NODE_JOURNAL set, to
+// reproduce a failed run offline. What the node sends goes to stdout, its logs
+// and the error it failed with to stderr.
+//
+// NODE_JOURNAL=/tmp/broadcast-{node}.jsonl ~/maelstrom/maelstrom test -w broadcast --bin target/debug/broadcast ...
+// cargo run --bin replay -- target/debug/broadcast /tmp/broadcast-n3.jsonl
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (bin, journal, realtime) = match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [bin, journal] => (bin.to_string(), journal.to_string(), false),
+        [bin, journal, "--realtime"] => (bin.to_string(), journal.to_string(), true),
+        _ => anyhow::bail!(USAGE),
+    };
+
+    let mut command = Command::new(&bin);
+    command
+        .env("NODE_REPLAY", &journal)
+        .env_remove("NODE_JOURNAL")
+        .stdin(Stdio::null());
+    if realtime {
+        command.env("NODE_REPLAY_REALTIME", "1");
+    }
+    let status = command.status().with_context(|| format!("run {}", bin))?;
+
+    anyhow::ensure!(
+        status.success(),
+        "{} exited with {} replaying {}",
+        bin,
+        status,
+        journal
+    );
+    Ok(())
+}
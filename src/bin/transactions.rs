@@ -1,7 +1,7 @@
 use anyhow::Context;
 use distributed_systems::*;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, io::StdoutLock, sync::Mutex};
+use std::{collections::HashMap, sync::Mutex};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
@@ -51,9 +51,11 @@ impl Node<(), Payload> for TransactionNode {
         })
     }
 
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
+    fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::EOF => return Ok(()),
+            Event::Injected(..) => panic!("got injected event when there's no event injection"),
         };
 
         let mut reply = input.into_reply(Some(&mut self.id));
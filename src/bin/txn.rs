@@ -3,8 +3,10 @@ use distributed_systems::*;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::StdoutLock;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
@@ -18,14 +20,8 @@ enum TxnElement {
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Txn {
-        txn: Vec<Vec<TxnElement>>,
-    },
-    TxnOk {
-        msg_id: usize,
-        in_reply_to: usize,
-        txn: Vec<Vec<TxnElement>>,
-    },
+    Txn { txn: Vec<Vec<TxnElement>> },
+    TxnOk { txn: Vec<Vec<TxnElement>> },
 }
 
 /*
@@ -48,34 +44,58 @@ enum Payload {
  - Used Arc for shared ownership: This allows for more efficient cloning of the store reference if needed in the future.
 */
 
+// Transactions are handled by a worker pool, those sharing a key one after
+// the other in the order they arrived, so each one sees the store as the
+// transactions before it on its keys left it
 struct TransactionNode {
-    node: String,
-    id: usize,
+    id: AtomicUsize,
     store: Arc<RwLock<HashMap<usize, TxnElement>>>,
 }
 
-impl Node<(), Payload> for TransactionNode {
+impl ParallelNode<(), Payload> for TransactionNode {
     fn from_init(
         _state: (),
-        init: Init,
+        _init: Init,
         _tx: std::sync::mpsc::Sender<Event<Payload>>,
-    ) -> anyhow::Result<Self>
-    where
-        Self: Sized,
-    {
+    ) -> anyhow::Result<Self> {
         Ok(TransactionNode {
-            id: 1,
-            node: init.node_id,
+            id: AtomicUsize::new(1),
             store: Arc::new(RwLock::new(HashMap::new())),
         })
     }
 
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
+    fn partition(&self, input: &Event<Payload>) -> Vec<u64> {
+        let Event::Message(Message {
+            body:
+                Body {
+                    payload: Payload::Txn { txn },
+                    ..
+                },
+            ..
+        }) = input
+        else {
+            return Vec::new();
         };
 
-        let mut reply = input.into_reply(Some(&mut self.id));
+        txn.iter()
+            .filter_map(|operation| match operation.as_slice() {
+                [_, TxnElement::USize(key), _] => Some(partition_key(key)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn step(&self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+        let input = match input {
+            Event::Message(input) => input,
+            Event::EOF => return Ok(()),
+            Event::Injected(..) => {
+                anyhow::bail!("got injected event when there's no event injection")
+            }
+        };
+
+        let mut id = self.id.fetch_add(1, Ordering::Relaxed);
+        let mut reply = input.into_reply(Some(&mut id));
 
         if let Payload::Txn { ref mut txn } = reply.body.payload {
             let mut updated_txn = Vec::new();
@@ -103,16 +123,16 @@ impl Node<(), Payload> for TransactionNode {
                     }
 
                     _ => {
-                        updated_txn.push(operation.clone());
+                        return Err(MaelstromError::new(
+                            ErrorCode::MalformedRequest,
+                            format!("unknown txn operation {:?}", operation),
+                        )
+                        .into());
                     }
                 }
             }
 
-            reply.body.payload = Payload::TxnOk {
-                msg_id: self.id,
-                in_reply_to: reply.body.in_reply_to.expect(""),
-                txn: updated_txn,
-            };
+            reply.body.payload = Payload::TxnOk { txn: updated_txn };
             reply.send(&mut *output).context("reply to txn")?;
         }
 
@@ -121,5 +141,34 @@ impl Node<(), Payload> for TransactionNode {
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, TransactionNode, _, _>(())
+    parallel_main_loop::<_, TransactionNode, _, _>((), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn transactions_on_one_node_are_serializable() -> anyhow::Result<()> {
+        let nodes = ["n0".to_string()];
+        let mut sim = Simulator::<(), Serial<TransactionNode>, Payload>::new(11, &["n0"], ())?;
+        let generator = Generator::new(Workload::Txn, 11, &nodes).with_key_count(4);
+        let options = WorkloadOptions {
+            rate: 100.0,
+            concurrency: 4,
+            time_limit: Duration::from_secs(5),
+            ..WorkloadOptions::default()
+        };
+
+        let history = sim.run_workload(Driver::new(generator, &nodes, options))?;
+        let results = checker::check_workload(
+            Workload::Txn,
+            checker::ConsistencyModel::Serializable,
+            &history,
+        );
+        assert!(results.valid, "{}", results);
+        Ok(())
+    }
 }
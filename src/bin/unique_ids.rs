@@ -1,18 +1,10 @@
 use distributed_systems::*;
 
-use anyhow::Context;
-use serde::{Deserialize, Serialize};
-use std::io::StdoutLock;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
+#[payload]
+#[derive(Debug, Clone)]
 enum Payload {
+    #[ok(#[serde(rename = "id")] guid: String)]
     Generate,
-    GenerateOk {
-        #[serde(rename = "id")]
-        guid: String,
-    },
 }
 
 struct UniqueNode {
@@ -35,26 +27,24 @@ impl Node<(), Payload> for UniqueNode {
         })
     }
 
-    fn step(&mut self, input: Event<Payload>, output: &mut StdoutLock) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            panic!("got injected event when there's no event injection");
-        };
-
-        let mut reply = input.into_reply(Some(&mut self.id));
-
-        match reply.body.payload {
-            Payload::Generate {} => {
-                let guid = format!("{}-{}", self.node, self.id);
-                reply.body.payload = Payload::GenerateOk { guid };
+    fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+        match input {
+            Event::Message(input) => self.dispatch(input, output),
+            Event::EOF => Ok(()),
+            Event::Injected(..) => panic!("got injected event when there's no event injection"),
+        }
+    }
+}
 
-                reply
-                    .send(&mut *output)
-                    .context("serialized response to generate")?;
-            }
-            Payload::GenerateOk { .. } => {}
-        };
+impl PayloadHandler for UniqueNode {
+    fn next_msg_id(&mut self) -> Option<usize> {
+        self.id += 1;
+        Some(self.id - 1)
+    }
 
-        Ok(())
+    fn generate(&mut self, _src: &str, _output: &mut Output) -> anyhow::Result<String> {
+        // The reply takes the next msg_id, which makes the guid unique to this node
+        Ok(format!("{}-{}", self.node, self.id))
     }
 }
 
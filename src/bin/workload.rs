@@ -0,0 +1,331 @@
+use distributed_systems::*;
+
+use anyhow::Context;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, BufReader, Write},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+const USAGE: &str =
+    "usage: workload <echo|unique-ids|broadcast|g-counter|kafka|txn-rw-register> <node binary> \
+[--node-count N] [--rate OPS_PER_SEC] [--concurrency N] [--time-limit SECS] [--seed N] \
+[--history PATH] [--results PATH] [--trace PATH] [--diagram PATH] \
+[--consistency-models read-uncommitted|read-committed|serializable]";
+
+// Runs a node binary the way Maelstrom does, without the JVM: spawns a cluster
+// of processes talking JSON lines over stdin/stdout, provides seq-kv, lin-kv
+// and lww-kv, drives a workload against it and checks the history it recorded.
+//
+// cargo build && cargo run --bin workload -- broadcast ./target/debug/broadcast --node-count 5
+struct Args {
+    workload: Workload,
+    bin: String,
+    node_count: usize,
+    seed: u64,
+    history: Option<String>,
+    results: Option<String>,
+    trace: Option<String>,
+    diagram: Option<String>,
+    consistency: checker::ConsistencyModel,
+    options: WorkloadOptions,
+}
+
+fn parse_args() -> anyhow::Result<Args> {
+    let mut args = std::env::args().skip(1);
+    let workload = args.next().context(USAGE)?.parse()?;
+    let bin = args.next().context(USAGE)?;
+    let mut parsed = Args {
+        workload,
+        bin,
+        node_count: 1,
+        seed: 0,
+        history: None,
+        results: None,
+        trace: None,
+        diagram: None,
+        consistency: checker::ConsistencyModel::Serializable,
+        options: WorkloadOptions::default(),
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .with_context(|| format!("missing value for {}", flag))?;
+        let secs = |v: &str| v.parse().map(Duration::from_secs_f64);
+        match flag.as_str() {
+            "--node-count" => parsed.node_count = value.parse().context("parse --node-count")?,
+            "--rate" => parsed.options.rate = value.parse().context("parse --rate")?,
+            "--concurrency" => {
+                parsed.options.concurrency = value.parse().context("parse --concurrency")?
+            }
+            "--time-limit" => {
+                parsed.options.time_limit = secs(&value).context("parse --time-limit")?
+            }
+            "--seed" => parsed.seed = value.parse().context("parse --seed")?,
+            "--history" => parsed.history = Some(value),
+            "--results" => parsed.results = Some(value),
+            "--trace" => parsed.trace = Some(value),
+            "--diagram" => parsed.diagram = Some(value),
+            "--consistency-models" => parsed.consistency = value.parse()?,
+            _ => anyhow::bail!("unknown flag {}\n{}", flag, USAGE),
+        }
+    }
+
+    Ok(parsed)
+}
+
+struct Cluster {
+    stdins: BTreeMap<String, Output>,
+    children: Vec<(String, Child)>,
+    rx: mpsc::Receiver<(String, Option<String>)>,
+    services: HashMap<&'static str, KvStore>,
+    next_msg_id: usize,
+    // Every message routed, which arrives the moment it is routed
+    trace: Option<Trace>,
+    started: Instant,
+}
+
+impl Cluster {
+    fn spawn(bin: &str, node_ids: &[String], trace: bool) -> anyhow::Result<Self> {
+        let (tx, rx) = mpsc::channel();
+        let mut stdins = BTreeMap::new();
+        let mut children = Vec::new();
+
+        for node_id in node_ids {
+            let mut child = Command::new(bin)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .spawn()
+                .with_context(|| format!("spawn {} as {}", bin, node_id))?;
+            stdins.insert(
+                node_id.clone(),
+                Output::new(child.stdin.take().expect("piped stdin")),
+            );
+
+            // Lines from every node end up on one channel, None once it exits
+            let stdout = child.stdout.take().expect("piped stdout");
+            children.push((node_id.clone(), child));
+            let tx = tx.clone();
+            let node_id = node_id.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    let Ok(line) = line else {
+                        break;
+                    };
+                    if tx.send((node_id.clone(), Some(line))).is_err() {
+                        return;
+                    }
+                }
+                let _ = tx.send((node_id, None));
+            });
+        }
+
+        let services = KvService::ALL
+            .into_iter()
+            .map(|service| (service.node_id(), KvStore::new()))
+            .collect();
+
+        Ok(Self {
+            stdins,
+            children,
+            rx,
+            services,
+            next_msg_id: 1,
+            trace: trace.then(Trace::new),
+            started: Instant::now(),
+        })
+    }
+
+    fn init(&mut self) -> anyhow::Result<()> {
+        let node_ids: Vec<String> = self.stdins.keys().cloned().collect();
+        for node_id in &node_ids {
+            let msg_id = self.next_msg_id;
+            self.next_msg_id += 1;
+            self.send(Message {
+                src: "c0".to_string(),
+                dst: node_id.clone(),
+                body: Body {
+                    id: Some(msg_id),
+                    in_reply_to: None,
+                    clock: Default::default(),
+                    payload: json!({"type": "init", "node_id": node_id, "node_ids": node_ids}),
+                },
+            })?;
+        }
+
+        let mut waiting = node_ids.len();
+        while waiting > 0 {
+            let msg = self
+                .recv(Duration::from_secs(10))?
+                .context("timed out waiting for init_ok")?;
+            if msg.dst == "c0" && msg.body.payload["type"] == "init_ok" {
+                waiting -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, msg: RawMessage) -> anyhow::Result<()> {
+        self.record(&msg);
+        if let Some(service) = self.services.get_mut(msg.dst.as_str()) {
+            let reply = service.serve(msg);
+            return self.send(reply);
+        }
+
+        let Some(stdin) = self.stdins.get_mut(&msg.dst) else {
+            trace::warn(
+                "dropping message to unknown node",
+                json!({"src": msg.src, "dest": msg.dst}),
+            );
+            return Ok(());
+        };
+        msg.send(stdin)
+            .with_context(|| format!("send message to {}", msg.dst))?;
+        stdin.flush().context("flush node stdin")
+    }
+
+    // Next message addressed outside the cluster, routing node-to-node traffic on the way
+    fn recv(&mut self, timeout: Duration) -> anyhow::Result<Option<RawMessage>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let (node, line) = match self.rx.recv_timeout(left) {
+                Ok(received) => received,
+                Err(mpsc::RecvTimeoutError::Timeout) => return Ok(None),
+                Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("all nodes exited"),
+            };
+            let line = line.with_context(|| format!("node {} exited", node))?;
+            let msg: RawMessage = serde_json::from_str(&line)
+                .with_context(|| format!("node {} wrote an invalid message: {}", node, line))?;
+
+            if self.stdins.contains_key(&msg.dst) || self.services.contains_key(msg.dst.as_str()) {
+                self.send(msg)?;
+            } else {
+                self.record(&msg);
+                return Ok(Some(msg));
+            }
+        }
+    }
+
+    fn record(&mut self, msg: &RawMessage) {
+        let Some(trace) = &mut self.trace else {
+            return;
+        };
+        let now = self.started.elapsed();
+        let body = serde_json::to_value(&msg.body).unwrap_or_default();
+        let hop = trace.sent(msg.src.clone(), msg.dst.clone(), body, now);
+        trace.received(hop, now);
+    }
+
+    // Closes every stdin and waits for the nodes to exit, handing back the trace
+    fn shutdown(mut self) -> anyhow::Result<Option<Trace>> {
+        self.stdins.clear();
+        for (node_id, mut child) in self.children {
+            let status = child.wait().context("wait for node to exit")?;
+            if !status.success() {
+                trace::warn(
+                    "node exited with an error",
+                    json!({"node": node_id, "status": status.to_string()}),
+                );
+            }
+        }
+        Ok(self.trace)
+    }
+}
+
+fn summarize(history: &History, elapsed: Duration) {
+    let mut by_f: BTreeMap<&str, [usize; 3]> = BTreeMap::new();
+    let mut latencies = Vec::new();
+    let operations = history.operations();
+
+    for op in &operations {
+        let counts = by_f.entry(op.f.as_str()).or_default();
+        match op.kind {
+            OpKind::Ok => counts[0] += 1,
+            OpKind::Fail => counts[1] += 1,
+            _ => counts[2] += 1,
+        }
+        if let (OpKind::Ok, Some(done)) = (op.kind, op.complete_time) {
+            latencies.push(done - op.invoke_time);
+        }
+    }
+    latencies.sort();
+
+    println!("{:<24} {:>8} {:>8} {:>8}", "f", "ok", "fail", "info");
+    for (f, [ok, fail, info]) in &by_f {
+        println!("{:<24} {:>8} {:>8} {:>8}", f, ok, fail, info);
+    }
+    println!(
+        "{} ops in {:.1?} ({:.1} ops/s)",
+        operations.len(),
+        elapsed,
+        operations.len() as f64 / elapsed.as_secs_f64()
+    );
+    if !latencies.is_empty() {
+        let at = |q: f64| latencies[((latencies.len() - 1) as f64 * q) as usize];
+        println!(
+            "latency: p50 {:.1?}, p99 {:.1?}, max {:.1?}",
+            at(0.5),
+            at(0.99),
+            at(1.0)
+        );
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = parse_args()?;
+    let node_ids: Vec<String> = (0..args.node_count).map(|i| format!("n{}", i)).collect();
+
+    let tracing = args.trace.is_some() || args.diagram.is_some();
+    let mut cluster = Cluster::spawn(&args.bin, &node_ids, tracing)?;
+    cluster.init().context("initialize cluster")?;
+
+    let generator = Generator::new(args.workload, args.seed, &node_ids);
+    let mut driver = Driver::new(generator, &node_ids, args.options);
+    let start = Instant::now();
+
+    while !driver.is_done() {
+        for msg in driver.poll(start.elapsed()) {
+            cluster.send(msg)?;
+        }
+        if let Some(reply) = cluster.recv(Duration::from_millis(1))? {
+            driver.receive(start.elapsed(), &reply);
+        }
+    }
+    let elapsed = start.elapsed();
+    let trace = cluster.shutdown()?;
+
+    if let Some(trace) = &trace {
+        if let Some(path) = &args.trace {
+            let file = std::fs::File::create(path).with_context(|| format!("create {}", path))?;
+            trace.write(std::io::BufWriter::new(file))?;
+        }
+        if let Some(path) = &args.diagram {
+            std::fs::write(path, trace.to_svg()).with_context(|| format!("write {}", path))?;
+        }
+    }
+
+    let history = driver.into_history();
+    if let Some(path) = &args.history {
+        let file = std::fs::File::create(path).with_context(|| format!("create {}", path))?;
+        serde_json::to_writer(file, history.ops()).context("write history")?;
+    }
+    summarize(&history, elapsed);
+
+    let results = checker::check_workload(args.workload, args.consistency, &history);
+    if let Some(path) = &args.results {
+        let file = std::fs::File::create(path).with_context(|| format!("create {}", path))?;
+        serde_json::to_writer_pretty(file, &results).context("write results")?;
+    }
+    println!("{}", results);
+    if !results.valid {
+        anyhow::bail!("analysis invalid");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,388 @@
+use crate::{History, OpKind, Operation};
+
+use serde_json::Value;
+use std::{collections::HashSet, hash::Hash};
+
+// Sequential specification an operation history is checked against
+pub trait Model: Clone + Eq + Hash {
+    // Applies `op` and returns the next state, or `None` when `op` could not
+    // have observed its output in this state. `op.output` is `None` for
+    // indeterminate operations, which accept any outcome.
+    fn step(&self, op: &Operation) -> Option<Self>;
+}
+
+// Single register supporting read, write and cas ([from, to])
+// Values are kept as their JSON text so the state stays hashable
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Register {
+    value: Option<String>,
+}
+
+impl Register {
+    pub fn new(initial: Option<Value>) -> Self {
+        Self {
+            value: initial.map(|v| v.to_string()),
+        }
+    }
+}
+
+impl Model for Register {
+    fn step(&self, op: &Operation) -> Option<Self> {
+        match op.f.as_str() {
+            "read" => match &op.output {
+                None => Some(self.clone()),
+                Some(Value::Null) => self.value.is_none().then(|| self.clone()),
+                Some(v) => {
+                    (self.value.as_deref() == Some(v.to_string().as_str())).then(|| self.clone())
+                }
+            },
+            "write" => Some(Self {
+                value: Some(op.input.to_string()),
+            }),
+            "cas" => {
+                let [from, to] = op.input.as_array()?.as_slice() else {
+                    return None;
+                };
+                (self.value.as_deref() == Some(from.to_string().as_str())).then(|| Self {
+                    value: Some(to.to_string()),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+// Counter supporting add (delta) and read
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Counter {
+    value: i64,
+}
+
+impl Counter {
+    pub fn new(initial: i64) -> Self {
+        Self { value: initial }
+    }
+}
+
+impl Model for Counter {
+    fn step(&self, op: &Operation) -> Option<Self> {
+        match op.f.as_str() {
+            "add" => Some(Self {
+                value: self.value + op.input.as_i64()?,
+            }),
+            "read" => match &op.output {
+                None => Some(self.clone()),
+                Some(v) if v.as_i64()? == self.value => Some(self.clone()),
+                Some(_) => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinearizabilityReport {
+    pub valid: bool,
+    pub operations: usize,
+    // Ops of the longest linearization found before the search got stuck
+    pub linearized: Vec<Operation>,
+    // The op that could not be linearized at that point
+    pub stuck_on: Option<Operation>,
+}
+
+// Doubly linked list of call and return events, as in Wing & Gong's algorithm
+#[derive(Clone, Copy)]
+struct Event {
+    op: usize,
+    is_call: bool,
+    // Index of the return event of a call, if the op completed
+    ret: Option<usize>,
+    prev: usize,
+    next: Option<usize>,
+}
+
+// Wing & Gong's search with Lowe's memoization of (linearized ops, state),
+// as used by Knossos and Porcupine. Failed ops are left out since they had no
+// effect, indeterminate ones may take effect any time after their invocation.
+pub fn check_linearizable<M: Model>(history: &History, model: M) -> LinearizabilityReport {
+    let operations: Vec<Operation> = history
+        .operations()
+        .into_iter()
+        .filter(|op| op.kind != OpKind::Fail)
+        .collect();
+
+    // Events in history order, index 0 is the list head
+    let mut order: Vec<(usize, usize, bool)> = Vec::new();
+    for (i, op) in operations.iter().enumerate() {
+        order.push((op.invoke_index, i, true));
+        if let (OpKind::Ok, Some(at)) = (op.kind, op.complete_index) {
+            order.push((at, i, false));
+        }
+    }
+    order.sort();
+
+    let mut events = vec![Event {
+        op: usize::MAX,
+        is_call: false,
+        ret: None,
+        prev: 0,
+        next: None,
+    }];
+    let mut ret_of = vec![None; operations.len()];
+    for (_, op, is_call) in &order {
+        let at = events.len();
+        events.push(Event {
+            op: *op,
+            is_call: *is_call,
+            ret: None,
+            prev: at - 1,
+            next: None,
+        });
+        events[at - 1].next = Some(at);
+        if !is_call {
+            ret_of[*op] = Some(at);
+        }
+    }
+    for event in events.iter_mut().skip(1) {
+        if event.is_call {
+            event.ret = ret_of[event.op];
+        }
+    }
+
+    let mut remaining_returns = ret_of.iter().flatten().count();
+    let mut linearized = vec![0u64; operations.len().div_ceil(64)];
+    let mut cache: HashSet<(Vec<u64>, M)> = HashSet::new();
+    let mut stack: Vec<(usize, M)> = Vec::new();
+    let mut state = model;
+    let mut entry = events[0].next;
+
+    let mut deepest: Vec<usize> = Vec::new();
+    let mut stuck_on = None;
+
+    while remaining_returns > 0 {
+        let Some(at) = entry else {
+            break;
+        };
+        let event = events[at];
+
+        if event.is_call {
+            if let Some(next) = state.step(&operations[event.op]) {
+                let mut candidate = linearized.clone();
+                candidate[event.op / 64] |= 1 << (event.op % 64);
+
+                if cache.insert((candidate.clone(), next.clone())) {
+                    stack.push((at, state));
+                    state = next;
+                    linearized = candidate;
+                    lift(&mut events, at);
+                    if event.ret.is_some() {
+                        remaining_returns -= 1;
+                    }
+                    entry = events[0].next;
+                    continue;
+                }
+            }
+            entry = event.next;
+        } else {
+            // The op returned before we found a place for it: backtrack
+            if stack.len() >= deepest.len() {
+                deepest = stack.iter().map(|(at, _)| events[*at].op).collect();
+                stuck_on = Some(event.op);
+            }
+
+            let Some((at, previous)) = stack.pop() else {
+                break;
+            };
+            let op = events[at].op;
+            state = previous;
+            linearized[op / 64] &= !(1 << (op % 64));
+            unlift(&mut events, at);
+            if events[at].ret.is_some() {
+                remaining_returns += 1;
+            }
+            entry = events[at].next;
+        }
+    }
+
+    let valid = remaining_returns == 0;
+    LinearizabilityReport {
+        valid,
+        operations: operations.len(),
+        linearized: if valid {
+            Vec::new()
+        } else {
+            deepest.iter().map(|&op| operations[op].clone()).collect()
+        },
+        stuck_on: if valid {
+            None
+        } else {
+            stuck_on.map(|op| operations[op].clone())
+        },
+    }
+}
+
+// Takes a call, and its return, out of the list
+fn lift(events: &mut [Event], call: usize) {
+    unlink(events, call);
+    if let Some(ret) = events[call].ret {
+        unlink(events, ret);
+    }
+}
+
+// Puts them back, in reverse order of removal
+fn unlift(events: &mut [Event], call: usize) {
+    if let Some(ret) = events[call].ret {
+        relink(events, ret);
+    }
+    relink(events, call);
+}
+
+fn unlink(events: &mut [Event], at: usize) {
+    let Event { prev, next, .. } = events[at];
+    events[prev].next = next;
+    if let Some(next) = next {
+        events[next].prev = prev;
+    }
+}
+
+fn relink(events: &mut [Event], at: usize) {
+    let Event { prev, next, .. } = events[at];
+    events[prev].next = Some(at);
+    if let Some(next) = next {
+        events[next].prev = at;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use std::time::Duration;
+    use OpKind::{Fail, Info, Invoke, Ok};
+
+    // Histories here only need the order of their ops, not the times
+    fn history(ops: &[(usize, OpKind, &str, Value)]) -> History {
+        let mut history = History::new();
+        for (process, kind, f, value) in ops {
+            let (process, value, at) = (*process, value.clone(), Duration::ZERO);
+            match kind {
+                OpKind::Invoke => history.invoke(process, *f, value, at),
+                OpKind::Ok => history.ok(process, *f, value, at),
+                OpKind::Fail => history.fail(process, *f, value, at),
+                OpKind::Info => history.info(process, *f, value, at),
+            }
+        }
+        history
+    }
+
+    #[test]
+    fn sequential_register_history_is_linearizable() {
+        let history = history(&[
+            (0, Invoke, "write", json!(1)),
+            (0, Ok, "write", json!(1)),
+            (1, Invoke, "cas", json!([1, 2])),
+            (1, Ok, "cas", json!([1, 2])),
+            (1, Invoke, "cas", json!([1, 3])),
+            (1, Fail, "cas", json!([1, 3])),
+            (0, Invoke, "read", Value::Null),
+            (0, Ok, "read", json!(2)),
+        ]);
+
+        let report = check_linearizable(&history, Register::default());
+        assert!(report.valid);
+        assert_eq!(report.operations, 3);
+    }
+
+    #[test]
+    fn stale_read_is_caught() {
+        let history = history(&[
+            (0, Invoke, "write", json!(1)),
+            (0, Ok, "write", json!(1)),
+            (0, Invoke, "write", json!(2)),
+            (0, Ok, "write", json!(2)),
+            (1, Invoke, "read", Value::Null),
+            (1, Ok, "read", json!(1)),
+        ]);
+
+        let report = check_linearizable(&history, Register::default());
+        assert!(!report.valid);
+        let stuck_on = report.stuck_on.expect("an op is blamed");
+        assert_eq!(
+            (stuck_on.f.as_str(), stuck_on.output),
+            ("read", Some(json!(1)))
+        );
+        assert_eq!(report.linearized.len(), 2);
+    }
+
+    #[test]
+    fn read_concurrent_with_a_write_sees_either_value() {
+        for seen in [Value::Null, json!(1)] {
+            let history = history(&[
+                (0, Invoke, "write", json!(1)),
+                (1, Invoke, "read", Value::Null),
+                (1, Ok, "read", seen.clone()),
+                (0, Ok, "write", json!(1)),
+            ]);
+            assert!(
+                check_linearizable(&history, Register::default()).valid,
+                "{}",
+                seen
+            );
+        }
+
+        let history = history(&[
+            (0, Invoke, "write", json!(1)),
+            (1, Invoke, "read", Value::Null),
+            (1, Ok, "read", json!(2)),
+            (0, Ok, "write", json!(1)),
+        ]);
+        assert!(!check_linearizable(&history, Register::default()).valid);
+    }
+
+    #[test]
+    fn indeterminate_write_takes_effect_once_or_never() {
+        let prefix = [
+            (0, Invoke, "write", json!(1)),
+            (0, Ok, "write", json!(1)),
+            (0, Invoke, "write", json!(2)),
+            (0, Info, "write", json!(2)),
+        ];
+        let reads = |values: [i64; 2]| {
+            let mut ops = prefix.to_vec();
+            for value in values {
+                ops.push((1, Invoke, "read", Value::Null));
+                ops.push((1, Ok, "read", json!(value)));
+            }
+            history(&ops)
+        };
+
+        // Late, or not at all
+        assert!(check_linearizable(&reads([1, 2]), Register::default()).valid);
+        assert!(check_linearizable(&reads([1, 1]), Register::default()).valid);
+        assert!(check_linearizable(&reads([2, 2]), Register::default()).valid);
+        // But not undone
+        assert!(!check_linearizable(&reads([2, 1]), Register::default()).valid);
+    }
+
+    #[test]
+    fn counter_reads_fall_between_the_adds_around_them() {
+        let valid = history(&[
+            (0, Invoke, "add", json!(2)),
+            (1, Invoke, "add", json!(3)),
+            (0, Ok, "add", json!(2)),
+            (2, Invoke, "read", Value::Null),
+            (2, Ok, "read", json!(5)),
+            (1, Ok, "add", json!(3)),
+        ]);
+        assert!(check_linearizable(&valid, Counter::new(0)).valid);
+
+        let invalid = history(&[
+            (0, Invoke, "add", json!(2)),
+            (0, Ok, "add", json!(2)),
+            (2, Invoke, "read", Value::Null),
+            (2, Ok, "read", json!(3)),
+        ]);
+        assert!(!check_linearizable(&invalid, Counter::new(0)).valid);
+    }
+}
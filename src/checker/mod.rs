@@ -0,0 +1,13 @@
+mod linearizable;
+mod txn;
+mod workload;
+
+pub use linearizable::{check_linearizable, Counter, LinearizabilityReport, Model, Register};
+pub use txn::{
+    check_transactions, Anomaly, AnomalyKind, ConsistencyModel, Dependency, DependencyKind,
+    TxnReport,
+};
+pub use workload::{
+    check_broadcast, check_counter, check_echo, check_kafka, check_stats, check_txn,
+    check_unique_ids, check_workload, CheckResult, Results,
+};
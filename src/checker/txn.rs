@@ -0,0 +1,645 @@
+use crate::{History, OpKind, Operation};
+
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Adya's anomalies, as reported by Elle for rw-register histories
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnomalyKind {
+    // Cycle of write-write dependencies
+    G0,
+    // Read of a value written by a failed transaction
+    G1a,
+    // Read of a value later overwritten by the transaction that wrote it
+    G1b,
+    // Cycle of write-write and write-read dependencies
+    G1c,
+    // Cycle with at least one read-write anti-dependency
+    G2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DependencyKind {
+    // The second transaction overwrote a value the first one wrote
+    WriteWrite,
+    // The second transaction read a value the first one wrote
+    WriteRead,
+    // The second transaction overwrote a value the first one read
+    ReadWrite,
+}
+
+// Why one transaction has to come before the next one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub kind: DependencyKind,
+    pub key: Value,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    // For cycles, `dependencies[i]` links `transactions[i]` to the next one,
+    // the last one leading back to the first. G1a and G1b are a writer and a
+    // reader linked by the offending read.
+    pub transactions: Vec<Operation>,
+    pub dependencies: Vec<Dependency>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxnReport {
+    pub valid: bool,
+    pub transactions: usize,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl TxnReport {
+    pub fn has(&self, kind: AnomalyKind) -> bool {
+        self.anomalies.iter().any(|anomaly| anomaly.kind == kind)
+    }
+
+    // Whether the anomalies found are all allowed by `model`
+    pub fn valid_under(&self, model: ConsistencyModel) -> bool {
+        self.anomalies
+            .iter()
+            .all(|anomaly| !model.prohibits(anomaly.kind))
+    }
+}
+
+// Consistency models a txn workload can be checked against, as in
+// Maelstrom's --consistency-models
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConsistencyModel {
+    ReadUncommitted,
+    ReadCommitted,
+    Serializable,
+}
+
+impl ConsistencyModel {
+    pub fn prohibits(&self, kind: AnomalyKind) -> bool {
+        match self {
+            ConsistencyModel::ReadUncommitted => kind == AnomalyKind::G0,
+            ConsistencyModel::ReadCommitted => kind != AnomalyKind::G2,
+            ConsistencyModel::Serializable => true,
+        }
+    }
+}
+
+impl std::str::FromStr for ConsistencyModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "read-uncommitted" => Ok(ConsistencyModel::ReadUncommitted),
+            "read-committed" => Ok(ConsistencyModel::ReadCommitted),
+            "serializable" => Ok(ConsistencyModel::Serializable),
+            _ => anyhow::bail!("unknown consistency model {}", s),
+        }
+    }
+}
+
+// ["r", key, value] or ["w", key, value]
+#[derive(Debug, Clone)]
+struct MicroOp {
+    write: bool,
+    key: Value,
+    value: Value,
+}
+
+impl MicroOp {
+    fn parse(mop: &Value) -> Option<Self> {
+        let [f, key, value] = mop.as_array()?.as_slice() else {
+            return None;
+        };
+        let write = match f.as_str()? {
+            "r" => false,
+            "w" => true,
+            _ => return None,
+        };
+        Some(Self {
+            write,
+            key: key.clone(),
+            value: value.clone(),
+        })
+    }
+
+    // Keys and values are compared by their JSON text
+    fn version(&self) -> (String, String) {
+        (self.key.to_string(), self.value.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    to: usize,
+    kind: DependencyKind,
+    // The micro-op of the source or target transaction the edge comes from
+    txn: usize,
+    mop: usize,
+}
+
+struct Txn {
+    op: Operation,
+    mops: Vec<MicroOp>,
+}
+
+impl Txn {
+    // Reads of keys the transaction hasn't written yet
+    fn external_reads(&self) -> impl Iterator<Item = (usize, &MicroOp)> {
+        let mut written = HashSet::new();
+        let mut read = HashSet::new();
+        self.mops.iter().enumerate().filter(move |(_, mop)| {
+            let key = mop.key.to_string();
+            if mop.write {
+                written.insert(key);
+                false
+            } else {
+                !written.contains(&key) && read.insert(key)
+            }
+        })
+    }
+
+    // Last write to each key, the one other transactions can observe
+    fn final_writes(&self) -> HashMap<String, usize> {
+        self.mops
+            .iter()
+            .enumerate()
+            .filter(|(_, mop)| mop.write)
+            .map(|(i, mop)| (mop.key.to_string(), i))
+            .collect()
+    }
+}
+
+// Checks "txn" operations whose value is a list of ["r"|"w", key, value]
+// micro-ops, as in Maelstrom's txn-rw-register workload. Like Elle, this
+// relies on every value being written at most once per key, so a read tells
+// which transaction it observed.
+//
+// Dependencies are inferred from what can be proven: a transaction reading a
+// value depends on its writer (wr), a transaction that read a key and then
+// overwrote it installs the next version after the one it read (ww), and every
+// other reader of that version must come before it (rw). Reading a key's
+// initial null puts the reader before every writer of that key.
+pub fn check_transactions(history: &History) -> TxnReport {
+    let txns: Vec<Txn> = history
+        .operations()
+        .into_iter()
+        .filter(|op| op.f == "txn")
+        .filter_map(|op| {
+            let mops = match (&op.kind, &op.output) {
+                (OpKind::Ok, Some(output)) => output,
+                _ => &op.input,
+            };
+            let mops = mops
+                .as_array()?
+                .iter()
+                .map(MicroOp::parse)
+                .collect::<Option<Vec<_>>>()?;
+            Some(Txn { op, mops })
+        })
+        .collect();
+
+    // Who wrote each (key, value), and whether that write was its last one
+    let mut writers: HashMap<(String, String), (usize, usize)> = HashMap::new();
+    let mut aborted: HashMap<(String, String), (usize, usize)> = HashMap::new();
+    let mut key_writers: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    let mut intermediate: HashSet<(usize, usize)> = HashSet::new();
+    for (t, txn) in txns.iter().enumerate() {
+        let final_writes = txn.final_writes();
+        for (m, mop) in txn.mops.iter().enumerate().filter(|(_, mop)| mop.write) {
+            if txn.op.kind == OpKind::Fail {
+                aborted.insert(mop.version(), (t, m));
+                continue;
+            }
+            writers.insert(mop.version(), (t, m));
+            if final_writes.get(&mop.key.to_string()) == Some(&m) {
+                key_writers
+                    .entry(mop.key.to_string())
+                    .or_default()
+                    .push((t, m));
+            } else {
+                intermediate.insert((t, m));
+            }
+        }
+    }
+
+    // Only committed reads are observations, reads of info transactions are unknown
+    let mut readers: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    for (t, txn) in txns.iter().enumerate() {
+        if txn.op.kind == OpKind::Ok {
+            for (_, mop) in txn.external_reads() {
+                readers.entry(mop.version()).or_default().push(t);
+            }
+        }
+    }
+
+    let mut anomalies = Vec::new();
+    let mut graph: Vec<Vec<Edge>> = vec![Vec::new(); txns.len()];
+
+    for (t, txn) in txns.iter().enumerate() {
+        if txn.op.kind != OpKind::Ok {
+            continue;
+        }
+        for (_, read) in txn.external_reads() {
+            let version = &read.version();
+            if version.1 == "null" {
+                for &(w, wm) in key_writers.get(&version.0).into_iter().flatten() {
+                    if w != t {
+                        graph[t].push(Edge {
+                            to: w,
+                            kind: DependencyKind::ReadWrite,
+                            txn: w,
+                            mop: wm,
+                        });
+                    }
+                }
+                continue;
+            }
+
+            if let Some(&(w, wm)) = aborted.get(version) {
+                anomalies.push(read_anomaly(AnomalyKind::G1a, &txns, (w, wm), t));
+                continue;
+            }
+            let Some(&(w, wm)) = writers.get(version) else {
+                continue;
+            };
+            if w == t {
+                continue;
+            }
+            if intermediate.contains(&(w, wm)) {
+                anomalies.push(read_anomaly(AnomalyKind::G1b, &txns, (w, wm), t));
+                continue;
+            }
+            graph[w].push(Edge {
+                to: t,
+                kind: DependencyKind::WriteRead,
+                txn: w,
+                mop: wm,
+            });
+        }
+    }
+
+    // Read-then-write within a transaction orders the two versions
+    for (t, txn) in txns.iter().enumerate() {
+        if txn.op.kind == OpKind::Fail {
+            continue;
+        }
+        let final_writes = txn.final_writes();
+        for (_, read) in txn.external_reads() {
+            let Some(&wm) = final_writes.get(&read.key.to_string()) else {
+                continue;
+            };
+            let version = read.version();
+            if let Some(&(w, rm)) = writers.get(&version) {
+                if w != t && !intermediate.contains(&(w, rm)) {
+                    graph[w].push(Edge {
+                        to: t,
+                        kind: DependencyKind::WriteWrite,
+                        txn: t,
+                        mop: wm,
+                    });
+                }
+            }
+            if version.1 == "null" {
+                continue;
+            }
+            for &r in readers.get(&version).into_iter().flatten() {
+                if r != t {
+                    graph[r].push(Edge {
+                        to: t,
+                        kind: DependencyKind::ReadWrite,
+                        txn: t,
+                        mop: wm,
+                    });
+                }
+            }
+        }
+    }
+
+    use DependencyKind::*;
+    for (kind, allowed, required) in [
+        (AnomalyKind::G0, &[WriteWrite][..], WriteWrite),
+        (AnomalyKind::G1c, &[WriteWrite, WriteRead][..], WriteRead),
+        (
+            AnomalyKind::G2,
+            &[WriteWrite, WriteRead, ReadWrite][..],
+            ReadWrite,
+        ),
+    ] {
+        for cycle in find_cycles(&graph, allowed, required) {
+            anomalies.push(Anomaly {
+                kind,
+                transactions: cycle
+                    .iter()
+                    .map(|(from, _)| txns[*from].op.clone())
+                    .collect(),
+                dependencies: cycle
+                    .iter()
+                    .map(|(_, edge)| dependency(&txns, edge))
+                    .collect(),
+            });
+        }
+    }
+
+    TxnReport {
+        valid: anomalies.is_empty(),
+        transactions: txns.len(),
+        anomalies,
+    }
+}
+
+fn read_anomaly(
+    kind: AnomalyKind,
+    txns: &[Txn],
+    (w, wm): (usize, usize),
+    reader: usize,
+) -> Anomaly {
+    Anomaly {
+        kind,
+        transactions: vec![txns[w].op.clone(), txns[reader].op.clone()],
+        dependencies: vec![dependency(
+            txns,
+            &Edge {
+                to: reader,
+                kind: DependencyKind::WriteRead,
+                txn: w,
+                mop: wm,
+            },
+        )],
+    }
+}
+
+fn dependency(txns: &[Txn], edge: &Edge) -> Dependency {
+    let mop = &txns[edge.txn].mops[edge.mop];
+    Dependency {
+        kind: edge.kind,
+        key: mop.key.clone(),
+        value: mop.value.clone(),
+    }
+}
+
+// One cycle per strongly connected component of the `allowed` subgraph that
+// goes through a `required` edge, as (source, edge) pairs in order
+fn find_cycles(
+    graph: &[Vec<Edge>],
+    allowed: &[DependencyKind],
+    required: DependencyKind,
+) -> Vec<Vec<(usize, Edge)>> {
+    let component = components(graph, allowed);
+    let mut done = HashSet::new();
+    let mut cycles = Vec::new();
+
+    for (from, edges) in graph.iter().enumerate() {
+        for edge in edges {
+            let scc = component[from];
+            if edge.kind != required || component[edge.to] != scc || done.contains(&scc) {
+                continue;
+            }
+            let cycle = if edge.to == from {
+                Some(vec![(from, *edge)])
+            } else {
+                path(graph, allowed, &component, edge.to, from)
+                    .map(|path| std::iter::once((from, *edge)).chain(path).collect())
+            };
+            if let Some(cycle) = cycle {
+                done.insert(scc);
+                cycles.push(cycle);
+            }
+        }
+    }
+
+    cycles
+}
+
+// Shortest path between two nodes of the same component
+fn path(
+    graph: &[Vec<Edge>],
+    allowed: &[DependencyKind],
+    component: &[usize],
+    from: usize,
+    to: usize,
+) -> Option<Vec<(usize, Edge)>> {
+    let mut came_from: HashMap<usize, (usize, Edge)> = HashMap::new();
+    let mut queue = VecDeque::from([from]);
+
+    while let Some(at) = queue.pop_front() {
+        if at == to {
+            let mut path = Vec::new();
+            let mut at = to;
+            while at != from {
+                let (prev, edge) = came_from[&at];
+                path.push((prev, edge));
+                at = prev;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for edge in &graph[at] {
+            if allowed.contains(&edge.kind)
+                && component[edge.to] == component[from]
+                && edge.to != from
+                && !came_from.contains_key(&edge.to)
+            {
+                came_from.insert(edge.to, (at, *edge));
+                queue.push_back(edge.to);
+            }
+        }
+    }
+
+    None
+}
+
+// Tarjan's strongly connected components, iterative so long dependency
+// chains don't overflow the stack
+fn components(graph: &[Vec<Edge>], allowed: &[DependencyKind]) -> Vec<usize> {
+    const UNVISITED: usize = usize::MAX;
+    let n = graph.len();
+    let mut index = vec![UNVISITED; n];
+    let mut low = vec![0; n];
+    let mut on_stack = vec![false; n];
+    let mut component = vec![UNVISITED; n];
+    let mut stack = Vec::new();
+    let mut next_index = 0;
+    let mut next_component = 0;
+
+    for root in 0..n {
+        if index[root] != UNVISITED {
+            continue;
+        }
+        // (node, position in its edge list)
+        let mut work = vec![(root, 0)];
+        while let Some(&(at, mut position)) = work.last() {
+            if position == 0 && index[at] == UNVISITED {
+                index[at] = next_index;
+                low[at] = next_index;
+                next_index += 1;
+                stack.push(at);
+                on_stack[at] = true;
+            }
+
+            let mut descend = None;
+            while let Some(edge) = graph[at].get(position) {
+                position += 1;
+                if !allowed.contains(&edge.kind) {
+                    continue;
+                }
+                if index[edge.to] == UNVISITED {
+                    descend = Some(edge.to);
+                    break;
+                }
+                if on_stack[edge.to] {
+                    low[at] = low[at].min(index[edge.to]);
+                }
+            }
+            if let Some(to) = descend {
+                if let Some(top) = work.last_mut() {
+                    top.1 = position;
+                }
+                work.push((to, 0));
+                continue;
+            }
+
+            work.pop();
+            if let Some(&(parent, _)) = work.last() {
+                low[parent] = low[parent].min(low[at]);
+            }
+            if low[at] == index[at] {
+                while let Some(node) = stack.pop() {
+                    on_stack[node] = false;
+                    component[node] = next_component;
+                    if node == at {
+                        break;
+                    }
+                }
+                next_component += 1;
+            }
+        }
+    }
+
+    component
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use std::time::Duration;
+
+    // One process per transaction, each completing before the next is invoked
+    fn history(txns: &[(OpKind, Value)]) -> History {
+        let mut history = History::new();
+        for (process, (kind, txn)) in txns.iter().enumerate() {
+            history.invoke(process, "txn", txn.clone(), Duration::ZERO);
+            match kind {
+                OpKind::Ok => history.ok(process, "txn", txn.clone(), Duration::ZERO),
+                OpKind::Fail => history.fail(process, "txn", txn.clone(), Duration::ZERO),
+                _ => history.info(process, "txn", txn.clone(), Duration::ZERO),
+            }
+        }
+        history
+    }
+
+    fn kinds(report: &TxnReport) -> Vec<AnomalyKind> {
+        report
+            .anomalies
+            .iter()
+            .map(|anomaly| anomaly.kind)
+            .collect()
+    }
+
+    #[test]
+    fn serial_history_has_no_anomalies() {
+        let report = check_transactions(&history(&[
+            (OpKind::Ok, json!([["w", "x", 1]])),
+            (OpKind::Ok, json!([["r", "x", 1], ["w", "x", 2]])),
+            (OpKind::Ok, json!([["r", "x", 2], ["r", "y", null]])),
+            (OpKind::Fail, json!([["w", "y", 3]])),
+            (OpKind::Info, json!([["w", "y", 4]])),
+        ]));
+
+        assert!(report.valid, "{:?}", kinds(&report));
+        assert_eq!(report.transactions, 5);
+        assert!(report.valid_under(ConsistencyModel::Serializable));
+    }
+
+    #[test]
+    fn write_cycle_is_g0() {
+        let report = check_transactions(&history(&[
+            (
+                OpKind::Ok,
+                json!([["r", "x", 2], ["w", "x", 1], ["w", "y", 1]]),
+            ),
+            (
+                OpKind::Ok,
+                json!([["r", "y", 1], ["w", "y", 2], ["w", "x", 2]]),
+            ),
+        ]));
+
+        assert!(report.has(AnomalyKind::G0));
+        assert!(!report.valid_under(ConsistencyModel::ReadUncommitted));
+        let g0 = &report.anomalies[0];
+        assert_eq!(g0.kind, AnomalyKind::G0);
+        assert_eq!(g0.transactions.len(), 2);
+        assert!(g0
+            .dependencies
+            .iter()
+            .all(|dep| dep.kind == DependencyKind::WriteWrite));
+    }
+
+    #[test]
+    fn aborted_read_is_g1a() {
+        let report = check_transactions(&history(&[
+            (OpKind::Fail, json!([["w", "x", 1]])),
+            (OpKind::Ok, json!([["r", "x", 1]])),
+        ]));
+
+        assert_eq!(kinds(&report), [AnomalyKind::G1a]);
+        let g1a = &report.anomalies[0];
+        assert_eq!(g1a.transactions[0].kind, OpKind::Fail);
+        assert_eq!(
+            (&g1a.dependencies[0].key, &g1a.dependencies[0].value),
+            (&json!("x"), &json!(1))
+        );
+    }
+
+    #[test]
+    fn intermediate_read_is_g1b() {
+        let report = check_transactions(&history(&[
+            (OpKind::Ok, json!([["w", "x", 1], ["w", "x", 2]])),
+            (OpKind::Ok, json!([["r", "x", 1]])),
+        ]));
+
+        assert_eq!(kinds(&report), [AnomalyKind::G1b]);
+        assert!(report.valid_under(ConsistencyModel::ReadUncommitted));
+        assert!(!report.valid_under(ConsistencyModel::ReadCommitted));
+    }
+
+    #[test]
+    fn circular_information_flow_is_g1c() {
+        let report = check_transactions(&history(&[
+            (OpKind::Ok, json!([["w", "x", 1], ["r", "y", 1]])),
+            (OpKind::Ok, json!([["w", "y", 1], ["r", "x", 1]])),
+        ]));
+
+        assert_eq!(kinds(&report), [AnomalyKind::G1c]);
+        assert!(report.valid_under(ConsistencyModel::ReadUncommitted));
+        assert!(!report.valid_under(ConsistencyModel::ReadCommitted));
+    }
+
+    #[test]
+    fn write_skew_is_g2() {
+        let report = check_transactions(&history(&[
+            (OpKind::Ok, json!([["r", "x", null], ["w", "y", 1]])),
+            (OpKind::Ok, json!([["r", "y", null], ["w", "x", 1]])),
+        ]));
+
+        assert_eq!(kinds(&report), [AnomalyKind::G2]);
+        assert!(report.valid_under(ConsistencyModel::ReadCommitted));
+        assert!(!report.valid_under(ConsistencyModel::Serializable));
+        assert!(report.anomalies[0]
+            .dependencies
+            .iter()
+            .all(|dep| dep.kind == DependencyKind::ReadWrite));
+    }
+}
@@ -0,0 +1,631 @@
+use super::{check_transactions, AnomalyKind, ConsistencyModel};
+use crate::{History, OpKind, Operation, Workload};
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+// How many offending values a report lists before it stops
+const EXAMPLES: usize = 16;
+
+// Outcome of one checker, with whatever it found worth reporting
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    #[serde(rename = "valid?")]
+    pub valid: bool,
+    #[serde(flatten)]
+    pub details: Map<String, Value>,
+}
+
+impl CheckResult {
+    fn new(valid: bool, details: Value) -> Self {
+        let Value::Object(details) = details else {
+            panic!("check details must be an object");
+        };
+        Self { valid, details }
+    }
+}
+
+// What a run ends with, shaped like Maelstrom's results.edn
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Results {
+    #[serde(rename = "valid?")]
+    pub valid: bool,
+    pub stats: CheckResult,
+    pub workload: CheckResult,
+}
+
+impl std::fmt::Display for Results {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string_pretty(self).map_err(|_| std::fmt::Error)?;
+        writeln!(f, "{}", json)?;
+        if self.valid {
+            write!(f, "Everything looks good! ヽ(‘ー`)ノ")
+        } else {
+            write!(f, "Analysis invalid! (ﾉಥ益ಥ）ﾉ ┻━┻")
+        }
+    }
+}
+
+// `consistency` only applies to the txn workload
+pub fn check_workload(
+    workload: Workload,
+    consistency: ConsistencyModel,
+    history: &History,
+) -> Results {
+    let operations = history.operations();
+    let stats = check_stats(&operations);
+    let workload = match workload {
+        Workload::Echo => check_echo(&operations),
+        Workload::UniqueIds => check_unique_ids(&operations),
+        Workload::Broadcast => check_broadcast(&operations),
+        Workload::Counter => check_counter(&operations),
+        Workload::Kafka => check_kafka(&operations),
+        Workload::Txn => check_txn(history, consistency),
+    };
+
+    Results {
+        valid: stats.valid && workload.valid,
+        stats,
+        workload,
+    }
+}
+
+// Counts per f, invalid when some f never succeeded
+pub fn check_stats(operations: &[Operation]) -> CheckResult {
+    let mut by_f: BTreeMap<&str, [usize; 3]> = BTreeMap::new();
+    for op in operations {
+        let counts = by_f.entry(op.f.as_str()).or_default();
+        match op.kind {
+            OpKind::Ok => counts[0] += 1,
+            OpKind::Fail => counts[1] += 1,
+            _ => counts[2] += 1,
+        }
+    }
+
+    let count = |i: usize| by_f.values().map(|c| c[i]).sum::<usize>();
+    let by_f_json: Map<String, Value> = by_f
+        .iter()
+        .map(|(f, [ok, fail, info])| {
+            let valid = *ok > 0;
+            let counts =
+                json!({"valid?": valid, "ok-count": ok, "fail-count": fail, "info-count": info});
+            (f.to_string(), counts)
+        })
+        .collect();
+
+    CheckResult::new(
+        !by_f.is_empty() && by_f.values().all(|c| c[0] > 0),
+        json!({
+            "count": operations.len(),
+            "ok-count": count(0),
+            "fail-count": count(1),
+            "info-count": count(2),
+            "by-f": by_f_json,
+        }),
+    )
+}
+
+pub fn check_echo(operations: &[Operation]) -> CheckResult {
+    let ok = operations.iter().filter(|op| op.is_ok());
+    let mismatched: Vec<Value> = ok
+        .clone()
+        .filter(|op| op.output.as_ref() != Some(&op.input))
+        .map(|op| json!({"expected": op.input, "received": op.output}))
+        .collect();
+
+    CheckResult::new(
+        mismatched.is_empty(),
+        json!({
+            "ok-count": ok.count(),
+            "mismatched-count": mismatched.len(),
+            "mismatched": examples(mismatched),
+        }),
+    )
+}
+
+// Every acknowledged id has to be distinct
+pub fn check_unique_ids(operations: &[Operation]) -> CheckResult {
+    let ids: Vec<&Value> = operations
+        .iter()
+        .filter(|op| op.f == "generate" && op.is_ok())
+        .filter_map(|op| op.output.as_ref())
+        .collect();
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for id in &ids {
+        *seen.entry(id.to_string()).or_default() += 1;
+    }
+    let mut duplicated: Vec<(String, usize)> =
+        seen.into_iter().filter(|(_, count)| *count > 1).collect();
+    duplicated.sort();
+    let duplicated: Vec<Value> = duplicated
+        .into_iter()
+        .map(|(id, count)| json!([id, count]))
+        .collect();
+
+    CheckResult::new(
+        duplicated.is_empty(),
+        json!({
+            "attempted-count": operations.iter().filter(|op| op.f == "generate").count(),
+            "acknowledged-count": ids.len(),
+            "duplicated-count": duplicated.len(),
+            "duplicated": examples(duplicated),
+        }),
+    )
+}
+
+// Every acknowledged broadcast has to show up in every node's final read
+pub fn check_broadcast(operations: &[Operation]) -> CheckResult {
+    let attempted: BTreeSet<u64> = operations
+        .iter()
+        .filter(|op| op.f == "broadcast")
+        .filter_map(|op| op.input.as_u64())
+        .collect();
+    let acknowledged: BTreeSet<u64> = operations
+        .iter()
+        .filter(|op| op.f == "broadcast" && op.is_ok())
+        .filter_map(|op| op.input.as_u64())
+        .collect();
+
+    let final_reads = final_ops(operations, "read");
+    let mut lost: BTreeSet<u64> = BTreeSet::new();
+    let mut unexpected: BTreeSet<u64> = BTreeSet::new();
+    for read in &final_reads {
+        let seen: BTreeSet<u64> = read
+            .output
+            .as_ref()
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_u64)
+            .collect();
+        lost.extend(acknowledged.difference(&seen));
+        unexpected.extend(seen.difference(&attempted));
+    }
+
+    CheckResult::new(
+        !final_reads.is_empty() && lost.is_empty() && unexpected.is_empty(),
+        json!({
+            "attempt-count": attempted.len(),
+            "acknowledged-count": acknowledged.len(),
+            "final-read-count": final_reads.len(),
+            "lost-count": lost.len(),
+            "lost": examples(lost.into_iter().map(Value::from).collect()),
+            "unexpected-count": unexpected.len(),
+            "unexpected": examples(unexpected.into_iter().map(Value::from).collect()),
+        }),
+    )
+}
+
+// Final reads must equal the sum of acknowledged adds, plus any subset of the
+// indeterminate ones. Earlier reads must fall between the adds that completed
+// before they started and the adds that started before they completed.
+pub fn check_counter(operations: &[Operation]) -> CheckResult {
+    let adds: Vec<&Operation> = operations
+        .iter()
+        .filter(|op| op.f == "add" && op.kind != OpKind::Fail)
+        .collect();
+    let delta = |op: &Operation| op.input.as_i64().unwrap_or(0);
+    let acknowledged: i64 = adds
+        .iter()
+        .filter(|op| op.is_ok())
+        .map(|op| delta(op))
+        .sum();
+    let indeterminate: i64 = adds
+        .iter()
+        .filter(|op| !op.is_ok())
+        .map(|op| delta(op))
+        .sum();
+
+    let final_reads = final_ops(operations, "read");
+    let final_values: Vec<Option<i64>> = final_reads
+        .iter()
+        .map(|op| op.output.as_ref().and_then(Value::as_i64))
+        .collect();
+    let possible = acknowledged..=acknowledged + indeterminate;
+    let wrong_final = final_values
+        .iter()
+        .filter(|value| !value.is_some_and(|v| possible.contains(&v)))
+        .count();
+
+    let mut out_of_bounds = Vec::new();
+    for read in operations.iter().filter(|op| op.f == "read" && op.is_ok()) {
+        let (Some(value), Some(completed)) = (
+            read.output.as_ref().and_then(Value::as_i64),
+            read.complete_time,
+        ) else {
+            continue;
+        };
+        let lower: i64 = adds
+            .iter()
+            .filter(|add| add.is_ok() && add.complete_time.is_some_and(|t| t < read.invoke_time))
+            .map(|add| delta(add))
+            .sum();
+        let upper: i64 = adds
+            .iter()
+            .filter(|add| add.invoke_time < completed)
+            .map(|add| delta(add))
+            .sum();
+        if !(lower..=upper).contains(&value) {
+            out_of_bounds.push(json!({"value": value, "lower": lower, "upper": upper}));
+        }
+    }
+
+    CheckResult::new(
+        !final_reads.is_empty() && wrong_final == 0 && out_of_bounds.is_empty(),
+        json!({
+            "acknowledged-sum": acknowledged,
+            "indeterminate-sum": indeterminate,
+            "final-reads": final_values,
+            "wrong-final-read-count": wrong_final,
+            "out-of-bounds-count": out_of_bounds.len(),
+            "out-of-bounds": examples(out_of_bounds),
+        }),
+    )
+}
+
+// Sends must get distinct offsets per key, polls must return offsets in order
+// and agree with the sends, without skipping acknowledged ones, and every
+// acknowledged send below the highest polled offset must have been polled
+pub fn check_kafka(operations: &[Operation]) -> CheckResult {
+    // key -> offset -> message, as acknowledged by sends
+    let mut log: HashMap<String, BTreeMap<u64, Value>> = HashMap::new();
+    let mut duplicate_offsets = Vec::new();
+    for op in operations.iter().filter(|op| op.f == "send" && op.is_ok()) {
+        let Some(output) = &op.output else {
+            continue;
+        };
+        let (Some(key), Some(offset)) = (output[0].as_str(), output[1][0].as_u64()) else {
+            continue;
+        };
+        let msg = output[1][1].clone();
+        let entries = log.entry(key.to_string()).or_default();
+        match entries.get(&offset) {
+            Some(existing) if *existing != msg => duplicate_offsets
+                .push(json!({"key": key, "offset": offset, "msgs": [existing, msg]})),
+            _ => {
+                entries.insert(offset, msg);
+            }
+        }
+    }
+
+    let mut reordered = Vec::new();
+    let mut skipped = Vec::new();
+    let mut inconsistent = Vec::new();
+    let mut polled: HashMap<String, HashSet<u64>> = HashMap::new();
+    let mut highest: HashMap<String, u64> = HashMap::new();
+
+    for op in operations.iter().filter(|op| op.f == "poll" && op.is_ok()) {
+        let Some(Value::Object(msgs)) = &op.output else {
+            continue;
+        };
+        for (key, entries) in msgs {
+            let entries: Vec<(u64, &Value)> = entries
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| Some((entry[0].as_u64()?, &entry[1])))
+                .collect();
+            let sent = log.get(key);
+
+            for pair in entries.windows(2) {
+                let (prev, next) = (pair[0].0, pair[1].0);
+                if next <= prev {
+                    reordered.push(json!({"key": key, "offsets": [prev, next]}));
+                } else if let Some(sent) = sent {
+                    if let Some((missing, _)) = sent.range(prev + 1..next).next() {
+                        skipped
+                            .push(json!({"key": key, "offsets": [prev, next], "skipped": missing}));
+                    }
+                }
+            }
+
+            for &(offset, msg) in &entries {
+                if let Some(expected) = sent.and_then(|sent| sent.get(&offset)) {
+                    if expected != msg {
+                        inconsistent.push(
+                            json!({"key": key, "offset": offset, "sent": expected, "polled": msg}),
+                        );
+                    }
+                }
+                polled.entry(key.clone()).or_default().insert(offset);
+                let top = highest.entry(key.clone()).or_default();
+                *top = (*top).max(offset);
+            }
+        }
+    }
+
+    let mut lost = Vec::new();
+    for (key, entries) in &log {
+        let (Some(top), seen) = (highest.get(key), polled.get(key)) else {
+            continue;
+        };
+        for (offset, msg) in entries.range(..=top) {
+            if !seen.is_some_and(|seen| seen.contains(offset)) {
+                lost.push(json!({"key": key, "offset": offset, "msg": msg}));
+            }
+        }
+    }
+
+    let valid = duplicate_offsets.is_empty()
+        && reordered.is_empty()
+        && skipped.is_empty()
+        && inconsistent.is_empty()
+        && lost.is_empty();
+    CheckResult::new(
+        valid,
+        json!({
+            "send-count": log.values().map(BTreeMap::len).sum::<usize>(),
+            "duplicate-offset-count": duplicate_offsets.len(),
+            "duplicate-offsets": examples(duplicate_offsets),
+            "reordered-count": reordered.len(),
+            "reordered": examples(reordered),
+            "skipped-count": skipped.len(),
+            "skipped": examples(skipped),
+            "inconsistent-count": inconsistent.len(),
+            "inconsistent": examples(inconsistent),
+            "lost-count": lost.len(),
+            "lost": examples(lost),
+        }),
+    )
+}
+
+// Anomalies are all reported, only those `consistency` prohibits invalidate the run
+pub fn check_txn(history: &History, consistency: ConsistencyModel) -> CheckResult {
+    let report = check_transactions(history);
+    let mut anomalies: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for anomaly in &report.anomalies {
+        let steps: Vec<Value> = anomaly
+            .transactions
+            .iter()
+            .zip(&anomaly.dependencies)
+            .map(|(op, dep)| {
+                json!({
+                    "process": op.process,
+                    "value": op.output.as_ref().unwrap_or(&op.input),
+                    "then": format!("{:?}", dep.kind),
+                    "key": dep.key,
+                    "on": dep.value,
+                })
+            })
+            .collect();
+        anomalies
+            .entry(format!("{:?}", anomaly.kind))
+            .or_default()
+            .push(json!(steps));
+    }
+    let types: Vec<String> = [
+        AnomalyKind::G0,
+        AnomalyKind::G1a,
+        AnomalyKind::G1b,
+        AnomalyKind::G1c,
+        AnomalyKind::G2,
+    ]
+    .into_iter()
+    .filter(|kind| report.has(*kind))
+    .map(|kind| format!("{:?}", kind))
+    .collect();
+
+    CheckResult::new(
+        report.valid_under(consistency),
+        json!({
+            "consistency-model": format!("{:?}", consistency),
+            "txn-count": report.transactions,
+            "anomaly-types": types,
+            "anomalies": anomalies
+                .into_iter()
+                .map(|(kind, found)| (kind, Value::from(examples(found))))
+                .collect::<Map<String, Value>>(),
+        }),
+    )
+}
+
+// The final requests of `f` that succeeded
+fn final_ops<'a>(operations: &'a [Operation], f: &str) -> Vec<&'a Operation> {
+    operations
+        .iter()
+        .filter(|op| op.f == f && op.is_final && op.is_ok())
+        .collect()
+}
+
+fn examples(mut values: Vec<Value>) -> Vec<Value> {
+    values.truncate(EXAMPLES);
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    // Invokes `f` at `from` and completes it at `to` (ms), as info when `output` is None
+    fn op(
+        history: &mut History,
+        process: usize,
+        f: &str,
+        input: Value,
+        output: Option<Value>,
+        (from, to): (u64, u64),
+    ) {
+        history.invoke(process, f, input.clone(), Duration::from_millis(from));
+        let to = Duration::from_millis(to);
+        match output {
+            Some(output) => history.ok(process, f, output, to),
+            None => history.info(process, f, input, to),
+        }
+    }
+
+    fn final_read(history: &mut History, process: usize, output: Value, at: u64) {
+        history.invoke_final(process, "read", Value::Null, Duration::from_millis(at));
+        history.ok(process, "read", output, Duration::from_millis(at + 1));
+    }
+
+    fn count(result: &CheckResult, field: &str) -> u64 {
+        result.details[field].as_u64().expect("a count")
+    }
+
+    #[test]
+    fn broadcast_final_reads_hold_every_acknowledged_message() {
+        let mut history = History::new();
+        op(
+            &mut history,
+            0,
+            "broadcast",
+            json!(1),
+            Some(json!(1)),
+            (0, 1),
+        );
+        op(
+            &mut history,
+            1,
+            "broadcast",
+            json!(2),
+            Some(json!(2)),
+            (0, 2),
+        );
+        op(&mut history, 0, "broadcast", json!(3), None, (2, 3));
+        final_read(&mut history, 0, json!([1, 2, 3]), 10);
+        final_read(&mut history, 1, json!([2, 1]), 10);
+
+        let result = check_broadcast(&history.operations());
+        assert!(result.valid, "{:?}", result);
+        assert_eq!(count(&result, "final-read-count"), 2);
+    }
+
+    #[test]
+    fn broadcast_lost_and_unexpected_messages_are_invalid() {
+        let mut history = History::new();
+        op(
+            &mut history,
+            0,
+            "broadcast",
+            json!(1),
+            Some(json!(1)),
+            (0, 1),
+        );
+        op(
+            &mut history,
+            1,
+            "broadcast",
+            json!(2),
+            Some(json!(2)),
+            (0, 2),
+        );
+        final_read(&mut history, 0, json!([1, 2]), 10);
+        final_read(&mut history, 1, json!([1, 9]), 10);
+
+        let result = check_broadcast(&history.operations());
+        assert!(!result.valid);
+        assert_eq!(result.details["lost"], json!([2]));
+        assert_eq!(result.details["unexpected"], json!([9]));
+
+        // Without final reads there's nothing to go by
+        let mut history = History::new();
+        op(
+            &mut history,
+            0,
+            "broadcast",
+            json!(1),
+            Some(json!(1)),
+            (0, 1),
+        );
+        assert!(!check_broadcast(&history.operations()).valid);
+    }
+
+    #[test]
+    fn counter_reads_within_bounds_are_valid() {
+        let mut history = History::new();
+        op(&mut history, 0, "add", json!(2), Some(json!(2)), (0, 1));
+        op(&mut history, 1, "add", json!(3), Some(json!(3)), (2, 6));
+        // Concurrent with the second add, may or may not see it
+        op(&mut history, 0, "read", Value::Null, Some(json!(2)), (3, 4));
+        op(&mut history, 0, "read", Value::Null, Some(json!(5)), (4, 5));
+        // Indeterminate, final reads may or may not count it
+        op(&mut history, 2, "add", json!(4), None, (7, 8));
+        final_read(&mut history, 0, json!(5), 20);
+        final_read(&mut history, 1, json!(9), 20);
+
+        let result = check_counter(&history.operations());
+        assert!(result.valid, "{:?}", result);
+        assert_eq!(count(&result, "acknowledged-sum"), 5);
+        assert_eq!(count(&result, "indeterminate-sum"), 4);
+    }
+
+    #[test]
+    fn counter_reads_out_of_bounds_are_invalid() {
+        let mut history = History::new();
+        op(&mut history, 0, "add", json!(2), Some(json!(2)), (0, 1));
+        // Nothing was added before it completed but the first add
+        op(&mut history, 1, "read", Value::Null, Some(json!(7)), (2, 3));
+        op(&mut history, 0, "add", json!(3), Some(json!(3)), (4, 5));
+        final_read(&mut history, 0, json!(4), 20);
+
+        let result = check_counter(&history.operations());
+        assert!(!result.valid);
+        assert_eq!(count(&result, "wrong-final-read-count"), 1);
+        // The final read is out of bounds as well
+        assert_eq!(count(&result, "out-of-bounds-count"), 2);
+    }
+
+    fn send(history: &mut History, process: usize, key: &str, msg: u64, offset: u64, at: u64) {
+        let input = json!([key, msg]);
+        let output = json!([key, [offset, msg]]);
+        op(history, process, "send", input, Some(output), (at, at + 1));
+    }
+
+    fn poll(history: &mut History, process: usize, msgs: Value, at: u64) {
+        op(
+            history,
+            process,
+            "poll",
+            json!({}),
+            Some(msgs),
+            (at, at + 1),
+        );
+    }
+
+    #[test]
+    fn kafka_polls_agreeing_with_sends_are_valid() {
+        let mut history = History::new();
+        send(&mut history, 0, "k1", 10, 0, 0);
+        send(&mut history, 1, "k1", 11, 1, 0);
+        send(&mut history, 0, "k2", 20, 5, 2);
+        poll(
+            &mut history,
+            1,
+            json!({"k1": [[0, 10], [1, 11]], "k2": [[5, 20]]}),
+            4,
+        );
+        poll(&mut history, 0, json!({"k1": [[1, 11]]}), 6);
+
+        let result = check_kafka(&history.operations());
+        assert!(result.valid, "{:?}", result);
+        assert_eq!(count(&result, "send-count"), 3);
+    }
+
+    #[test]
+    fn kafka_offset_anomalies_are_invalid() {
+        let mut history = History::new();
+        send(&mut history, 0, "k1", 10, 0, 0);
+        send(&mut history, 1, "k1", 11, 0, 0);
+        send(&mut history, 0, "k1", 12, 1, 2);
+        send(&mut history, 0, "k1", 13, 2, 4);
+        send(&mut history, 0, "k1", 14, 3, 6);
+        poll(&mut history, 1, json!({"k1": [[1, 12], [0, 10]]}), 8);
+        poll(&mut history, 1, json!({"k1": [[1, 99], [3, 14]]}), 10);
+
+        let result = check_kafka(&history.operations());
+        assert!(!result.valid);
+        for field in [
+            "duplicate-offset-count",
+            "reordered-count",
+            "skipped-count",
+            "inconsistent-count",
+            "lost-count",
+        ] {
+            assert_eq!(count(&result, field), 1, "{}: {:?}", field, result);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::Message;
+
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+// Which logical clocks a node keeps. The library ticks them on every send and
+// receive and stamps them on outgoing messages, for nodes to order concurrent
+// events by and for tooling to reconstruct happens-before from a trace.
+//
+// Stamps are extra body fields, so only turn clocks on where whoever receives
+// the messages tolerates them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clocks {
+    pub lamport: bool,
+    pub vector: bool,
+}
+
+impl Default for Clocks {
+    fn default() -> Self {
+        Self {
+            lamport: true,
+            vector: true,
+        }
+    }
+}
+
+// Clock values a message was sent at
+// {"type":"gossip","lamport":7,"vclock":{"n0":3,"n1":4}}
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stamp {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamport: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vclock: Option<VectorClock>,
+}
+
+impl Stamp {
+    pub fn is_empty(&self) -> bool {
+        self.lamport.is_none() && self.vclock.is_none()
+    }
+}
+
+// Events seen per node. Ordered by happens-before, clocks of concurrent
+// events don't compare.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or_default()
+    }
+
+    pub fn tick(&mut self, node: &str) {
+        *self.0.entry(node.to_string()).or_default() += 1;
+    }
+
+    // Pointwise max, what a node knows after hearing from `other`
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, &n) in &other.0 {
+            let seen = self.0.entry(node.clone()).or_default();
+            *seen = (*seen).max(n);
+        }
+    }
+
+    pub fn concurrent(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.0.iter().map(|(node, &n)| (node.as_str(), n))
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let nodes = self.0.keys().chain(other.0.keys());
+        let (mut less, mut greater) = (false, false);
+        for node in nodes {
+            match self.get(node).cmp(&other.get(node)) {
+                Ordering::Less => less = true,
+                Ordering::Greater => greater = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (less, greater) {
+            (false, false) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (true, true) => None,
+        }
+    }
+}
+
+// The clocks of one node, kept by whatever runs it (a loop, or the simulator
+// for each of its nodes) and entered by the threads stepping it
+#[derive(Clone)]
+pub(crate) struct NodeClock(Arc<Mutex<ClockState>>);
+
+struct ClockState {
+    node: String,
+    clocks: Clocks,
+    lamport: u64,
+    vector: VectorClock,
+}
+
+impl NodeClock {
+    // Starts `node`'s clocks from zero, if it keeps any
+    pub(crate) fn new(node: &str, clocks: Option<Clocks>) -> Option<Self> {
+        Some(Self(Arc::new(Mutex::new(ClockState {
+            node: node.to_string(),
+            clocks: clocks?,
+            lamport: 0,
+            vector: VectorClock::default(),
+        }))))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, ClockState> {
+        self.0.lock().expect("clock poisoned")
+    }
+}
+
+thread_local! {
+    static ENTERED: RefCell<Option<NodeClock>> = const { RefCell::new(None) };
+}
+
+tokio::task_local! {
+    // Async handlers move between threads
+    static TASK_CLOCK: Option<NodeClock>;
+}
+
+// Clock of the node stepped on this thread until dropped, see `enter`
+pub(crate) struct Entered {
+    outer: Option<NodeClock>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        ENTERED.with(|current| current.replace(self.outer.take()));
+    }
+}
+
+// Makes `clock` the one messages sent and stepped on this thread tick
+pub(crate) fn enter(clock: Option<NodeClock>) -> Entered {
+    Entered {
+        outer: ENTERED.with(|current| current.replace(clock)),
+    }
+}
+
+// Same as `enter`, for the future of an async handler
+pub(crate) async fn scope<T>(clock: Option<NodeClock>, f: impl Future<Output = T>) -> T {
+    TASK_CLOCK.scope(clock, f).await
+}
+
+fn with_current<T>(f: impl FnOnce(&mut ClockState) -> T) -> Option<T> {
+    let clock = TASK_CLOCK
+        .try_with(Clone::clone)
+        .unwrap_or_else(|_| ENTERED.with(|current| current.borrow().clone()))?;
+    let mut state = clock.state();
+    Some(f(&mut state))
+}
+
+// Current Lamport time of the node being stepped, if it keeps one
+pub fn lamport() -> Option<u64> {
+    with_current(|clock| clock.clocks.lamport.then_some(clock.lamport)).flatten()
+}
+
+// Current vector time of the node being stepped, if it keeps one
+pub fn vector() -> Option<VectorClock> {
+    with_current(|clock| clock.clocks.vector.then(|| clock.vector.clone())).flatten()
+}
+
+// Sending is an event of the sender, whose clocks go out with the message
+pub(crate) fn sent<P>(msg: &mut Message<P>) {
+    with_current(|clock| {
+        if clock.node != msg.src {
+            return;
+        }
+        if clock.clocks.lamport {
+            clock.lamport += 1;
+            msg.body.clock.lamport = Some(clock.lamport);
+        }
+        if clock.clocks.vector {
+            clock.vector.tick(&msg.src);
+            msg.body.clock.vclock = Some(clock.vector.clone());
+        }
+    });
+}
+
+// Taking up a message stamped with `stamp` moves the receiver's clocks past
+// the sender's. Ticked as the node steps it (or gets it as the reply it
+// waits on), not when it is read.
+pub(crate) fn received(stamp: &Stamp) {
+    with_current(|clock| {
+        if clock.clocks.lamport {
+            clock.lamport = clock.lamport.max(stamp.lamport.unwrap_or_default()) + 1;
+        }
+        if clock.clocks.vector {
+            if let Some(vclock) = &stamp.vclock {
+                clock.vector.merge(vclock);
+            }
+            let node = clock.node.clone();
+            clock.vector.tick(&node);
+        }
+    });
+}
@@ -0,0 +1,594 @@
+use crate::{
+    codec, trace,
+    transport::{ChannelReader, Transport},
+    workload::grid_topology,
+    Body, Codec, Init, Message,
+};
+
+use anyhow::Context;
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Addresses of every node of a standalone cluster:
+// {"nodes": {"n0": {"peer": "127.0.0.1:7000", "client": "127.0.0.1:8000"}, ...}}
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterConfig {
+    pub nodes: BTreeMap<String, NodeAddresses>,
+    // Neighbours of each node, Maelstrom's grid if not given
+    #[serde(default)]
+    pub topology: Option<HashMap<String, Vec<String>>>,
+    // What every connection of the cluster speaks, peers and clients alike,
+    // JSON lines if not given (see codec::by_name), eg: "codec": "msgpack"
+    #[serde(default)]
+    pub codec: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeAddresses {
+    // Where the other nodes reach this one
+    pub peer: String,
+    // Where clients connect to send requests, if anywhere
+    #[serde(default)]
+    pub client: Option<String>,
+}
+
+impl ClusterConfig {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let config = std::fs::read_to_string(path)
+            .with_context(|| format!("read cluster config {}", path))?;
+        serde_json::from_str(&config).with_context(|| format!("parse cluster config {}", path))
+    }
+
+    // The init Maelstrom would have sent
+    pub fn init(&self, node_id: &str) -> anyhow::Result<Init> {
+        anyhow::ensure!(
+            self.nodes.contains_key(node_id),
+            "node {} is not in the cluster config",
+            node_id
+        );
+        Ok(Init {
+            node_id: node_id.to_string(),
+            node_ids: self.nodes.keys().cloned().collect(),
+        })
+    }
+
+    pub fn codec(&self) -> anyhow::Result<Codec> {
+        match &self.codec {
+            Some(name) => codec::by_name(name),
+            None => Ok(Codec::JsonLines),
+        }
+    }
+
+    // The topology Maelstrom would have sent once the cluster was up
+    pub fn topology(&self) -> HashMap<String, Vec<String>> {
+        match &self.topology {
+            Some(topology) => topology.clone(),
+            None => grid_topology(&self.nodes.keys().cloned().collect::<Vec<_>>()),
+        }
+    }
+}
+
+// Sender of the synthesized init and topology, their replies go nowhere
+const CLUSTER: &str = "cluster";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(100);
+// How long to wait before trying a peer that refused us again, doubling up to the max
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+
+// Runs a node as one process of a networked cluster instead of under Maelstrom.
+// Peers exchange messages over TCP, one connection per direction, and clients
+// connect to the client port and get replies on the same connection, routed by
+// the `src` they used. Messages to unreachable peers are dropped, like on any
+// network, and the cluster has no seq-kv/lin-kv/lww-kv to talk to.
+pub struct ClusterTransport {
+    config: ClusterConfig,
+    node_id: String,
+    codec: Codec,
+}
+
+impl ClusterTransport {
+    pub fn new(config: ClusterConfig, node_id: impl Into<String>) -> anyhow::Result<Self> {
+        let node_id = node_id.into();
+        config.init(&node_id)?;
+        let codec = config.codec()?;
+        Ok(Self {
+            config,
+            node_id,
+            codec,
+        })
+    }
+}
+
+impl Transport for ClusterTransport {
+    fn split(self: Box<Self>) -> anyhow::Result<(Box<dyn BufRead + Send>, Box<dyn Write + Send>)> {
+        // Everything the node receives, from peers and clients alike, in arrival order
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let clients: Clients = Arc::default();
+
+        let init = self.config.init(&self.node_id)?;
+        let setup = [
+            json!({"type": "init", "node_id": init.node_id, "node_ids": init.node_ids}),
+            json!({"type": "topology", "topology": self.config.topology()}),
+        ];
+        for (msg_id, payload) in setup.into_iter().enumerate() {
+            let msg = Message {
+                src: CLUSTER.to_string(),
+                dst: init.node_id.clone(),
+                body: Body {
+                    id: Some(msg_id),
+                    in_reply_to: None,
+                    clock: Default::default(),
+                    payload,
+                },
+            };
+            let mut frame = Vec::new();
+            self.codec
+                .encode(&msg, &mut frame)
+                .context("serialize setup message")?;
+            tx.send(frame).expect("receiver is alive");
+        }
+
+        let addresses = &self.config.nodes[&self.node_id];
+        let peers = TcpListener::bind(&addresses.peer)
+            .with_context(|| format!("bind peer address {}", addresses.peer))?;
+        let peer_tx = tx.clone();
+        let codec = self.codec;
+        thread::spawn(move || accept(peers, peer_tx, None, codec));
+
+        if let Some(client) = &addresses.client {
+            let listener = TcpListener::bind(client)
+                .with_context(|| format!("bind client address {}", client))?;
+            let clients = clients.clone();
+            let tx = tx.clone();
+            thread::spawn(move || accept(listener, tx, Some(clients), codec));
+        }
+
+        let router = Router {
+            node_id: self.node_id.clone(),
+            codec,
+            peers: self
+                .config
+                .nodes
+                .iter()
+                .filter(|(id, _)| **id != self.node_id)
+                .map(|(id, addresses)| {
+                    let (peer_tx, peer_rx) = mpsc::channel();
+                    let address = addresses.peer.clone();
+                    thread::spawn(move || write_peer(address, peer_rx));
+                    (id.clone(), peer_tx)
+                })
+                .collect(),
+            clients,
+            loopback: tx,
+            written: Vec::new(),
+        };
+
+        Ok((Box::new(ChannelReader::new(rx)), Box::new(router)))
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+}
+
+// Client ids seen on each client connection, so replies find their way back
+type Clients = Arc<Mutex<HashMap<String, (usize, TcpStream)>>>;
+
+fn accept(
+    listener: TcpListener,
+    tx: mpsc::Sender<Vec<u8>>,
+    clients: Option<Clients>,
+    codec: Codec,
+) {
+    for (conn, stream) in listener.incoming().enumerate() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let _ = stream.set_nodelay(true);
+        let tx = tx.clone();
+        let clients = clients.clone();
+        thread::spawn(move || {
+            let Ok(reply_stream) = stream.try_clone() else {
+                return;
+            };
+            let mut input = BufReader::new(stream);
+            while let Ok(Some(frame)) = codec.read_frame(&mut input) {
+                if let Some(clients) = &clients {
+                    if let Some(src) = envelope(codec, &frame).map(|e| e.src) {
+                        if let Ok(stream) = reply_stream.try_clone() {
+                            let mut clients = clients.lock().expect("clients poisoned");
+                            clients.entry(src).or_insert((conn, stream));
+                        }
+                    }
+                }
+                if tx.send(frame).is_err() {
+                    return;
+                }
+            }
+            if let Some(clients) = &clients {
+                let mut clients = clients.lock().expect("clients poisoned");
+                clients.retain(|_, (c, _)| *c != conn);
+            }
+        });
+    }
+}
+
+// Writes the frames for one peer, (re)connecting as needed on its own thread so
+// a peer that is down never holds up the node. Frames that can't be delivered
+// are dropped, backing off between attempts so that costs next to nothing.
+fn write_peer(address: String, frames: mpsc::Receiver<Vec<u8>>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut backoff = RECONNECT_BACKOFF;
+    let mut retry_at = Instant::now();
+
+    for frame in frames {
+        if stream.is_none() && Instant::now() >= retry_at {
+            stream = connect(&address);
+            if stream.is_some() {
+                backoff = RECONNECT_BACKOFF;
+            } else {
+                retry_at = Instant::now() + backoff;
+                backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+            }
+        }
+        let Some(connected) = &mut stream else {
+            continue;
+        };
+        if connected.write_all(&frame).is_err() {
+            // Reconnect on the next frame
+            stream = None;
+        }
+    }
+}
+
+fn connect(address: &str) -> Option<TcpStream> {
+    let address = address.to_socket_addrs().ok()?.next()?;
+    let stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).ok()?;
+    let _ = stream.set_nodelay(true);
+    Some(stream)
+}
+
+// Who a frame is from and to, leaving its body be
+fn envelope(codec: Codec, frame: &[u8]) -> Option<Message<IgnoredAny>> {
+    codec.decode_frame(frame).ok()
+}
+
+// Sends each message the node writes to whoever its dest is
+struct Router {
+    node_id: String,
+    codec: Codec,
+    // Frames for each other node, written by its own thread
+    peers: HashMap<String, mpsc::Sender<Vec<u8>>>,
+    clients: Clients,
+    loopback: mpsc::Sender<Vec<u8>>,
+    // Written but not a whole frame yet
+    written: Vec<u8>,
+}
+
+impl Router {
+    fn route(&mut self, frame: Vec<u8>) {
+        let Some(Message { dst: dest, .. }) = envelope(self.codec, &frame) else {
+            trace::warn("dropping unparseable output frame", json!({}));
+            return;
+        };
+
+        if dest == self.node_id {
+            let _ = self.loopback.send(frame);
+            return;
+        }
+
+        if let Some(peer) = self.peers.get(&dest) {
+            let _ = peer.send(frame);
+            return;
+        }
+
+        let mut clients = self.clients.lock().expect("clients poisoned");
+        if let Some((_, stream)) = clients.get_mut(&dest) {
+            if stream.write_all(&frame).is_err() {
+                clients.remove(&dest);
+            }
+        } else if dest != CLUSTER {
+            trace::warn(
+                "dropping message to unknown destination",
+                json!({"dest": dest}),
+            );
+        }
+    }
+}
+
+impl Write for Router {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.written.extend_from_slice(buf);
+        loop {
+            match self.codec.frame_len(&self.written) {
+                Ok(Some(len)) => {
+                    let frame = self.written.drain(..len).collect();
+                    self.route(frame);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    // Framing is lost for good
+                    self.written.clear();
+                    return Err(std::io::Error::other(e));
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codec::Decoded, main_loop_with, Event, Node, Output};
+
+    use serde::Serialize;
+    use serde_json::Value;
+    use std::io::Read;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Payload {
+        Topology {
+            topology: HashMap<String, Vec<String>>,
+        },
+        Echo {
+            echo: String,
+        },
+        EchoOk {
+            echo: String,
+        },
+        // Asks the other node to echo on behalf of a client
+        Relay {
+            client: String,
+            reply_to: Option<usize>,
+            echo: String,
+        },
+        Relayed {
+            client: String,
+            reply_to: Option<usize>,
+            echo: String,
+        },
+    }
+
+    // Echoes what clients send, by way of the other node of the cluster
+    struct Relay {
+        node: String,
+        peer: String,
+    }
+
+    impl Relay {
+        fn send(
+            &self,
+            dst: &str,
+            in_reply_to: Option<usize>,
+            payload: Payload,
+            output: &mut Output,
+        ) -> anyhow::Result<()> {
+            Message {
+                src: self.node.clone(),
+                dst: dst.to_string(),
+                body: Body {
+                    id: None,
+                    in_reply_to,
+                    clock: Default::default(),
+                    payload,
+                },
+            }
+            .send(output)
+        }
+    }
+
+    impl Node<(), Payload> for Relay {
+        fn from_init(
+            _state: (),
+            init: Init,
+            _inject: mpsc::Sender<Event<Payload>>,
+        ) -> anyhow::Result<Self> {
+            let peer = init
+                .node_ids
+                .iter()
+                .find(|id| **id != init.node_id)
+                .context("no other node")?
+                .clone();
+            Ok(Relay {
+                node: init.node_id,
+                peer,
+            })
+        }
+
+        fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            match input.body.payload {
+                Payload::Echo { echo } => {
+                    let relay = Payload::Relay {
+                        client: input.src,
+                        reply_to: input.body.id,
+                        echo,
+                    };
+                    self.send(&self.peer, None, relay, output)
+                }
+                Payload::Relay {
+                    client,
+                    reply_to,
+                    echo,
+                } => {
+                    let echo = format!("{} via {}", echo, self.node);
+                    let relayed = Payload::Relayed {
+                        client,
+                        reply_to,
+                        echo,
+                    };
+                    self.send(&input.src, None, relayed, output)
+                }
+                Payload::Relayed {
+                    client,
+                    reply_to,
+                    echo,
+                } => self.send(&client, reply_to, Payload::EchoOk { echo }, output),
+                Payload::Topology { .. } | Payload::EchoOk { .. } => Ok(()),
+            }
+        }
+    }
+
+    // An address nothing listens on, until something binds it
+    fn free_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        listener.local_addr().expect("address").to_string()
+    }
+
+    fn config(nodes: &[&str], codec: Option<&str>) -> ClusterConfig {
+        ClusterConfig {
+            nodes: nodes
+                .iter()
+                .map(|id| {
+                    let addresses = NodeAddresses {
+                        peer: free_address(),
+                        client: Some(free_address()),
+                    };
+                    (id.to_string(), addresses)
+                })
+                .collect(),
+            topology: None,
+            codec: codec.map(str::to_string),
+        }
+    }
+
+    fn connect_to(address: &str) -> TcpStream {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match TcpStream::connect(address) {
+                Ok(stream) => return stream,
+                Err(e) if Instant::now() > deadline => panic!("connect {}: {}", address, e),
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    fn next_frame(codec: Codec, input: &mut impl BufRead) -> Message<Value> {
+        match codec.decode::<Value>(input).expect("decode") {
+            Some(Decoded::Message(msg) | Decoded::Raw(msg)) => msg,
+            Some(Decoded::Unknown(unknown)) => panic!("not a message: {}", unknown),
+            None => panic!("closed before a message"),
+        }
+    }
+
+    #[test]
+    fn nodes_start_with_the_init_and_topology_maelstrom_would_send() -> anyhow::Result<()> {
+        let config = config(&["n0", "n1"], Some("msgpack"));
+        assert!(ClusterTransport::new(config.clone(), "n9").is_err());
+
+        let transport = Box::new(ClusterTransport::new(config, "n1")?);
+        let codec = transport.codec();
+        assert_eq!(codec, Codec::MessagePack);
+        let (mut input, _output) = transport.split()?;
+
+        let init = next_frame(codec, &mut input);
+        assert_eq!((init.src.as_str(), init.dst.as_str()), (CLUSTER, "n1"));
+        assert_eq!(
+            init.body.payload,
+            json!({"type": "init", "node_id": "n1", "node_ids": ["n0", "n1"]})
+        );
+
+        // Two nodes are neighbours on Maelstrom's grid
+        let topology = next_frame(codec, &mut input);
+        assert_eq!(
+            (topology.src.as_str(), topology.dst.as_str()),
+            (CLUSTER, "n1")
+        );
+        assert_eq!(
+            topology.body.payload,
+            json!({"type": "topology", "topology": {"n0": ["n1"], "n1": ["n0"]}})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn client_requests_reach_the_peer_and_replies_their_own_connection() -> anyhow::Result<()> {
+        let config = config(&["n0", "n1"], None);
+        let codec = config.codec()?;
+        for id in ["n0", "n1"] {
+            let transport = Box::new(ClusterTransport::new(config.clone(), id)?);
+            thread::spawn(move || main_loop_with::<_, Relay, Payload, ()>((), transport));
+        }
+        // Relays to a peer that isn't listening yet would be dropped
+        for addresses in config.nodes.values() {
+            connect_to(&addresses.peer);
+        }
+
+        let client_address = config.nodes["n0"].client.clone().expect("client address");
+        let clients = ["c1", "c2"].map(|client| {
+            let mut stream = connect_to(&client_address);
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .expect("read timeout");
+            let echo = Message {
+                src: client.to_string(),
+                dst: "n0".to_string(),
+                body: Body {
+                    id: Some(7),
+                    in_reply_to: None,
+                    clock: Default::default(),
+                    payload: json!({"type": "echo", "echo": client}),
+                },
+            };
+            let mut frame = Vec::new();
+            codec.encode(&echo, &mut frame).expect("encode");
+            stream.write_all(&frame).expect("write");
+            (client, BufReader::new(stream))
+        });
+
+        for (client, mut stream) in clients {
+            let reply = next_frame(codec, &mut stream);
+            assert_eq!((reply.src.as_str(), reply.dst.as_str()), ("n0", client));
+            assert_eq!(reply.body.in_reply_to, Some(7));
+            assert_eq!(
+                reply.body.payload,
+                json!({"type": "echo_ok", "echo": format!("{} via n1", client)})
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn frames_for_a_peer_that_is_down_are_dropped_until_the_backoff_is_up() {
+        let address = free_address();
+        let (frames, rx) = mpsc::channel();
+        let writer = {
+            let address = address.clone();
+            thread::spawn(move || write_peer(address, rx))
+        };
+
+        // Refused, so the next attempt waits out the backoff even once the peer is up
+        frames.send(b"down\n".to_vec()).expect("writer is alive");
+        thread::sleep(RECONNECT_BACKOFF / 5);
+        let listener = TcpListener::bind(&address).expect("bind");
+        frames
+            .send(b"backing off\n".to_vec())
+            .expect("writer is alive");
+        thread::sleep(RECONNECT_BACKOFF * 2);
+        frames.send(b"up\n".to_vec()).expect("writer is alive");
+        drop(frames);
+        writer.join().expect("writer panicked");
+
+        let (mut stream, _) = listener.accept().expect("accept");
+        let mut received = String::new();
+        stream.read_to_string(&mut received).expect("read");
+        assert_eq!(received, "up\n");
+    }
+}
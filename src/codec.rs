@@ -0,0 +1,636 @@
+use crate::{Body, Message, RawMessage, Stamp, VectorClock};
+
+use anyhow::Context;
+use serde::{
+    de::DeserializeOwned,
+    ser::{self, Impossible, SerializeMap, SerializeStruct},
+    Deserialize, Serialize, Serializer,
+};
+use serde_json::Value;
+use std::{
+    borrow::Cow,
+    io::{BufRead, ErrorKind},
+};
+
+// How messages are laid out on the wire. JSON lines is what Maelstrom speaks,
+// the binary formats save the (de)serialization of large bodies on transports
+// where both ends agree on them, eg: tcp://127.0.0.1:7000?codec=msgpack
+//
+// Messages are encoded from and decoded into the node's own Payload type, so
+// bodies never go through a `serde_json::Value` on the way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    JsonLines,
+    MessagePack,
+    Cbor,
+    // bincode isn't self describing, while payloads are tagged by their `type`
+    // field, so bodies go as MessagePack inside the bincode envelope
+    Bincode,
+}
+
+// What a codec read off the input
+#[derive(Debug)]
+pub enum Decoded<P> {
+    Message(Message<P>),
+    // A message with a body that isn't a P, eg: the reply of a service
+    Raw(RawMessage),
+    // As much of the frame as could be made sense of
+    Unknown(Value),
+}
+
+pub fn by_name(name: &str) -> anyhow::Result<Codec> {
+    Ok(match name {
+        "json" => Codec::JsonLines,
+        "msgpack" => Codec::MessagePack,
+        "cbor" => Codec::Cbor,
+        "bincode" => Codec::Bincode,
+        _ => anyhow::bail!("unknown codec {}", name),
+    })
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::JsonLines => "json",
+            Codec::MessagePack => "msgpack",
+            Codec::Cbor => "cbor",
+            Codec::Bincode => "bincode",
+        }
+    }
+
+    // Frames end in a newline, and nowhere else. The others are length prefixed.
+    pub fn is_line_delimited(self) -> bool {
+        self == Codec::JsonLines
+    }
+
+    // Appends `msg` to `out` as one frame
+    pub fn encode<P>(self, msg: &Message<P>, out: &mut Vec<u8>) -> anyhow::Result<()>
+    where
+        P: Serialize,
+    {
+        let frame = match self {
+            Codec::JsonLines => {
+                serde_json::to_writer(&mut *out, msg).context("serialize message")?;
+                out.push(b'\n');
+                return Ok(());
+            }
+            Codec::MessagePack => rmp_serde::to_vec_named(&Frame::new(msg, &msg.body.payload))
+                .context("serialize message")?,
+            Codec::Cbor => serde_cbor::to_vec(&Frame::new(msg, &msg.body.payload))
+                .context("serialize message")?,
+            Codec::Bincode => {
+                let body =
+                    rmp_serde::to_vec_named(&msg.body.payload).context("serialize message")?;
+                bincode::serialize(&Frame::new(msg, body)).context("serialize message")?
+            }
+        };
+        write_prefixed(&frame, out)
+    }
+
+    // Reads the next frame, None once the input is exhausted. Errors are for
+    // input that can't be read or framed, frames that aren't messages are `Unknown`.
+    pub fn decode<P>(self, input: &mut dyn BufRead) -> anyhow::Result<Option<Decoded<P>>>
+    where
+        P: DeserializeOwned,
+    {
+        let Some(frame) = self.read_frame(input)? else {
+            return Ok(None);
+        };
+        if let Ok(msg) = self.decode_frame(&frame) {
+            return Ok(Some(Decoded::Message(msg)));
+        }
+        if let Ok(raw) = self.decode_frame(&frame) {
+            return Ok(Some(Decoded::Raw(raw)));
+        }
+        Ok(Some(Decoded::Unknown(self.unknown(&frame))))
+    }
+
+    pub fn read_frame(self, input: &mut dyn BufRead) -> anyhow::Result<Option<Vec<u8>>> {
+        if !self.is_line_delimited() {
+            return read_prefixed(input);
+        }
+        let mut line = Vec::new();
+        match input
+            .read_until(b'\n', &mut line)
+            .context("input could not be read")?
+        {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+
+    // Length of the frame `buf` starts with, None until all of it is there
+    pub fn frame_len(self, buf: &[u8]) -> anyhow::Result<Option<usize>> {
+        if self.is_line_delimited() {
+            return Ok(buf.iter().position(|&b| b == b'\n').map(|end| end + 1));
+        }
+        let Some(len) = buf.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(len.try_into().expect("4 bytes")) as usize;
+        anyhow::ensure!(len <= MAX_FRAME, "frame of {} bytes is too large", len);
+        Ok((buf.len() >= 4 + len).then_some(4 + len))
+    }
+
+    // Decodes a frame as read by `read_frame`
+    pub fn decode_frame<P>(self, frame: &[u8]) -> anyhow::Result<Message<P>>
+    where
+        P: DeserializeOwned,
+    {
+        Ok(match self {
+            Codec::JsonLines => serde_json::from_slice(frame)?,
+            Codec::MessagePack => {
+                let frame: Frame<P> = rmp_serde::from_slice(unprefixed(frame)?)?;
+                frame.into_message(|body| body)
+            }
+            Codec::Cbor => {
+                let frame: Frame<P> = serde_cbor::from_slice(unprefixed(frame)?)?;
+                frame.into_message(|body| body)
+            }
+            Codec::Bincode => {
+                let frame: Frame<Vec<u8>> = bincode::deserialize(unprefixed(frame)?)?;
+                let payload = rmp_serde::from_slice(&frame.body)?;
+                frame.into_message(|_| payload)
+            }
+        })
+    }
+
+    // Frames that aren't messages are handed on as whatever can be made of them.
+    // Lines that aren't even JSON, or UTF-8, are passed on as they are.
+    fn unknown(self, frame: &[u8]) -> Value {
+        match self {
+            Codec::JsonLines => serde_json::from_slice(frame).unwrap_or_else(|_| {
+                Value::String(String::from_utf8_lossy(frame).trim_end().into())
+            }),
+            Codec::MessagePack => unprefixed(frame)
+                .ok()
+                .and_then(|body| rmp_serde::from_slice(body).ok())
+                .unwrap_or_default(),
+            Codec::Cbor => unprefixed(frame)
+                .ok()
+                .and_then(|body| serde_cbor::from_slice(body).ok())
+                .unwrap_or_default(),
+            // Nothing can be made of a bincode frame without knowing its type
+            Codec::Bincode => Value::Null,
+        }
+    }
+}
+
+// The binary codecs send the envelope as fixed fields, so their decoders don't
+// have to buffer the body to find the message type like `flatten` does
+#[derive(Serialize, Deserialize)]
+struct Frame<'a, B> {
+    src: Cow<'a, str>,
+    dest: Cow<'a, str>,
+    msg_id: Option<usize>,
+    in_reply_to: Option<usize>,
+    lamport: Option<u64>,
+    vclock: Option<Cow<'a, VectorClock>>,
+    body: B,
+}
+
+impl<'a, B> Frame<'a, B> {
+    fn new<P>(msg: &'a Message<P>, body: B) -> Self {
+        Self {
+            src: Cow::Borrowed(&msg.src),
+            dest: Cow::Borrowed(&msg.dst),
+            msg_id: msg.body.id,
+            in_reply_to: msg.body.in_reply_to,
+            lamport: msg.body.clock.lamport,
+            vclock: msg.body.clock.vclock.as_ref().map(Cow::Borrowed),
+            body,
+        }
+    }
+
+    fn into_message<P>(self, payload: impl FnOnce(B) -> P) -> Message<P> {
+        Message {
+            src: self.src.into_owned(),
+            dst: self.dest.into_owned(),
+            body: Body {
+                id: self.msg_id,
+                in_reply_to: self.in_reply_to,
+                clock: Stamp {
+                    lamport: self.lamport,
+                    vclock: self.vclock.map(Cow::into_owned),
+                },
+                payload: payload(self.body),
+            },
+        }
+    }
+}
+
+// Guards against allocating whatever a corrupt length prefix says
+const MAX_FRAME: usize = 256 * 1024 * 1024;
+
+// A big endian u32 length, then the frame
+fn write_prefixed(frame: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+    let len = u32::try_from(frame.len()).context("frame too large")?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(frame);
+    Ok(())
+}
+
+// Frames are read with their prefix, as that is how routers pass them on
+fn read_prefixed(input: &mut dyn BufRead) -> anyhow::Result<Option<Vec<u8>>> {
+    // Input may only end between frames
+    if input
+        .fill_buf()
+        .context("input could not be read")?
+        .is_empty()
+    {
+        return Ok(None);
+    }
+    let mut frame = vec![0; 4];
+    input.read_exact(&mut frame).map_err(truncated)?;
+    let len = u32::from_be_bytes(frame[..4].try_into().expect("4 bytes")) as usize;
+    anyhow::ensure!(len <= MAX_FRAME, "frame of {} bytes is too large", len);
+
+    frame.resize(4 + len, 0);
+    input.read_exact(&mut frame[4..]).map_err(truncated)?;
+    Ok(Some(frame))
+}
+
+fn truncated(e: std::io::Error) -> anyhow::Error {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => anyhow::anyhow!("input ended inside a frame"),
+        _ => anyhow::Error::new(e).context("input could not be read"),
+    }
+}
+
+fn unprefixed(frame: &[u8]) -> anyhow::Result<&[u8]> {
+    frame.get(4..).context("frame is missing its length")
+}
+
+// The `type` a payload is tagged with, read off its serialization without
+// serializing the rest of it, eg: for metrics and tracing
+pub(crate) fn payload_type<P>(payload: &P) -> Option<String>
+where
+    P: Serialize + ?Sized,
+{
+    payload.serialize(TypeOf).ok().flatten()
+}
+
+type SerError = serde::de::value::Error;
+
+// Looks at the fields of a struct or map only, payloads that are neither have no type
+struct TypeOf;
+
+// Whatever isn't a struct or a map has no `type` field
+macro_rules! untyped {
+    ($($method:ident: $ty:ty),* $(,)?) => {
+        $(
+            fn $method(self, _: $ty) -> Result<Self::Ok, Self::Error> {
+                Ok(None)
+            }
+        )*
+    };
+}
+
+impl Serializer for TypeOf {
+    type Ok = Option<String>;
+    type Error = SerError;
+    type SerializeSeq = Impossible<Self::Ok, SerError>;
+    type SerializeTuple = Impossible<Self::Ok, SerError>;
+    type SerializeTupleStruct = Impossible<Self::Ok, SerError>;
+    type SerializeTupleVariant = Impossible<Self::Ok, SerError>;
+    type SerializeMap = TypeField;
+    type SerializeStruct = TypeField;
+    type SerializeStructVariant = Impossible<Self::Ok, SerError>;
+
+    untyped! {
+        serialize_bool: bool,
+        serialize_i8: i8,
+        serialize_i16: i16,
+        serialize_i32: i32,
+        serialize_i64: i64,
+        serialize_u8: u8,
+        serialize_u16: u16,
+        serialize_u32: u32,
+        serialize_u64: u64,
+        serialize_f32: f32,
+        serialize_f64: f64,
+        serialize_char: char,
+        serialize_str: &str,
+        serialize_bytes: &[u8],
+        serialize_unit_struct: &'static str,
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        Ok(None)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, Self::Error> {
+        Ok(None)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(ser::Error::custom("untyped"))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Err(ser::Error::custom("untyped"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(ser::Error::custom("untyped"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        Err(ser::Error::custom("untyped"))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(TypeField::default())
+    }
+
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Ok(TypeField::default())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        Err(ser::Error::custom("untyped"))
+    }
+}
+
+// Keeps the `type` field and skips the others, tagged enums write it first
+#[derive(Default)]
+struct TypeField {
+    kind: Option<String>,
+    // The map key just seen was `type`
+    at_type: bool,
+}
+
+impl TypeField {
+    fn field<T: Serialize + ?Sized>(&mut self, value: &T) {
+        if self.kind.is_none() {
+            self.kind = match value.serialize(serde_json::value::Serializer) {
+                Ok(Value::String(kind)) => Some(kind),
+                _ => None,
+            };
+        }
+    }
+}
+
+impl SerializeStruct for TypeField {
+    type Ok = Option<String>;
+    type Error = SerError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        if key == "type" {
+            self.field(value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(self.kind)
+    }
+}
+
+impl SerializeMap for TypeField {
+    type Ok = Option<String>;
+    type Error = SerError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Self::Error> {
+        self.at_type = matches!(
+            key.serialize(serde_json::value::Serializer),
+            Ok(Value::String(key)) if key == "type"
+        );
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Self::Error> {
+        if std::mem::take(&mut self.at_type) {
+            self.field(value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(self.kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::de::IgnoredAny;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    const CODECS: [Codec; 4] = [
+        Codec::JsonLines,
+        Codec::MessagePack,
+        Codec::Cbor,
+        Codec::Bincode,
+    ];
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Payload {
+        Read,
+        ReadOk {
+            messages: Vec<u64>,
+            offsets: BTreeMap<String, i64>,
+            ratio: f64,
+        },
+    }
+
+    fn message<P>(msg_id: usize, payload: P) -> Message<P> {
+        Message {
+            src: "n1".to_string(),
+            dst: "c1".to_string(),
+            body: Body {
+                id: Some(msg_id),
+                in_reply_to: Some(msg_id + 1),
+                clock: Stamp {
+                    lamport: Some(7),
+                    vclock: Some(serde_json::from_value(json!({"n1": 3, "n2": 4})).unwrap()),
+                },
+                payload,
+            },
+        }
+    }
+
+    fn read_ok() -> Payload {
+        Payload::ReadOk {
+            messages: vec![0, 1, u64::MAX],
+            offsets: BTreeMap::from([("k1".to_string(), i64::MIN), ("k2".to_string(), -1)]),
+            ratio: 0.25,
+        }
+    }
+
+    #[test]
+    fn every_codec_round_trips_typed_messages() {
+        for codec in CODECS {
+            let mut wire = Vec::new();
+            codec.encode(&message(1, read_ok()), &mut wire).unwrap();
+            codec.encode(&message(2, Payload::Read), &mut wire).unwrap();
+            // Not a Payload, eg: a reply from a service
+            let other = message(3, json!({"type": "cas_ok", "value": 1.5}));
+            codec.encode(&other, &mut wire).unwrap();
+
+            let mut input = wire.as_slice();
+            let mut decoded = Vec::new();
+            while let Some(next) = codec.decode::<Payload>(&mut input).unwrap() {
+                decoded.push(next);
+            }
+
+            let name = codec.name();
+            assert_eq!(decoded.len(), 3, "{}", name);
+            let Decoded::Message(first) = &decoded[0] else {
+                panic!("{} decoded {:?}", name, decoded[0]);
+            };
+            assert_eq!(first.src, "n1");
+            assert_eq!(first.dst, "c1");
+            assert_eq!(first.body.id, Some(1));
+            assert_eq!(first.body.in_reply_to, Some(2));
+            assert_eq!(first.body.clock, message(1, ()).body.clock, "{}", name);
+            assert_eq!(first.body.payload, read_ok(), "{}", name);
+            assert!(
+                matches!(&decoded[1], Decoded::Message(msg) if msg.body.payload == Payload::Read)
+            );
+            assert!(
+                matches!(&decoded[2], Decoded::Raw(raw) if raw.body.payload == other.body.payload),
+                "{} decoded {:?}",
+                name,
+                decoded[2]
+            );
+        }
+    }
+
+    #[test]
+    fn frames_split_and_envelopes_decode_without_the_body() {
+        for codec in CODECS {
+            let mut wire = Vec::new();
+            codec.encode(&message(1, read_ok()), &mut wire).unwrap();
+            let len = wire.len();
+            codec.encode(&message(2, Payload::Read), &mut wire).unwrap();
+
+            assert_eq!(codec.frame_len(&wire[..len - 1]).unwrap(), None);
+            assert_eq!(codec.frame_len(&wire).unwrap(), Some(len));
+            let envelope: Message<IgnoredAny> = codec.decode_frame(&wire[..len]).unwrap();
+            assert_eq!(envelope.dst, "c1", "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn prefixed_frames_may_only_end_between_frames() {
+        for codec in [Codec::MessagePack, Codec::Cbor, Codec::Bincode] {
+            let mut wire = Vec::new();
+            codec.encode(&message(1, Payload::Read), &mut wire).unwrap();
+
+            let read = |mut input: &[u8]| codec.read_frame(&mut input);
+            assert!(read(&[]).unwrap().is_none());
+            assert_eq!(read(&wire).unwrap().as_deref(), Some(wire.as_slice()));
+            for truncated in [&wire[..2], &wire[..wire.len() - 1]] {
+                let e = read(truncated).unwrap_err();
+                assert_eq!(e.to_string(), "input ended inside a frame");
+            }
+
+            let huge = ((MAX_FRAME + 1) as u32).to_be_bytes();
+            assert!(read(&huge).unwrap_err().to_string().contains("too large"));
+            assert!(codec.frame_len(&huge).is_err());
+        }
+    }
+
+    #[test]
+    fn binary_frames_that_arent_messages_are_unknown() {
+        let mut wire = Vec::new();
+        write_prefixed(&rmp_serde::to_vec(&json!([1, 2])).unwrap(), &mut wire).unwrap();
+        let mut input = wire.as_slice();
+        let decoded = Codec::MessagePack.decode::<Payload>(&mut input).unwrap();
+        assert!(matches!(decoded, Some(Decoded::Unknown(raw)) if raw == json!([1, 2])));
+    }
+
+    #[test]
+    fn payload_type_reads_the_type_field_only() {
+        assert_eq!(payload_type(&read_ok()).as_deref(), Some("read_ok"));
+        assert_eq!(payload_type(&Payload::Read).as_deref(), Some("read"));
+        let raw = json!({"value": [1, 2], "type": "cas"});
+        assert_eq!(payload_type(&raw).as_deref(), Some("cas"));
+        assert_eq!(payload_type(&json!({"type": 1})), None);
+        assert_eq!(payload_type(&json!([1, 2])), None);
+        assert_eq!(payload_type(&()), None);
+    }
+
+    #[test]
+    fn json_lines_pass_on_lines_that_arent_messages() {
+        let mut input: &[u8] = b"{\"src\":\"c1\",\"dest\":\"n1\",\"body\":{\"type\":\"read\"}}\n\
+            [1, 2]\n\
+            not json\n\
+            \xff\xfe\n";
+        let mut decoded = Vec::new();
+        while let Some(next) = Codec::JsonLines
+            .decode::<Value>(&mut input)
+            .expect("decodes")
+        {
+            decoded.push(next);
+        }
+
+        assert_eq!(decoded.len(), 4);
+        assert!(matches!(&decoded[0], Decoded::Message(msg) if msg.dst == "n1"));
+        assert!(matches!(&decoded[1], Decoded::Unknown(raw) if *raw == serde_json::json!([1, 2])));
+        assert!(matches!(&decoded[2], Decoded::Unknown(Value::String(line)) if line == "not json"));
+        assert!(
+            matches!(&decoded[3], Decoded::Unknown(Value::String(line)) if line == "\u{fffd}\u{fffd}")
+        );
+    }
+}
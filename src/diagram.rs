@@ -0,0 +1,470 @@
+use crate::history::nanos;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt::Write as _,
+    hash::{Hash, Hasher},
+    io::{BufRead, Write},
+    time::Duration,
+};
+
+// One message of a trace: {"src":"n0","dest":"n1","sent":1200000,"received":1900000,"body":{...}}
+// Messages that never arrived have no `received`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hop {
+    pub src: String,
+    pub dest: String,
+    #[serde(with = "nanos")]
+    pub sent: Duration,
+    #[serde(with = "nanos::option", default)]
+    pub received: Option<Duration>,
+    pub body: Value,
+}
+
+// Messages in the order they were sent, as recorded by the simulator or the
+// workload runner, or recovered from the json logs of the nodes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    hops: Vec<Hop>,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hops(&self) -> &[Hop] {
+        &self.hops
+    }
+
+    pub fn len(&self) -> usize {
+        self.hops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hops.is_empty()
+    }
+
+    // Records a message leaving `src`, and returns its index for `received`
+    pub fn sent(
+        &mut self,
+        src: impl Into<String>,
+        dest: impl Into<String>,
+        body: Value,
+        at: Duration,
+    ) -> usize {
+        self.hops.push(Hop {
+            src: src.into(),
+            dest: dest.into(),
+            sent: at,
+            received: None,
+            body,
+        });
+        self.hops.len() - 1
+    }
+
+    pub fn received(&mut self, index: usize, at: Duration) {
+        self.hops[index].received = Some(at);
+    }
+
+    // One hop per line
+    pub fn write(&self, mut output: impl Write) -> anyhow::Result<()> {
+        for hop in &self.hops {
+            serde_json::to_writer(&mut output, hop).context("serialize hop")?;
+            output.write_all(b"\n").context("write trace")?;
+        }
+        Ok(())
+    }
+
+    // Reads what `write` wrote, or node logs (see `from_log`)
+    pub fn read(input: impl BufRead) -> anyhow::Result<Self> {
+        let lines: Vec<String> = input
+            .lines()
+            .collect::<Result<_, _>>()
+            .context("read trace")?;
+        let first = lines.iter().find(|line| !line.trim().is_empty());
+        if first.is_none_or(|line| serde_json::from_str::<Hop>(line).is_err()) {
+            return Ok(Self::from_log(&lines));
+        }
+
+        let hops = lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).context("parse hop"))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { hops })
+    }
+
+    // Pairs up the send and recv lines nodes log at debug with
+    // NODE_LOG_FORMAT=json, from any number of nodes. Bodies are only there at
+    // trace level, otherwise hops just carry the message type and ids.
+    //
+    // Clients and services don't log, so their messages only have one side:
+    // those to them count as received right away, those from them as sent the
+    // moment they were received. Messages to a node that never logged their
+    // receipt are lost.
+    pub fn from_log(lines: &[String]) -> Self {
+        let mut events: Vec<(Duration, Map<String, Value>)> = lines
+            .iter()
+            .filter_map(|line| serde_json::from_str::<Map<String, Value>>(line).ok())
+            .filter(|line| {
+                matches!(
+                    line.get("message").and_then(Value::as_str),
+                    Some("send" | "recv")
+                )
+            })
+            .map(|line| {
+                let time = line.get("time").and_then(Value::as_f64).unwrap_or_default();
+                (Duration::from_secs_f64(time.max(0.0)), line)
+            })
+            .collect();
+        events.sort_by_key(|(time, _)| *time);
+
+        let logging: BTreeSet<&str> = events
+            .iter()
+            .filter_map(|(_, line)| line.get("node").and_then(Value::as_str))
+            .collect();
+
+        let mut trace = Trace::new();
+        // Sent messages waiting for their recv, oldest first
+        let mut in_flight: HashMap<String, VecDeque<usize>> = HashMap::new();
+        for (time, line) in &events {
+            let field = |name: &str| line.get(name).cloned().unwrap_or(Value::Null);
+            let src = field("src").as_str().unwrap_or_default().to_string();
+            let dest = field("dest").as_str().unwrap_or_default().to_string();
+            let key = json!([
+                src,
+                dest,
+                field("type"),
+                field("msg_id"),
+                field("in_reply_to"),
+                field("lamport")
+            ])
+            .to_string();
+
+            if line.get("message").and_then(Value::as_str) == Some("send") {
+                let body = match line.get("body") {
+                    Some(body) => body.clone(),
+                    None => {
+                        let mut body = Map::new();
+                        for name in ["type", "msg_id", "in_reply_to", "lamport"] {
+                            if let Some(value) = line.get(name) {
+                                body.insert(name.to_string(), value.clone());
+                            }
+                        }
+                        Value::Object(body)
+                    }
+                };
+                let index = trace.sent(src, dest, body, *time);
+                in_flight.entry(key).or_default().push_back(index);
+                continue;
+            }
+
+            match in_flight.get_mut(&key).and_then(VecDeque::pop_front) {
+                Some(index) => trace.received(index, *time),
+                None => {
+                    let body = line
+                        .get("body")
+                        .cloned()
+                        .unwrap_or_else(|| json!({"type": field("type")}));
+                    let index = trace.sent(src, dest, body, *time);
+                    trace.received(index, *time);
+                }
+            }
+        }
+
+        for hop in &mut trace.hops {
+            if hop.received.is_none() && !logging.contains(hop.dest.as_str()) {
+                hop.received = Some(hop.sent);
+            }
+        }
+        trace
+    }
+
+    // A space-time diagram like the ones Maelstrom draws: a lane per node or
+    // client, time flowing down, and an arrow per message colored by its type.
+    // Every send and receive gets a row of its own, in the order they happened.
+    pub fn to_svg(&self) -> String {
+        let mut lanes: Vec<&str> = self
+            .hops
+            .iter()
+            .flat_map(|hop| [hop.src.as_str(), hop.dest.as_str()])
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        lanes.sort_by_key(|lane| lane_order(lane));
+        let lane_x: HashMap<&str, f64> = lanes
+            .iter()
+            .enumerate()
+            .map(|(i, lane)| (*lane, MARGIN + LANE_WIDTH * (i as f64 + 0.5)))
+            .collect();
+
+        // (time, receive after send, hop)
+        let mut events: Vec<(Duration, bool, usize)> = Vec::new();
+        for (i, hop) in self.hops.iter().enumerate() {
+            events.push((hop.sent, false, i));
+            if let Some(received) = hop.received {
+                events.push((received.max(hop.sent), true, i));
+            }
+        }
+        events.sort();
+        let mut rows = vec![(0.0, None); self.hops.len()];
+        for (row, &(_, receive, i)) in events.iter().enumerate() {
+            let y = HEADER + ROW * (row as f64 + 1.0);
+            match receive {
+                false => rows[i].0 = y,
+                true => rows[i].1 = Some(y),
+            }
+        }
+
+        let width = MARGIN * 2.0 + LANE_WIDTH * lanes.len() as f64;
+        let height = HEADER + ROW * (events.len() as f64 + 2.0);
+        let mut svg = String::new();
+        let _ = write!(
+            svg,
+            r#"<?xml version="1.0" encoding="UTF-8"?><svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{width}" height="{height}"><style>
+svg {{ font-family: Helvetica, Arial, sans-serif; font-size: 11px; }}
+text.label {{ paint-order: stroke; stroke: #fff; stroke-width: 3px; }}
+</style>"#
+        );
+
+        svg.push_str("<g>");
+        for lane in &lanes {
+            let x = lane_x[lane];
+            let _ = write!(
+                svg,
+                r##"<text x="{x}" y="{}" text-anchor="middle" fill="#000">{}</text><line stroke="#ccc" x1="{x}" y1="{HEADER}" x2="{x}" y2="{height}" />"##,
+                HEADER / 2.0,
+                escape(lane)
+            );
+        }
+        svg.push_str("</g><g>");
+
+        for (hop, &(y1, y2)) in self.hops.iter().zip(&rows) {
+            let kind = hop.body.get("type").and_then(Value::as_str).unwrap_or("?");
+            let color = color(kind);
+            let (x1, to) = (lane_x[hop.src.as_str()], lane_x[hop.dest.as_str()]);
+            // Lost messages fall halfway
+            let (x2, y2, dash) = match y2 {
+                Some(y2) => (to, y2, ""),
+                None => (x1 + (to - x1) / 2.0, y1 + ROW, r#" stroke-dasharray="4 3""#),
+            };
+            let _ = write!(
+                svg,
+                "<g><title>{} → {} {}</title>",
+                escape(&hop.src),
+                escape(&hop.dest),
+                escape(&hop.body.to_string())
+            );
+
+            let (label_x, label_y, angle, head) = if hop.src == hop.dest {
+                let _ = write!(
+                    svg,
+                    r#"<path d="M {x1} {y1} C {cx} {y1}, {cx} {y2}, {x2} {y2}" fill="none" stroke="{color}" stroke-width="2"{dash} />"#,
+                    cx = x1 + LOOP
+                );
+                (x1 + LOOP, (y1 + y2) / 2.0, 0.0, (-1.0, 0.0))
+            } else {
+                let _ = write!(
+                    svg,
+                    r#"<line x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}" stroke="{color}" stroke-width="2"{dash} />"#
+                );
+                let (dx, dy) = (x2 - x1, y2 - y1);
+                let length = (dx * dx + dy * dy).sqrt().max(1.0);
+                let mut angle = dy.atan2(dx).to_degrees();
+                // Keep labels of leftward arrows upright
+                if angle.abs() > 90.0 {
+                    angle -= 180.0f64.copysign(angle);
+                }
+                (
+                    (x1 + x2) / 2.0,
+                    (y1 + y2) / 2.0,
+                    angle,
+                    (dx / length, dy / length),
+                )
+            };
+
+            if dash.is_empty() {
+                let (ux, uy) = head;
+                let _ = write!(
+                    svg,
+                    r#"<polygon points="{x2},{y2} {},{} {},{}" fill="{color}" />"#,
+                    x2 - ux * 10.0 - uy * 4.0,
+                    y2 - uy * 10.0 + ux * 4.0,
+                    x2 - ux * 10.0 + uy * 4.0,
+                    y2 - uy * 10.0 - ux * 4.0,
+                );
+            }
+            let _ = write!(
+                svg,
+                r#"<text class="label" x="{label_x}" y="{}" text-anchor="middle" fill="{color}" transform="rotate({angle} {label_x} {label_y})">{}</text></g>"#,
+                label_y - 4.0,
+                escape(&label(&hop.body))
+            );
+        }
+        svg.push_str("</g></svg>\n");
+        svg
+    }
+}
+
+const MARGIN: f64 = 20.0;
+const LANE_WIDTH: f64 = 240.0;
+const HEADER: f64 = 30.0;
+const ROW: f64 = 24.0;
+// How far messages a node sends itself bulge out
+const LOOP: f64 = 40.0;
+const MAX_LABEL: usize = 60;
+
+// Clients, then nodes, then services, numbered ones in numeric order
+fn lane_order(lane: &str) -> (u8, String, u64, String) {
+    let group = match lane.chars().next() {
+        Some('c') => 0,
+        Some('n') => 1,
+        _ => 2,
+    };
+    let prefix = lane.trim_end_matches(|c: char| c.is_ascii_digit());
+    let number = lane[prefix.len()..].parse().unwrap_or_default();
+    (group, prefix.to_string(), number, lane.to_string())
+}
+
+// Requests and their replies share a hue, errors are red
+fn color(kind: &str) -> String {
+    if kind == "error" {
+        return "#d62728".to_string();
+    }
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    kind.trim_end_matches("_ok").hash(&mut hasher);
+    format!("hsl({}, 65%, 42%)", hasher.finish() % 360)
+}
+
+// The type and whatever else the body carries besides the envelope
+fn label(body: &Value) -> String {
+    let Value::Object(fields) = body else {
+        return body.to_string();
+    };
+    let kind = fields.get("type").and_then(Value::as_str).unwrap_or("?");
+    let rest: Map<String, Value> = fields
+        .iter()
+        .filter(|(name, _)| {
+            !matches!(
+                name.as_str(),
+                "type" | "msg_id" | "in_reply_to" | "lamport" | "vclock"
+            )
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    let mut label = match rest.is_empty() {
+        true => kind.to_string(),
+        false => format!("{} {}", kind, Value::Object(rest)),
+    };
+    if label.chars().count() > MAX_LABEL {
+        label = label.chars().take(MAX_LABEL - 1).collect::<String>() + "…";
+    }
+    label
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn traces_read_back_what_they_wrote() -> anyhow::Result<()> {
+        let mut trace = Trace::new();
+        let read = trace.sent("c1", "n1", json!({"type": "read", "msg_id": 1}), at(1));
+        trace.received(read, at(3));
+        trace.sent("n1", "n2", json!({"type": "gossip", "seen": [1, 2]}), at(2));
+
+        let mut written = Vec::new();
+        trace.write(&mut written)?;
+        assert_eq!(written.iter().filter(|&&b| b == b'\n').count(), 2);
+        assert_eq!(Trace::read(written.as_slice())?, trace);
+        Ok(())
+    }
+
+    #[test]
+    fn logs_pair_up_sends_and_receipts_across_nodes() -> anyhow::Result<()> {
+        let log = r#"{"time":0.5,"level":"debug","node":"n1","message":"recv","src":"c1","dest":"n1","type":"read","msg_id":5}
+0.600 DEBUG n1 not json
+{"time":0.7,"level":"debug","node":"n1","message":"send","src":"n1","dest":"c1","type":"read_ok","in_reply_to":5}
+{"time":1.0,"level":"debug","node":"n1","message":"send","src":"n1","dest":"n2","type":"ping","msg_id":1,"lamport":3}
+{"time":1.1,"level":"info","node":"n2","message":"metrics"}
+{"time":1.6,"level":"debug","node":"n2","message":"send","src":"n2","dest":"n1","type":"ping_ok","in_reply_to":1}
+{"time":1.5,"level":"debug","node":"n2","message":"recv","src":"n1","dest":"n2","type":"ping","msg_id":1,"lamport":3}
+{"time":1.9,"level":"debug","node":"n1","message":"recv","src":"n2","dest":"n1","type":"ping_ok","in_reply_to":1}
+{"time":2.0,"level":"debug","node":"n1","message":"send","src":"n1","dest":"n2","type":"ping","msg_id":2}
+"#;
+        let trace = Trace::read(log.as_bytes())?;
+
+        let hops: Vec<_> = trace
+            .hops()
+            .iter()
+            .map(|hop| {
+                (
+                    hop.src.as_str(),
+                    hop.dest.as_str(),
+                    hop.body["type"].as_str().unwrap_or_default(),
+                    hop.sent.as_millis(),
+                    hop.received.map(|at| at.as_millis()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            hops,
+            [
+                // Clients don't log, so their side is taken to be instant
+                ("c1", "n1", "read", 500, Some(500)),
+                ("n1", "c1", "read_ok", 700, Some(700)),
+                ("n1", "n2", "ping", 1000, Some(1500)),
+                ("n2", "n1", "ping_ok", 1600, Some(1900)),
+                // n2 never got it
+                ("n1", "n2", "ping", 2000, None),
+            ]
+        );
+        assert_eq!(
+            trace.hops()[2].body,
+            json!({"type": "ping", "msg_id": 1, "lamport": 3})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn diagrams_lay_out_clients_then_nodes_then_services() {
+        let mut trace = Trace::new();
+        let request = trace.sent("c1", "n10", json!({"type": "add", "delta": 1}), at(1));
+        trace.received(request, at(2));
+        let read = trace.sent("n10", "lin-kv", json!({"type": "read", "key": "x"}), at(3));
+        trace.received(read, at(4));
+        trace.sent("n10", "n2", json!({"type": "gossip", "note": "<&>"}), at(5));
+
+        let svg = trace.to_svg();
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(svg.contains("&lt;&amp;&gt;"));
+        // The lost gossip is dashed and has no arrowhead
+        assert_eq!(svg.matches("stroke-dasharray").count(), 1);
+        assert_eq!(svg.matches("<polygon").count(), 2);
+
+        let lanes: Vec<usize> = ["c1", "n2", "n10", "lin-kv"]
+            .iter()
+            .map(|lane| {
+                svg.find(&format!(">{}</text>", lane))
+                    .unwrap_or_else(|| panic!("no lane for {}", lane))
+            })
+            .collect();
+        assert!(lanes.is_sorted(), "{:?}", lanes);
+    }
+}
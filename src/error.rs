@@ -0,0 +1,243 @@
+use crate::{Body, Message, RawMessage, RpcError};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+    cell::RefCell,
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// Error codes defined by the Maelstrom protocol
+// https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    // Codes 1000 and above are free for application specific errors
+    Custom(u32),
+}
+
+impl ErrorCode {
+    pub fn code(self) -> u32 {
+        match self {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Custom(code) => code,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            code => ErrorCode::Custom(code),
+        }
+    }
+
+    // Definite errors guarantee the request had no effect, so the client may
+    // safely retry it. Timeouts and crashes leave the outcome unknown.
+    pub fn is_definite(self) -> bool {
+        !matches!(
+            self,
+            ErrorCode::Timeout | ErrorCode::Crash | ErrorCode::Custom(_)
+        )
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(ErrorCode::from_code)
+    }
+}
+
+// Error Message RPC: {"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":5,"code":20,"text":"no such key"}}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename = "error")]
+pub struct MaelstromError {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl MaelstromError {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+
+    // Handlers can return a `MaelstromError` through anyhow to pick the code,
+    // anything else is reported as a crash since we can't tell what happened
+    pub fn from_anyhow(error: &anyhow::Error) -> Self {
+        if let Some(error) = error.downcast_ref::<MaelstromError>() {
+            return error.clone();
+        }
+
+        let code = match error.downcast_ref::<RpcError>() {
+            Some(RpcError::Timeout { .. }) => ErrorCode::Timeout,
+            _ => ErrorCode::Crash,
+        };
+
+        Self::new(code, format!("{:#}", error))
+    }
+
+    // Picks the error out of a reply, if the reply is one
+    pub fn from_reply(reply: &RawMessage) -> Option<Self> {
+        if reply.body.payload.get("type")?.as_str()? != "error" {
+            return None;
+        }
+        serde_json::from_value(reply.body.payload.clone()).ok()
+    }
+}
+
+impl fmt::Display for MaelstromError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "error {} ({:?}): {}",
+            self.code.code(),
+            self.code,
+            self.text
+        )
+    }
+}
+
+impl std::error::Error for MaelstromError {}
+
+impl<Payload> Message<Payload> {
+    // Reply to this message with an error instead of the regular response
+    pub fn into_error_reply(self, error: &anyhow::Error) -> Message<MaelstromError> {
+        Message {
+            src: self.dst,
+            dst: self.src,
+            body: Body {
+                id: None,
+                in_reply_to: self.body.id,
+                clock: Default::default(),
+                payload: MaelstromError::from_anyhow(error),
+            },
+        }
+    }
+}
+
+// The not-supported error owed for a message the node doesn't understand,
+// if it was a request. Anything else has nobody waiting to be told.
+pub(crate) fn not_supported(raw: &Value) -> Option<Message<MaelstromError>> {
+    let request = RawMessage::deserialize(raw).ok()?;
+    if request.body.id.is_none() || request.body.in_reply_to.is_some() {
+        return None;
+    }
+    let text = match request.body.payload.get("type").and_then(Value::as_str) {
+        Some(kind) => format!("unsupported message type {}", kind),
+        None => "message has no type".to_string(),
+    };
+    let error = MaelstromError::new(ErrorCode::NotSupported, text);
+    Some(request.into_error_reply(&error.into()))
+}
+
+// A request being handled, and whether its handler has replied to it yet.
+// Replies are spotted as `Message::send` writes them, so the library only
+// answers with an error for handlers that failed before answering.
+#[derive(Clone)]
+struct Handling {
+    src: String,
+    msg_id: usize,
+    replied: Arc<AtomicBool>,
+}
+
+thread_local! {
+    static HANDLING: RefCell<Option<Handling>> = const { RefCell::new(None) };
+}
+
+tokio::task_local! {
+    // Async handlers move between threads
+    static TASK_HANDLING: Option<Handling>;
+}
+
+impl Handling {
+    fn new(request: Option<&Message<()>>) -> Option<Self> {
+        let request = request?;
+        Some(Self {
+            src: request.src.clone(),
+            msg_id: request.body.id?,
+            replied: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    fn replied(handling: &Option<Self>) -> bool {
+        handling
+            .as_ref()
+            .is_some_and(|handling| handling.replied.load(Ordering::Relaxed))
+    }
+}
+
+// Runs `f` as the handler of `request`, returning whether it replied to it
+pub(crate) fn handling<T>(request: Option<&Message<()>>, f: impl FnOnce() -> T) -> (T, bool) {
+    let handling = Handling::new(request);
+    let outer = HANDLING.with(|current| current.replace(handling.clone()));
+    let result = f();
+    HANDLING.with(|current| current.replace(outer));
+    (result, Handling::replied(&handling))
+}
+
+// Same as `handling`, for the handler of an async node
+pub(crate) async fn handling_async<T>(
+    request: Option<&Message<()>>,
+    f: impl Future<Output = T>,
+) -> (T, bool) {
+    let handling = Handling::new(request);
+    let result = TASK_HANDLING.scope(handling.clone(), f).await;
+    (result, Handling::replied(&handling))
+}
+
+// Notes `msg` going out, see `handling`
+pub(crate) fn sent<P>(msg: &Message<P>) {
+    let mark = |handling: &Option<Handling>| {
+        if let Some(handling) = handling {
+            if msg.dst == handling.src && msg.body.in_reply_to == Some(handling.msg_id) {
+                handling.replied.store(true, Ordering::Relaxed);
+            }
+        }
+    };
+    if TASK_HANDLING.try_with(mark).is_err() {
+        HANDLING.with(|current| mark(&current.borrow()));
+    }
+}
@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, time::Duration};
+
+// Same vocabulary as Maelstrom/Knossos histories: every operation is invoked,
+// then completes as ok, fails without effect, or stays indeterminate (info)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum OpKind {
+    Invoke,
+    Ok,
+    Fail,
+    Info,
+}
+
+// History entry: {"process":0,"type":"invoke","f":"write","value":3,"time":1200000}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Op {
+    pub process: usize,
+    #[serde(rename = "type")]
+    pub kind: OpKind,
+    pub f: String,
+    pub value: Value,
+    #[serde(with = "nanos")]
+    pub time: Duration,
+    // Set on the requests a run ends with, once the cluster had time to recover
+    #[serde(rename = "final?", default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_final: bool,
+}
+
+// An invocation paired with its completion
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub process: usize,
+    pub f: String,
+    pub input: Value,
+    // `None` while the outcome is unknown, ie: info or never completed
+    pub output: Option<Value>,
+    pub kind: OpKind,
+    pub invoke_index: usize,
+    pub complete_index: Option<usize>,
+    pub invoke_time: Duration,
+    pub complete_time: Option<Duration>,
+    pub is_final: bool,
+}
+
+impl Operation {
+    pub fn is_ok(&self) -> bool {
+        self.kind == OpKind::Ok
+    }
+}
+
+// Client operations in the order they were observed
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct History {
+    ops: Vec<Op>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn push(&mut self, op: Op) {
+        self.ops.push(op);
+    }
+
+    pub fn invoke(&mut self, process: usize, f: impl Into<String>, value: Value, time: Duration) {
+        self.record(process, OpKind::Invoke, f, value, time);
+    }
+
+    // Invokes one of the final requests, eg: the reads a broadcast run ends with
+    pub fn invoke_final(
+        &mut self,
+        process: usize,
+        f: impl Into<String>,
+        value: Value,
+        time: Duration,
+    ) {
+        self.record(process, OpKind::Invoke, f, value, time);
+        if let Some(op) = self.ops.last_mut() {
+            op.is_final = true;
+        }
+    }
+
+    pub fn ok(&mut self, process: usize, f: impl Into<String>, value: Value, time: Duration) {
+        self.record(process, OpKind::Ok, f, value, time);
+    }
+
+    pub fn fail(&mut self, process: usize, f: impl Into<String>, value: Value, time: Duration) {
+        self.record(process, OpKind::Fail, f, value, time);
+    }
+
+    pub fn info(&mut self, process: usize, f: impl Into<String>, value: Value, time: Duration) {
+        self.record(process, OpKind::Info, f, value, time);
+    }
+
+    fn record(
+        &mut self,
+        process: usize,
+        kind: OpKind,
+        f: impl Into<String>,
+        value: Value,
+        time: Duration,
+    ) {
+        self.ops.push(Op {
+            process,
+            kind,
+            f: f.into(),
+            value,
+            time,
+            is_final: false,
+        });
+    }
+
+    // Pairs each invocation with the next completion from the same process.
+    // Invocations that never complete are reported as info.
+    pub fn operations(&self) -> Vec<Operation> {
+        let mut operations: Vec<Operation> = Vec::new();
+        let mut open: HashMap<usize, usize> = HashMap::new();
+
+        for (index, op) in self.ops.iter().enumerate() {
+            if op.kind == OpKind::Invoke {
+                open.insert(op.process, operations.len());
+                operations.push(Operation {
+                    process: op.process,
+                    f: op.f.clone(),
+                    input: op.value.clone(),
+                    output: None,
+                    kind: OpKind::Info,
+                    invoke_index: index,
+                    complete_index: None,
+                    invoke_time: op.time,
+                    complete_time: None,
+                    is_final: op.is_final,
+                });
+                continue;
+            }
+
+            let Some(at) = open.remove(&op.process) else {
+                continue;
+            };
+            let operation = &mut operations[at];
+            operation.kind = op.kind;
+            operation.complete_index = Some(index);
+            operation.complete_time = Some(op.time);
+            if op.kind != OpKind::Info {
+                operation.output = Some(op.value.clone());
+            }
+        }
+
+        operations
+    }
+
+    // Sub-history of the operations `keep` selects, eg: one key of a KV history
+    pub fn filter(&self, mut keep: impl FnMut(&Op) -> bool) -> History {
+        History {
+            ops: self.ops.iter().filter(|op| keep(op)).cloned().collect(),
+        }
+    }
+}
+
+// Times are stored as nanoseconds, like Maelstrom does
+pub(crate) mod nanos {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(time: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(time.as_nanos() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_nanos)
+    }
+
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::time::Duration;
+
+        pub fn serialize<S: Serializer>(
+            time: &Option<Duration>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match time {
+                Some(time) => serializer.serialize_some(&(time.as_nanos() as u64)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Duration>, D::Error> {
+            Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_nanos))
+        }
+    }
+}
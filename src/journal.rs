@@ -0,0 +1,363 @@
+use crate::{
+    clock, history::nanos, step_node, trace, Event, Init, Message, Node, Output, RawMessage,
+    RpcClient,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Everything the main loops hand the node, in the order the node saw it, so a run
+// can be replayed offline against the same Node implementation. One entry per line:
+// {"time":1200000,"event":"message","message":{"src":"c1","dest":"n0","body":{...}}}
+//
+// Replies resolved for the node's own requests are journaled as they arrive,
+// as those don't go through `step`.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Entry<M, I> {
+    Init { init: Init },
+    Message { message: M },
+    Injected { payload: I },
+    Unknown { raw: Value },
+    Reply { message: RawMessage },
+    Eof,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record<M, I> {
+    // Since the node started
+    #[serde(with = "nanos")]
+    time: Duration,
+    #[serde(flatten)]
+    entry: Entry<M, I>,
+}
+
+pub(crate) struct Journal {
+    started: Instant,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Journal {
+    // NODE_JOURNAL names the file, where {node} stands for the node id, as every
+    // node Maelstrom runs sees the same environment, eg: /tmp/journal-{node}.jsonl
+    pub(crate) fn from_env(init: &Init) -> anyhow::Result<Option<Self>> {
+        let Ok(path) = std::env::var("NODE_JOURNAL") else {
+            return Ok(None);
+        };
+        let path = path.replace("{node}", &init.node_id);
+        let file = File::create(&path).with_context(|| format!("create journal {}", path))?;
+
+        let journal = Self {
+            started: Instant::now(),
+            file: Mutex::new(BufWriter::new(file)),
+        };
+        journal.write::<(), ()>(Entry::Init { init: init.clone() })?;
+        Ok(Some(journal))
+    }
+
+    pub(crate) fn event<P, IP>(&self, event: &Event<P, IP>) -> anyhow::Result<()>
+    where
+        P: Serialize,
+        IP: Serialize,
+    {
+        self.write(match event {
+            Event::Message(message) => Entry::Message { message },
+            Event::Injected(payload) => Entry::Injected { payload },
+            Event::EOF => Entry::Eof,
+        })
+    }
+
+    pub(crate) fn unknown(&self, raw: &Value) -> anyhow::Result<()> {
+        self.write::<(), ()>(Entry::Unknown { raw: raw.clone() })
+    }
+
+    pub(crate) fn reply(&self, message: RawMessage) -> anyhow::Result<()> {
+        self.write::<(), ()>(Entry::Reply { message })
+    }
+
+    // Flushed right away, the entries before a crash are the interesting ones
+    fn write<M, I>(&self, entry: Entry<M, I>) -> anyhow::Result<()>
+    where
+        M: Serialize,
+        I: Serialize,
+    {
+        let record = Record {
+            time: self.started.elapsed(),
+            entry,
+        };
+        let mut file = self.file.lock().expect("journal poisoned");
+        serde_json::to_writer(&mut *file, &record).context("serialize journal entry")?;
+        file.write_all(b"\n").context("write journal")?;
+        file.flush().context("flush journal")
+    }
+}
+
+pub(crate) enum Replayed<P, IP> {
+    Event(Event<P, IP>),
+    Unknown(Value),
+}
+
+enum Read<P, IP> {
+    Replayed(Replayed<P, IP>),
+    // in_reply_to of a reply, handed to whoever waits on it
+    Reply(Option<usize>),
+}
+
+// The journal left to replay, read in order as the node takes up its events.
+// A step waiting on a reply reads ahead to it instead (see `serve`), setting
+// the events it passes on the way aside for the node to take up next.
+pub(crate) struct Replay<P, IP> {
+    records: std::vec::IntoIter<Record<Message<P>, IP>>,
+    passed: VecDeque<Replayed<P, IP>>,
+    // Replies read before the request they answer was made, by in_reply_to
+    early: HashMap<usize, RawMessage>,
+    rpc: Option<RpcClient>,
+    started: Instant,
+    // Entries keep their original spacing
+    realtime: bool,
+}
+
+impl<P, IP> Replay<P, IP>
+where
+    P: DeserializeOwned + Send + 'static,
+    IP: DeserializeOwned + Send + 'static,
+{
+    pub(crate) fn open(path: &str, realtime: bool) -> anyhow::Result<(Init, Self)> {
+        let file = File::open(path).with_context(|| format!("open journal {}", path))?;
+        let mut records = Vec::new();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context("read journal")?;
+            let record: Record<Message<P>, IP> = serde_json::from_str(&line)
+                .with_context(|| format!("journal entry {} could not be deserialized", n + 1))?;
+            records.push(record);
+        }
+        let mut records = records.into_iter();
+        let Some(Record {
+            entry: Entry::Init { init },
+            ..
+        }) = records.next()
+        else {
+            anyhow::bail!("journal should start with init");
+        };
+
+        let replay = Self {
+            records,
+            passed: VecDeque::new(),
+            early: HashMap::new(),
+            rpc: None,
+            started: Instant::now(),
+            realtime,
+        };
+        Ok((init, replay))
+    }
+
+    // Resolves the requests `rpc` makes with the journaled replies, reading
+    // ahead to the one a step waits on rather than having it time out
+    pub(crate) fn serve(replay: &Arc<Mutex<Self>>, rpc: RpcClient) {
+        let parked = Arc::downgrade(replay);
+        rpc.park_with(Arc::new(move |msg_id, _timeout| {
+            let Some(replay) = parked.upgrade() else {
+                return false;
+            };
+            let mut replay = replay.lock().expect("replay poisoned");
+            replay.reply_to(msg_id)
+        }));
+        replay.lock().expect("replay poisoned").rpc = Some(rpc);
+    }
+
+    // The next event for the node, handing out the replies before it
+    pub(crate) fn next(&mut self) -> anyhow::Result<Option<Replayed<P, IP>>> {
+        if let Some(passed) = self.passed.pop_front() {
+            return Ok(Some(passed));
+        }
+        while let Some(read) = self.read()? {
+            if let Read::Replayed(replayed) = read {
+                return Ok(Some(replayed));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read(&mut self) -> anyhow::Result<Option<Read<P, IP>>> {
+        let Some(Record { time, entry }) = self.records.next() else {
+            return Ok(None);
+        };
+        if self.realtime {
+            thread::sleep(time.saturating_sub(self.started.elapsed()));
+        }
+
+        Ok(Some(match entry {
+            Entry::Message { message } => Read::Replayed(Replayed::Event(Event::Message(message))),
+            Entry::Injected { payload } => {
+                Read::Replayed(Replayed::Event(Event::Injected(payload)))
+            }
+            Entry::Eof => Read::Replayed(Replayed::Event(Event::EOF)),
+            Entry::Unknown { raw } => Read::Replayed(Replayed::Unknown(raw)),
+            Entry::Init { .. } => anyhow::bail!("journal has a second init"),
+            Entry::Reply { message } => {
+                let in_reply_to = message.body.in_reply_to;
+                if let (Some(rpc), Some(id)) = (&self.rpc, in_reply_to) {
+                    if let Some(reply) = rpc.resolve(message) {
+                        self.early.insert(id, reply);
+                    }
+                }
+                Read::Reply(in_reply_to)
+            }
+        }))
+    }
+
+    // Whether the reply to `msg_id` turned up
+    fn reply_to(&mut self, msg_id: usize) -> bool {
+        if let Some(reply) = self.early.remove(&msg_id) {
+            let rpc = self
+                .rpc
+                .as_ref()
+                .expect("replies are kept for an rpc client");
+            return rpc.resolve(reply).is_none();
+        }
+        loop {
+            match self.read() {
+                Ok(Some(Read::Replayed(replayed))) => self.passed.push_back(replayed),
+                Ok(Some(Read::Reply(Some(id)))) if id == msg_id => {
+                    return !self.early.contains_key(&id)
+                }
+                Ok(Some(Read::Reply(_))) => {}
+                Ok(None) => return false,
+                Err(e) => {
+                    trace::warn(
+                        "journal could not be read",
+                        json!({"error": format!("{:#}", e)}),
+                    );
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+// Feeds the journal at `path` to a fresh node instead of running it against a
+// transport, writing whatever it sends to stdout. Events are stepped in their
+// journaled order. Whatever the node injects itself is ignored, its journaled
+// injections stand in for it, and the node's requests get their journaled
+// replies. With `realtime` entries keep their original spacing.
+pub(crate) fn replay<S, N, P, IP>(init_state: S, path: &str, realtime: bool) -> anyhow::Result<()>
+where
+    P: DeserializeOwned + Send + 'static,
+    N: Node<S, P, IP>,
+    IP: DeserializeOwned + Send + 'static,
+{
+    let (init, replay) = Replay::<P, IP>::open(path, realtime)?;
+    let _tracer = trace::enter(Some(trace::NodeTracer::new(&init.node_id)));
+    let node_id = init.node_id.clone();
+    let (inject_tx, inject_rx) = mpsc::channel();
+    thread::spawn(move || for _ in inject_rx {});
+
+    let mut node: N =
+        Node::from_init(init_state, init, inject_tx).context("node initialization failed")?;
+    let _clock = clock::enter(clock::NodeClock::new(&node_id, node.clocks()));
+    let mut stdout = Output::new(std::io::stdout());
+    let replay = Arc::new(Mutex::new(replay));
+    if let Some(rpc) = node.rpc() {
+        Replay::serve(&replay, rpc);
+    }
+
+    let mut replayed = 0;
+    loop {
+        // Not held while stepping, waiting on a reply reads ahead
+        let next = replay.lock().expect("replay poisoned").next()?;
+        let Some(next) = next else {
+            break;
+        };
+        replayed += 1;
+        let eof = match next {
+            Replayed::Event(event) => {
+                let eof = matches!(event, Event::EOF);
+                step_node(&mut node, event, &mut stdout)?;
+                eof
+            }
+            Replayed::Unknown(raw) => {
+                node.on_unknown(raw, &mut stdout)
+                    .context("Node on_unknown failed")?;
+                false
+            }
+        };
+        stdout.flush().context("flush output")?;
+        if eof {
+            break;
+        }
+    }
+
+    node.on_shutdown(&mut stdout)
+        .context("node shutdown failed")?;
+    stdout.flush().context("flush output")?;
+    drop(node);
+    trace::info("replayed", json!({"journal": path, "events": replayed}));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(name: &str, entries: &[Value]) -> String {
+        let path =
+            std::env::temp_dir().join(format!("journal-{}-{}.jsonl", name, std::process::id()));
+        let lines: Vec<String> = entries.iter().map(Value::to_string).collect();
+        std::fs::write(&path, lines.join("\n") + "\n").expect("write journal");
+        path.to_string_lossy().into_owned()
+    }
+
+    fn message(src: &str, in_reply_to: Option<usize>, kind: &str) -> Value {
+        json!({"src": src, "dest": "n0", "body": {"type": kind, "in_reply_to": in_reply_to}})
+    }
+
+    fn src(next: Option<Replayed<Value, ()>>) -> String {
+        match next {
+            Some(Replayed::Event(Event::Message(msg))) => msg.src,
+            _ => panic!("expected a message"),
+        }
+    }
+
+    #[test]
+    fn waiting_on_a_reply_reads_ahead_and_keeps_the_events_passed() {
+        let path = journal(
+            "reads-ahead",
+            &[
+                json!({"time": 0, "event": "init", "init": {"node_id": "n0", "node_ids": ["n0"]}}),
+                json!({"time": 1, "event": "message", "message": message("c1", None, "read")}),
+                json!({"time": 2, "event": "message", "message": message("c2", None, "read")}),
+                json!({"time": 3, "event": "reply", "message": message("n1", Some(1), "read_ok")}),
+            ],
+        );
+        let (init, replay) = Replay::<Value, ()>::open(&path, false).expect("open journal");
+        assert_eq!(init.node_id, "n0");
+        let replay = Arc::new(Mutex::new(replay));
+        let rpc = RpcClient::new("n0");
+        Replay::serve(&replay, rpc.clone());
+
+        assert_eq!(src(replay.lock().unwrap().next().unwrap()), "c1");
+        // c1's step asks n1 and waits, without c2 being handed out first
+        let pending = rpc
+            .call(
+                "n1",
+                json!({"type": "read"}),
+                &mut Output::new(std::io::sink()),
+            )
+            .expect("call");
+        let reply: Message<Value> = pending.wait(Duration::from_secs(1)).expect("reply");
+        assert_eq!(reply.src, "n1");
+
+        assert_eq!(src(replay.lock().unwrap().next().unwrap()), "c2");
+        assert!(replay.lock().unwrap().next().unwrap().is_none());
+        let _ = std::fs::remove_file(path);
+    }
+}
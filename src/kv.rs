@@ -0,0 +1,399 @@
+use crate::{
+    Body, ErrorCode, MaelstromError, Message, Output, RawMessage, RpcClient, SharedOutput,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::HashMap, time::Duration};
+
+// Key-value services provided by Maelstrom to every node
+// https://github.com/jepsen-io/maelstrom/blob/main/doc/services.md
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvService {
+    Seq,
+    Lin,
+    Lww,
+}
+
+impl KvService {
+    pub const ALL: [KvService; 3] = [KvService::Seq, KvService::Lin, KvService::Lww];
+
+    pub fn node_id(self) -> &'static str {
+        match self {
+            KvService::Seq => "seq-kv",
+            KvService::Lin => "lin-kv",
+            KvService::Lww => "lww-kv",
+        }
+    }
+}
+
+// KV Message RPC: {"src":"n1","dest":"seq-kv","body":{"type":"cas","msg_id":4,"key":"counter","from":1,"to":2}}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum KvPayload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+}
+
+// Talks to a KV service over the node's own message stream, so it relies on
+// the node returning its `RpcClient` from `Node::rpc` to get the replies back
+#[derive(Clone)]
+pub struct KvClient {
+    rpc: RpcClient,
+    service: KvService,
+    timeout: Duration,
+}
+
+impl KvClient {
+    pub fn new(rpc: RpcClient, service: KvService) -> Self {
+        Self {
+            rpc,
+            service,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn service(&self) -> KvService {
+        self.service
+    }
+
+    // Returns `None` when the key has never been written
+    pub fn read<K, V>(&self, key: K, output: &mut Output) -> anyhow::Result<Option<V>>
+    where
+        K: Serialize,
+        V: DeserializeOwned,
+    {
+        read_result(self.request(read_request(key)?, output))
+    }
+
+    pub fn write<K, V>(&self, key: K, value: V, output: &mut Output) -> anyhow::Result<()>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        write_result(self.request(write_request(key, value)?, output))
+    }
+
+    // Fails with a `PreconditionFailed` `MaelstromError` when the current value isn't `from`
+    pub fn cas<K, V>(
+        &self,
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+        output: &mut Output,
+    ) -> anyhow::Result<()>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        let request = cas_request(key, from, to, create_if_not_exists)?;
+        cas_result(self.request(request, output))
+    }
+
+    // Read-modify-write loop: applies `f` to the current value and retries
+    // whenever another node won the cas in between. Returns the stored value.
+    //
+    // A missing key is first created holding `initial`, and `f` applied to that
+    // in a second round, as concurrent creations would all succeed otherwise
+    // and every update but the last one would be lost.
+    pub fn update<K, V, F>(
+        &self,
+        key: K,
+        initial: V,
+        output: &mut Output,
+        mut f: F,
+    ) -> anyhow::Result<V>
+    where
+        K: Serialize + Clone,
+        V: Serialize + DeserializeOwned + Clone,
+        F: FnMut(V) -> V,
+    {
+        loop {
+            let result = match self.read::<K, V>(key.clone(), &mut *output)? {
+                Some(current) => {
+                    let next = f(current.clone());
+                    self.cas(key.clone(), current, next.clone(), false, &mut *output)
+                        .map(|()| Some(next))
+                }
+                None => self
+                    .cas(
+                        key.clone(),
+                        initial.clone(),
+                        initial.clone(),
+                        true,
+                        &mut *output,
+                    )
+                    .map(|()| None),
+            };
+
+            match result {
+                Ok(Some(next)) => return Ok(next),
+                Ok(None) => continue,
+                Err(e) if is_lost_race(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn request(&self, payload: KvPayload, output: &mut Output) -> anyhow::Result<KvPayload> {
+        let reply = self
+            .rpc
+            .call(self.service.node_id(), payload, output)?
+            .wait::<KvPayload>(self.timeout)
+            .with_context(|| format!("request to {}", self.service.node_id()))?;
+        Ok(reply.body.payload)
+    }
+}
+
+// Async flavour of `KvClient` for `AsyncNode`s, obtained through `AsyncContext::kv`
+#[derive(Clone)]
+pub struct AsyncKvClient {
+    rpc: RpcClient,
+    service: KvService,
+    timeout: Duration,
+    output: SharedOutput,
+}
+
+impl AsyncKvClient {
+    pub fn new(rpc: RpcClient, service: KvService, output: SharedOutput) -> Self {
+        Self {
+            rpc,
+            service,
+            timeout: Duration::from_secs(1),
+            output,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn service(&self) -> KvService {
+        self.service
+    }
+
+    pub async fn read<K, V>(&self, key: K) -> anyhow::Result<Option<V>>
+    where
+        K: Serialize,
+        V: DeserializeOwned,
+    {
+        read_result(self.request(read_request(key)?).await)
+    }
+
+    pub async fn write<K, V>(&self, key: K, value: V) -> anyhow::Result<()>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        write_result(self.request(write_request(key, value)?).await)
+    }
+
+    pub async fn cas<K, V>(
+        &self,
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+    ) -> anyhow::Result<()>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        let request = cas_request(key, from, to, create_if_not_exists)?;
+        cas_result(self.request(request).await)
+    }
+
+    // See `KvClient::update`
+    pub async fn update<K, V, F>(&self, key: K, initial: V, mut f: F) -> anyhow::Result<V>
+    where
+        K: Serialize + Clone,
+        V: Serialize + DeserializeOwned + Clone,
+        F: FnMut(V) -> V,
+    {
+        loop {
+            let result = match self.read::<K, V>(key.clone()).await? {
+                Some(current) => {
+                    let next = f(current.clone());
+                    self.cas(key.clone(), current, next.clone(), false)
+                        .await
+                        .map(|()| Some(next))
+                }
+                None => self
+                    .cas(key.clone(), initial.clone(), initial.clone(), true)
+                    .await
+                    .map(|()| None),
+            };
+
+            match result {
+                Ok(Some(next)) => return Ok(next),
+                Ok(None) => continue,
+                Err(e) if is_lost_race(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn request(&self, payload: KvPayload) -> anyhow::Result<KvPayload> {
+        let pending = self
+            .output
+            .with(|output| self.rpc.call_async(self.service.node_id(), payload, output))?;
+        let reply = pending
+            .wait::<KvPayload>(self.timeout)
+            .await
+            .with_context(|| format!("request to {}", self.service.node_id()))?;
+        Ok(reply.body.payload)
+    }
+}
+
+fn read_request<K: Serialize>(key: K) -> anyhow::Result<KvPayload> {
+    Ok(KvPayload::Read {
+        key: serde_json::to_value(key).context("serialize kv key")?,
+    })
+}
+
+fn read_result<V: DeserializeOwned>(reply: anyhow::Result<KvPayload>) -> anyhow::Result<Option<V>> {
+    match reply {
+        Ok(KvPayload::ReadOk { value }) => Ok(Some(
+            serde_json::from_value(value).context("deserialize kv value")?,
+        )),
+        Ok(other) => anyhow::bail!("unexpected reply to kv read: {:?}", other),
+        Err(e) if is_code(&e, ErrorCode::KeyDoesNotExist) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_request<K: Serialize, V: Serialize>(key: K, value: V) -> anyhow::Result<KvPayload> {
+    Ok(KvPayload::Write {
+        key: serde_json::to_value(key).context("serialize kv key")?,
+        value: serde_json::to_value(value).context("serialize kv value")?,
+    })
+}
+
+fn write_result(reply: anyhow::Result<KvPayload>) -> anyhow::Result<()> {
+    match reply? {
+        KvPayload::WriteOk => Ok(()),
+        other => anyhow::bail!("unexpected reply to kv write: {:?}", other),
+    }
+}
+
+fn cas_request<K: Serialize, V: Serialize>(
+    key: K,
+    from: V,
+    to: V,
+    create_if_not_exists: bool,
+) -> anyhow::Result<KvPayload> {
+    Ok(KvPayload::Cas {
+        key: serde_json::to_value(key).context("serialize kv key")?,
+        from: serde_json::to_value(from).context("serialize kv value")?,
+        to: serde_json::to_value(to).context("serialize kv value")?,
+        create_if_not_exists,
+    })
+}
+
+fn cas_result(reply: anyhow::Result<KvPayload>) -> anyhow::Result<()> {
+    match reply? {
+        KvPayload::CasOk => Ok(()),
+        other => anyhow::bail!("unexpected reply to kv cas: {:?}", other),
+    }
+}
+
+// Another writer got in between our read and cas
+fn is_lost_race(error: &anyhow::Error) -> bool {
+    is_code(error, ErrorCode::PreconditionFailed) || is_code(error, ErrorCode::KeyDoesNotExist)
+}
+
+fn is_code(error: &anyhow::Error, code: ErrorCode) -> bool {
+    matches!(error.downcast_ref::<MaelstromError>(), Some(e) if e.code == code)
+}
+
+// In-memory KV service answering read, write and cas requests like Maelstrom's,
+// for the simulator and the workload runner. Linearizable, so it stands in for
+// seq-kv and lww-kv with stronger guarantees than the real thing.
+#[derive(Debug, Default)]
+pub struct KvStore {
+    values: HashMap<String, Value>,
+}
+
+impl KvStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The reply owed for `request`, an error one for requests it doesn't know
+    pub fn serve(&mut self, request: RawMessage) -> RawMessage {
+        let body = &request.body.payload;
+        let key = body["key"].to_string();
+        let payload = match body["type"].as_str() {
+            Some("read") => match self.values.get(&key) {
+                Some(value) => json!({"type": "read_ok", "value": value}),
+                None => json!(MaelstromError::new(
+                    ErrorCode::KeyDoesNotExist,
+                    "key does not exist"
+                )),
+            },
+            Some("write") => {
+                self.values.insert(key, body["value"].clone());
+                json!({"type": "write_ok"})
+            }
+            Some("cas") => match self.values.get(&key) {
+                None if body["create_if_not_exists"] == true => {
+                    self.values.insert(key, body["to"].clone());
+                    json!({"type": "cas_ok"})
+                }
+                None => json!(MaelstromError::new(
+                    ErrorCode::KeyDoesNotExist,
+                    "key does not exist"
+                )),
+                Some(value) if *value == body["from"] => {
+                    self.values.insert(key, body["to"].clone());
+                    json!({"type": "cas_ok"})
+                }
+                Some(value) => json!(MaelstromError::new(
+                    ErrorCode::PreconditionFailed,
+                    format!("expected {}, had {}", body["from"], value),
+                )),
+            },
+            _ => json!(MaelstromError::new(
+                ErrorCode::NotSupported,
+                "kv only supports read, write and cas",
+            )),
+        };
+
+        Message {
+            src: request.dst,
+            dst: request.src,
+            body: Body {
+                id: None,
+                in_reply_to: request.body.id,
+                clock: Default::default(),
+                payload,
+            },
+        }
+    }
+}
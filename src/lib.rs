@@ -1,9 +1,61 @@
 use anyhow::Context;
+use codec::Decoded;
+use metrics::NodeMetrics;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    io::{BufRead, StdoutLock, Write},
+    io::{BufRead, Write},
     sync::{atomic::AtomicUsize, Arc, Mutex},
 };
+use trace::NodeTracer;
+
+// So #[payload]'s expansion resolves in this crate's own tests
+#[cfg(test)]
+extern crate self as distributed_systems;
+
+pub mod checker;
+pub mod clock;
+pub mod codec;
+pub mod metrics;
+pub mod trace;
+pub mod transport;
+
+mod async_node;
+mod cluster;
+mod diagram;
+mod error;
+mod history;
+mod journal;
+mod kv;
+mod nemesis;
+mod output;
+mod parallel_node;
+mod queue;
+mod rpc;
+mod simulator;
+mod timer;
+mod workload;
+
+pub use async_node::{async_main_loop, async_main_loop_with, AsyncContext, AsyncNode};
+pub use clock::{Clocks, Stamp, VectorClock};
+pub use cluster::{ClusterConfig, ClusterTransport, NodeAddresses};
+pub use codec::Codec;
+pub use diagram::{Hop, Trace};
+pub use distributed_systems_derive::payload;
+pub use error::{ErrorCode, MaelstromError};
+pub use history::{History, Op, OpKind, Operation};
+pub use kv::{AsyncKvClient, KvClient, KvService, KvStore};
+pub use nemesis::{Faults, Latency, NetworkStats};
+pub use output::{Buffering, Output, OutputStats, SharedOutput};
+pub use parallel_node::{
+    parallel_main_loop, parallel_main_loop_with, partition_key, ParallelNode, Serial,
+};
+pub use queue::{Backpressure, Overflow};
+pub use rpc::{AsyncPendingReply, PendingReply, RawMessage, RpcClient, RpcError};
+pub use simulator::Simulator;
+pub use timer::Timers;
+pub use transport::Transport;
+pub use workload::{Driver, Generator, Request, Workload, WorkloadOptions};
 
 lazy_static::lazy_static! {
     pub static ref GLOBAL_COUNTER: Arc<Mutex<AtomicUsize>> = Arc::new(Mutex::new(AtomicUsize::new(0)));
@@ -29,19 +81,97 @@ impl<Payload> Message<Payload> {
                     mid
                 }),
                 in_reply_to: self.body.id,
+                clock: Default::default(),
                 payload: self.body.payload,
             },
         }
     }
 
-    pub fn send(&self, output: &mut impl Write) -> anyhow::Result<()>
+    // Encodes the message with the codec of `output`
+    pub fn send(&self, output: &mut Output) -> anyhow::Result<()>
     where
         Payload: Serialize,
     {
-        serde_json::to_writer(&mut *output, self).context("serialize response message")?;
-        output.write_all(b"\n").context("write trailing newline")?;
+        let mut msg = Message {
+            src: self.src.clone(),
+            dst: self.dst.clone(),
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                clock: self.body.clock.clone(),
+                payload: &self.body.payload,
+            },
+        };
+        clock::sent(&mut msg);
+        let kind = codec::payload_type(&self.body.payload);
+        if let Some(metrics) = output.metrics() {
+            metrics.sent(&msg, kind.as_deref());
+        }
+        trace::sent(&msg, kind.as_deref());
+        // One write per message, so sinks see whole frames
+        let mut frame = Vec::new();
+        output.codec().encode(&msg, &mut frame)?;
+        output.write_frame(&frame).context("write message")?;
+        error::sent(&msg);
         Ok(())
     }
+
+    // For whoever takes any message as raw JSON, eg: requests waiting on a reply
+    pub(crate) fn into_raw(self) -> anyhow::Result<RawMessage>
+    where
+        Payload: Serialize,
+    {
+        Ok(Message {
+            src: self.src,
+            dst: self.dst,
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                clock: self.body.clock,
+                payload: serde_json::to_value(&self.body.payload).context("serialize message")?,
+            },
+        })
+    }
+}
+
+impl RawMessage {
+    // Interpret the raw body as the given Payload type
+    pub fn decode<Payload>(self) -> anyhow::Result<Message<Payload>>
+    where
+        Payload: DeserializeOwned,
+    {
+        Ok(Message {
+            src: self.src,
+            dst: self.dst,
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                clock: self.body.clock,
+                payload: serde_json::from_value(self.body.payload)
+                    .context("payload does not match message type")?,
+            },
+        })
+    }
+
+    // Like `decode`, but hands the message back when it isn't a Payload
+    pub fn try_decode<Payload>(self) -> Result<Message<Payload>, Box<Self>>
+    where
+        Payload: DeserializeOwned,
+    {
+        match Payload::deserialize(&self.body.payload) {
+            Ok(payload) => Ok(Message {
+                src: self.src,
+                dst: self.dst,
+                body: Body {
+                    id: self.body.id,
+                    in_reply_to: self.body.in_reply_to,
+                    clock: self.body.clock,
+                    payload,
+                },
+            }),
+            Err(_) => Err(Box::new(self)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -51,11 +181,34 @@ pub enum Event<Payload, InjectedPayload = ()> {
     EOF,
 }
 
+impl<Payload, InjectedPayload> Event<Payload, InjectedPayload> {
+    // Keep the envelope of requests around, so a failing handler answers with an error
+    pub(crate) fn request_envelope(&self) -> Option<Message<()>> {
+        match self {
+            Event::Message(msg) if msg.body.id.is_some() => Some(Message {
+                src: msg.src.clone(),
+                dst: msg.dst.clone(),
+                body: Body {
+                    id: msg.body.id,
+                    in_reply_to: None,
+                    clock: Default::default(),
+                    payload: (),
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Body<Payload> {
     #[serde(rename = "msg_id")]
     pub id: Option<usize>,
     pub in_reply_to: Option<usize>,
+    // Logical clocks of the sender, for nodes that keep them (see `Node::clocks`).
+    // Comes before the payload so it never sees the clock fields.
+    #[serde(flatten)]
+    pub clock: Stamp,
     #[serde(flatten)]
     pub payload: Payload,
 }
@@ -87,85 +240,661 @@ pub trait Node<S, Payload, InjectedPayload = ()> {
     fn step(
         &mut self,
         input: Event<Payload, InjectedPayload>,
-        output: &mut StdoutLock,
+        output: &mut Output,
     ) -> anyhow::Result<()>;
+
+    // Nodes that issue requests return their client here, so replies are
+    // resolved on the input thread instead of being delivered to `step`
+    fn rpc(&self) -> Option<RpcClient> {
+        None
+    }
+
+    // Nodes sending many small messages can have main_loop coalesce them into
+    // fewer writes. Buffered output also goes out whenever the node is idle.
+    fn buffering(&self) -> Option<Buffering> {
+        None
+    }
+
+    // Bounds the events waiting for `step`, unbounded by default
+    fn backpressure(&self) -> Option<Backpressure> {
+        None
+    }
+
+    // Gets whatever arrived that isn't a Payload, or isn't a message at all, as
+    // raw JSON instead of the node failing on it. Requests are answered with a
+    // not-supported error by default, so peers can add message types freely.
+    fn on_unknown(&mut self, raw: Value, output: &mut Output) -> anyhow::Result<()> {
+        reply_not_supported(&raw, output)
+    }
+
+    // Logical clocks the library keeps for the node and stamps its messages
+    // with, none by default. Read them with `clock::lamport`/`clock::vector`
+    // while stepping.
+    fn clocks(&self) -> Option<Clocks> {
+        None
+    }
+
+    // Runs once after `Event::EOF` has been stepped, right before main_loop
+    // returns. Timers held by the node stop when it is dropped afterwards.
+    fn on_shutdown(&mut self, _output: &mut Output) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+// What on_unknown does unless a node says otherwise
+pub(crate) fn reply_not_supported(raw: &Value, output: &mut Output) -> anyhow::Result<()> {
+    match error::not_supported(raw) {
+        Some(reply) => reply.send(output).context("reply to unknown message"),
+        None => {
+            trace::warn("ignoring unknown message", raw.clone());
+            Ok(())
+        }
+    }
+}
+
+// Reads the init message off the input, which always comes first
+fn read_init(input: &mut dyn BufRead, codec: Codec) -> anyhow::Result<Message<InitPayload>> {
+    let init_msg = match codec
+        .decode(input)
+        .context("failed to read init message")?
+        .context("no init message recieved")?
+    {
+        Decoded::Message(msg) => msg,
+        Decoded::Raw(_) | Decoded::Unknown(_) => {
+            anyhow::bail!("init msg could not be deserialized")
+        }
+    };
+    Ok(init_msg)
+}
+
+// Takes the init message, which is always the first one a node receives, and
+// builds the init_ok reply owed for it
+fn handle_init(init_msg: &Message<InitPayload>) -> anyhow::Result<(Init, Message<InitPayload>)> {
+    let InitPayload::Init(init) = &init_msg.body.payload else {
+        anyhow::bail!("first message should be init");
+    };
+    let init = init.clone();
+
+    let reply = Message {
+        src: init_msg.dst.clone(),
+        dst: init_msg.src.clone(),
+        body: Body {
+            id: Some(0),
+            in_reply_to: init_msg.body.id,
+            clock: Default::default(),
+            payload: InitPayload::InitOk,
+        },
+    };
+
+    Ok((init, reply))
 }
 
 // We have different State Machines in Binary Crates
 // That execute the main_loop with their States => Eg: Echo, UniqueIds
 // Init is state is always executed
+//
+// Messages go over stdin/stdout unless NODE_TRANSPORT names another transport,
+// eg: NODE_TRANSPORT=tcp://127.0.0.1:7000 (see transport::connect), or the node
+// runs as NODE_ID of the standalone cluster described by CLUSTER_CONFIG.
+// The transport also decides the codec messages are encoded with.
+//
+// With NODE_JOURNAL every event the node steps is journaled (see journal.rs),
+// and NODE_REPLAY=journal replays one instead of running for real, at its
+// original pace with NODE_REPLAY_REALTIME=1 (see the replay binary)
 pub fn main_loop<S, N, P, IP>(init_state: S) -> anyhow::Result<()>
 where
-    P: DeserializeOwned + Send + 'static,
+    P: Serialize + DeserializeOwned + Send + 'static,
     N: Node<S, P, IP>,
-    IP: Send + 'static,
+    IP: Serialize + DeserializeOwned + Send + 'static,
 {
-    let (tx, rx) = std::sync::mpsc::channel();
-
-    let stdin = std::io::stdin().lock();
-    let mut stdin = stdin.lines();
-    let mut stdout = std::io::stdout().lock();
-
-    // Deserializing entire String of the line
-    let init_msg: Message<InitPayload> = serde_json::from_str(
-        &stdin
-            .next()
-            .expect("no init message recieved")
-            .context("failed to read init message from stdin")?,
-    )
-    .context("init msg could not be deserialized")?;
-
-    let InitPayload::Init(init) = init_msg.body.payload else {
-        panic!("first message should be init");
-    };
+    if let Ok(path) = std::env::var("NODE_REPLAY") {
+        let realtime = std::env::var("NODE_REPLAY_REALTIME").is_ok_and(|v| v == "1");
+        return journal::replay::<S, N, P, IP>(init_state, &path, realtime);
+    }
+    main_loop_with::<S, N, P, IP>(init_state, transport_from_env()?)
+}
+
+pub(crate) fn transport_from_env() -> anyhow::Result<Box<dyn Transport>> {
+    Ok(if let Ok(path) = std::env::var("CLUSTER_CONFIG") {
+        let node_id =
+            std::env::var("NODE_ID").context("NODE_ID is required with CLUSTER_CONFIG")?;
+        Box::new(ClusterTransport::new(ClusterConfig::load(&path)?, node_id)?)
+    } else if let Ok(address) = std::env::var("NODE_TRANSPORT") {
+        transport::connect(&address).context("set up NODE_TRANSPORT")?
+    } else {
+        Box::new(transport::Stdio)
+    })
+}
+
+// main_loop over any transport
+pub fn main_loop_with<S, N, P, IP>(
+    init_state: S,
+    transport: Box<dyn Transport>,
+) -> anyhow::Result<()>
+where
+    P: Serialize + DeserializeOwned + Send + 'static,
+    N: Node<S, P, IP>,
+    IP: Serialize + Send + 'static,
+{
+    let (inject_tx, inject_rx) = std::sync::mpsc::channel();
+
+    let Connection {
+        input,
+        output,
+        codec,
+        metrics,
+        tracer,
+        init,
+        init_reply,
+    } = connect(transport)?;
+    let _tracer = trace::enter(Some(tracer.clone()));
+    let mut stdout = Output::new(output)
+        .with_codec(codec)
+        .with_metrics(metrics.clone());
+    let journal = journal::Journal::from_env(&init)?.map(Arc::new);
 
     // Let Node inject it's own messages using tx sender
     let mut node: N =
-        Node::from_init(init_state, init, tx.clone()).context("node initialization failed")?;
+        Node::from_init(init_state, init, inject_tx).context("node initialization failed")?;
 
-    let reply = Message {
-        src: init_msg.dst,
-        dst: init_msg.src,
-        body: Body {
-            id: Some(0),
-            in_reply_to: init_msg.body.id,
-            payload: InitPayload::InitOk,
-        },
+    init_reply
+        .send(&mut stdout)
+        .context("Serialize response to init")?;
+
+    let _clock = clock::enter(clock::NodeClock::new(&init_reply.src, node.clocks()));
+    stdout.set_buffering(node.buffering());
+    stdout.flush().context("flush init reply")?;
+
+    let rpc = node.rpc();
+    if let Some(rpc) = &rpc {
+        metrics.watch_rpc(rpc.clone());
+    }
+    let reader = Reader {
+        input,
+        codec,
+        metrics: metrics.clone(),
+        tracer,
     };
+    let inbox = Inbox::start(
+        reader,
+        rpc.clone(),
+        journal.clone(),
+        node.backpressure(),
+        inject_rx,
+    );
+
+    loop {
+        // Running out of work is the moment to hand buffered output over
+        let popped = match inbox.queue.try_pop() {
+            Some(popped) => popped,
+            None => {
+                stdout.flush().context("flush output")?;
+                inbox.queue.pop()
+            }
+        };
+
+        let event = match next_input(popped, journal.as_deref(), &mut stdout)? {
+            Some(Next::Event(event)) => event,
+            Some(Next::Unknown(raw)) => {
+                node.on_unknown(raw, &mut stdout)
+                    .context("Node on_unknown failed")?;
+                continue;
+            }
+            None => continue,
+        };
+        let eof = matches!(event, Event::EOF);
+        step_node(&mut node, event, &mut stdout)?;
 
-    serde_json::to_writer(&mut stdout, &reply).context("Serialize response to init")?;
-    stdout.write_all(b"\n").context("write trailing newline")?;
+        if eof {
+            break;
+        }
+        stdout.flush_if_due().context("flush output")?;
+    }
+
+    // Shutdown sequence: nobody will answer outstanding requests anymore
+    if let Some(rpc) = &rpc {
+        rpc.fail_pending();
+    }
+    node.on_shutdown(&mut stdout)
+        .context("node shutdown failed")?;
+    let buffered = node.buffering().is_some();
+    drop(node);
+    stdout.flush().context("flush output")?;
+    trace::info("metrics", metrics.snapshot());
+    if buffered {
+        let stats = stdout.stats();
+        trace::info(
+            "output",
+            serde_json::json!({
+                "messages": stats.messages,
+                "bytes": stats.bytes,
+                "writes": stats.writes,
+                "messages_per_write": (stats.messages_per_write() * 10.0).round() / 10.0,
+            }),
+        );
+    }
+
+    inbox.join()
+}
+
+// A transport init has been read from: the input still to come, where the
+// output goes and the init_ok owed
+pub(crate) struct Connection {
+    pub(crate) input: Box<dyn BufRead + Send>,
+    pub(crate) output: Box<dyn Write + Send>,
+    pub(crate) codec: Codec,
+    // Of the node about to be initialized, counting init already
+    pub(crate) metrics: NodeMetrics,
+    pub(crate) tracer: NodeTracer,
+    pub(crate) init: Init,
+    pub(crate) init_reply: Message<InitPayload>,
+}
 
-    drop(stdin);
+// Reads init off `transport`, whose codec the node's output encodes with
+pub(crate) fn connect(transport: Box<dyn Transport>) -> anyhow::Result<Connection> {
+    let codec = transport.codec();
+    let (mut input, output) = transport.split().context("split transport")?;
+    let init_msg = read_init(&mut input, codec)?;
+    let (init, init_reply) = handle_init(&init_msg)?;
 
-    let jh = std::thread::spawn(move || {
-        let stdin = std::io::stdin().lock();
+    let metrics = NodeMetrics::default();
+    let tracer = NodeTracer::new(&init.node_id);
+    metrics.received(&init_msg, Some("init"));
+    tracer.received(&init_msg, Some("init"));
 
-        // Listen to stdin and write the Payload for that State
-        for line in stdin.lines() {
-            let line = line.context("input could not be read")?;
-            let input: Message<P> =
-                serde_json::from_str(&line).context("error deserializing input")?;
+    Ok(Connection {
+        input,
+        output,
+        codec,
+        metrics,
+        tracer,
+        init,
+        init_reply,
+    })
+}
+
+// Where a node's input is read from, and whose metrics and log it counts towards
+pub(crate) struct Reader {
+    pub(crate) input: Box<dyn BufRead + Send>,
+    pub(crate) codec: Codec,
+    pub(crate) metrics: NodeMetrics,
+    pub(crate) tracer: NodeTracer,
+}
 
-            if let Err(_) = tx.send(Event::Message(input)) {
-                return Ok::<_, anyhow::Error>(());
+// The event queue of a node, and the thread filling it with the node's input.
+// What the node injects joins the input on its way to the event loop.
+pub(crate) struct Inbox<P, IP> {
+    pub(crate) queue: Arc<queue::EventQueue<Input<P, IP>>>,
+    input: std::thread::JoinHandle<anyhow::Result<()>>,
+}
+
+impl<P, IP> Inbox<P, IP>
+where
+    P: Serialize + DeserializeOwned + Send + 'static,
+    IP: Send + 'static,
+{
+    pub(crate) fn start(
+        reader: Reader,
+        rpc: Option<RpcClient>,
+        journal: Option<Arc<journal::Journal>>,
+        backpressure: Option<Backpressure>,
+        inject: std::sync::mpsc::Receiver<Event<P, IP>>,
+    ) -> Self {
+        let queue = Arc::new(queue::EventQueue::new(backpressure, reader.metrics.clone()));
+
+        let inject_queue = queue.clone();
+        std::thread::spawn(move || {
+            for event in inject {
+                inject_queue.push(Input::Event(event), true, None);
             }
+        });
+
+        let input_queue = queue.clone();
+        let input = std::thread::spawn(move || {
+            let result = read_input(
+                reader.input,
+                reader.codec,
+                rpc,
+                journal.as_deref(),
+                &reader.metrics,
+                &reader.tracer,
+                |input| {
+                    // Only requests can be turned away, replies are still awaited
+                    let request = match &input {
+                        Input::Event(Event::Message(msg)) if msg.body.in_reply_to.is_some() => None,
+                        Input::Event(event) => event.request_envelope(),
+                        Input::Metrics(_) | Input::Unknown(_) => None,
+                    };
+                    input_queue.push(input, false, request);
+                    true
+                },
+            );
+
+            // Always tell the node, even when input failed, as its own senders
+            // (eg: timers) would otherwise keep the event loop waiting forever
+            input_queue.push(Input::Event(Event::EOF), false, None);
+            result
+        });
+
+        Self { queue, input }
+    }
+
+    // Once the node is done
+    pub(crate) fn join(self) -> anyhow::Result<()> {
+        self.input
+            .join()
+            .expect("input thread panicked")
+            .context("input thread err'd")
+    }
+}
+
+// What the node is handed from its queue
+pub(crate) enum Next<P, IP> {
+    Event(Event<P, IP>),
+    Unknown(Value),
+}
+
+// Answers what the library answers itself, and journals the rest on its way to the node
+pub(crate) fn next_input<P, IP>(
+    popped: queue::Popped<Input<P, IP>>,
+    journal: Option<&journal::Journal>,
+    output: &mut Output,
+) -> anyhow::Result<Option<Next<P, IP>>>
+where
+    P: Serialize,
+    IP: Serialize,
+{
+    Ok(match popped {
+        queue::Popped::Event(Input::Event(event)) => {
+            if let Some(journal) = journal {
+                journal.event(&event)?;
+            }
+            Some(Next::Event(event))
+        }
+        queue::Popped::Event(Input::Metrics(request)) => {
+            let metrics = output.metrics().cloned().unwrap_or_default();
+            metrics::reply(&metrics, request)
+                .send(output)
+                .context("reply to metrics")?;
+            None
+        }
+        queue::Popped::Event(Input::Unknown(raw)) => {
+            if let Some(journal) = journal {
+                journal.unknown(&raw)?;
+            }
+            Some(Next::Unknown(raw))
         }
+        queue::Popped::Rejected(request) => {
+            let error = MaelstromError::new(
+                ErrorCode::TemporarilyUnavailable,
+                "node is overloaded, event queue is full",
+            );
+            request
+                .into_error_reply(&error.into())
+                .send(output)
+                .context("reply to rejected request")?;
+            None
+        }
+    })
+}
 
-        let _ = tx.send(Event::EOF);
-        Ok(())
-    });
+// Steps the node, see `step_with`
+pub(crate) fn step_node<S, N, P, IP>(
+    node: &mut N,
+    event: Event<P, IP>,
+    output: &mut Output,
+) -> anyhow::Result<()>
+where
+    N: Node<S, P, IP>,
+{
+    step_with(event, output, |event, output| node.step(event, output))
+}
+
+// Runs `step` on `event`, answering a request it fails on with an error unless
+// it replied already. Failing on anything else fails the node.
+pub(crate) fn step_with<P, IP>(
+    event: Event<P, IP>,
+    output: &mut Output,
+    step: impl FnOnce(Event<P, IP>, &mut Output) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let request = event.request_envelope();
+    let message = matches!(event, Event::Message(_));
+    if let Event::Message(msg) = &event {
+        clock::received(&msg.body.clock);
+    }
+
+    let handler = metrics::handling(output.metrics(), request.as_ref());
+    let (result, replied) = error::handling(request.as_ref(), || step(event, output));
+    handler.done();
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => match step_failed(e, request, replied, message)? {
+            Some(reply) => reply.send(output).context("reply with error"),
+            None => Ok(()),
+        },
+    }
+}
 
-    for line in rx {
-        node.step(line, &mut stdout)
-            .context("Node step function failed")?;
+// The error reply owed for a step that failed with `e`, if any. A
+// `MaelstromError` about a message that isn't a request is only logged, as
+// nobody is waiting to hear about it.
+pub(crate) fn step_failed(
+    e: anyhow::Error,
+    request: Option<Message<()>>,
+    replied: bool,
+    message: bool,
+) -> anyhow::Result<Option<Message<MaelstromError>>> {
+    match request {
+        Some(request) if !replied => Ok(Some(request.into_error_reply(&e))),
+        None if message && e.downcast_ref::<MaelstromError>().is_some() => {
+            trace::warn(
+                "dropping error about a message that isn't a request",
+                serde_json::json!({"error": e.to_string()}),
+            );
+            Ok(None)
+        }
+        _ => Err(e).context("Node step function failed"),
     }
+}
+
+// What reaches main_loop's event loop: events for the node, requests the
+// library answers itself, or input that isn't a message of the node's protocol
+pub(crate) enum Input<P, IP> {
+    Event(Event<P, IP>),
+    Metrics(RawMessage),
+    Unknown(Value),
+}
 
-    jh.join()
-        .expect("stdin thread panicked")
-        .context("stdin thread err'd")?;
+// Hands every message read to `send`, until it returns false
+pub(crate) fn read_input<P, IP>(
+    mut input: impl BufRead,
+    codec: Codec,
+    rpc: Option<RpcClient>,
+    journal: Option<&journal::Journal>,
+    metrics: &NodeMetrics,
+    tracer: &NodeTracer,
+    mut send: impl FnMut(Input<P, IP>) -> bool,
+) -> anyhow::Result<()>
+where
+    P: Serialize + DeserializeOwned,
+{
+    // Listen to the input and write the Payload for that State
+    while let Some(input) = codec.decode::<P>(&mut input)? {
+        let raw = match input {
+            Decoded::Message(msg) => {
+                received(metrics, tracer, &msg);
+                // Replies to our own requests go to whoever waits on them, as raw JSON
+                let awaited = msg
+                    .body
+                    .in_reply_to
+                    .is_some_and(|msg_id| rpc.as_ref().is_some_and(|rpc| rpc.awaits(msg_id)));
+                if !awaited {
+                    if !send(Input::Event(Event::Message(msg))) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                vec![msg.into_raw()?]
+            }
+            Decoded::Raw(raw) => {
+                let raw = output::unbatch(raw);
+                raw.iter().for_each(|msg| received(metrics, tracer, msg));
+                raw
+            }
+            Decoded::Unknown(raw) => {
+                if !send(Input::Unknown(raw)) {
+                    return Ok(());
+                }
+                continue;
+            }
+        };
+
+        for input in raw {
+            if metrics::is_request(&input) {
+                if !send(Input::Metrics(input)) {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            let input = match &rpc {
+                Some(rpc) => {
+                    let reply = journal.map(|_| input.clone());
+                    match rpc.resolve(input) {
+                        Some(input) => input,
+                        None => {
+                            if let (Some(journal), Some(reply)) = (journal, reply) {
+                                journal.reply(reply)?;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                None => input,
+            };
+            let input = match input.try_decode::<P>() {
+                Ok(msg) => Input::Event(Event::Message(msg)),
+                Err(raw) => {
+                    Input::Unknown(serde_json::to_value(&raw).context("serialize unknown message")?)
+                }
+            };
+
+            if !send(input) {
+                return Ok(());
+            }
+        }
+    }
 
     Ok(())
 }
 
+fn received<P>(metrics: &NodeMetrics, tracer: &NodeTracer, msg: &Message<P>)
+where
+    P: Serialize,
+{
+    let kind = codec::payload_type(&msg.body.payload);
+    metrics.received(msg, kind.as_deref());
+    tracer.received(msg, kind.as_deref());
+}
+
 // ~/maelstrom/maelstrom test -w binary --bin target/debug/binary --node-count 1 --time-limit 20 --rate 10
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ChannelTransport;
+
+    use serde_json::json;
+
+    #[payload]
+    #[derive(Debug, Clone)]
+    enum Payload {
+        #[ok(echo: String)]
+        Echo {
+            echo: String,
+        },
+        #[ok]
+        Read,
+        Gossip {
+            seen: Vec<usize>,
+        },
+    }
+
+    // Only handles echo
+    struct Echo;
+
+    impl PayloadHandler for Echo {
+        fn echo(
+            &mut self,
+            _src: &str,
+            _output: &mut Output,
+            echo: String,
+        ) -> anyhow::Result<String> {
+            Ok(echo)
+        }
+    }
+
+    fn message(id: Option<usize>, payload: Value) -> Message<Payload> {
+        let raw = json!({"src": "c1", "dest": "n0", "body": payload});
+        let mut msg: Message<Payload> = serde_json::from_value(raw).expect("valid message");
+        msg.body.id = id;
+        msg
+    }
+
+    // Dispatches each message, returning the results and the replies sent
+    fn dispatch(msgs: Vec<Message<Payload>>) -> (Vec<anyhow::Result<()>>, Vec<RawMessage>) {
+        let (node, peer) = ChannelTransport::pair();
+        let (_, writer) = node.into_parts();
+        let mut output = Output::new(writer);
+        let results = msgs
+            .into_iter()
+            .map(|msg| Echo.dispatch(msg, &mut output))
+            .collect();
+        drop(output);
+
+        let (reader, _) = peer.into_parts();
+        let replies = reader
+            .lines()
+            .map(|line| serde_json::from_str(&line.expect("line")).expect("reply"))
+            .collect();
+        (results, replies)
+    }
+
+    #[test]
+    fn payload_handlers_reply_with_what_they_return() {
+        let (results, replies) = dispatch(vec![message(
+            Some(3),
+            json!({"type": "echo", "echo": "hi"}),
+        )]);
+
+        assert!(results[0].is_ok());
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].dst, "c1");
+        assert_eq!(replies[0].body.in_reply_to, Some(3));
+        assert_eq!(
+            replies[0].body.payload,
+            json!({"type": "echo_ok", "echo": "hi"})
+        );
+    }
+
+    #[test]
+    fn unhandled_requests_are_not_supported_and_other_messages_ignored() {
+        let (results, replies) = dispatch(vec![
+            message(Some(1), json!({"type": "read"})),
+            message(None, json!({"type": "gossip", "seen": [1]})),
+            message(None, json!({"type": "read"})),
+            message(Some(2), json!({"type": "echo_ok", "echo": "hi"})),
+        ]);
+
+        let error = results[0].as_ref().expect_err("read isn't handled");
+        let error = error
+            .downcast_ref::<MaelstromError>()
+            .expect("a maelstrom error");
+        assert_eq!(error.code, ErrorCode::NotSupported);
+        for result in &results[1..] {
+            assert!(result.is_ok(), "{:?}", result);
+        }
+        // main_loop answers the failed request, the handler sends nothing
+        assert!(replies.is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use crate::{trace, Message, RawMessage, RpcClient};
+
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Latencies in power of two buckets of microseconds, the last one open ended
+const BUCKETS: usize = 28;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros().max(1) as u64;
+        let bucket = (64 - micros.leading_zeros() as usize - 1).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        let nanos = self.sum.as_nanos() / self.count.max(1) as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    // Upper bound of the bucket holding the `q` quantile, never above the max.
+    // The last bucket has none but the max.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = ((self.count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &n) in self.buckets.iter().enumerate().take(BUCKETS - 1) {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros(2 << bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn summary(&self) -> Value {
+        let ms = |d: Duration| (d.as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0;
+        json!({
+            "count": self.count,
+            "mean_ms": ms(self.mean()),
+            "p50_ms": ms(self.quantile(0.5)),
+            "p95_ms": ms(self.quantile(0.95)),
+            "p99_ms": ms(self.quantile(0.99)),
+            "max_ms": ms(self.max),
+        })
+    }
+}
+
+// What a node has been doing, answered to `metrics` requests and logged on EOF.
+//
+// Latency is the time the node's handler took over a request, per request
+// type, leaving out the time it waited in the queue.
+// Messages are told apart by Maelstrom's naming: nodes are n*, clients c*.
+#[derive(Default)]
+struct Metrics {
+    sent: BTreeMap<String, u64>,
+    received: BTreeMap<String, u64>,
+    latency: BTreeMap<String, Histogram>,
+    client_requests: u64,
+    server_messages: u64,
+    queue_depth: usize,
+    max_queue_depth: usize,
+    queue_capacity: Option<usize>,
+    // Events that found the queue full, and what became of them
+    queue_full: u64,
+    queue_dropped: u64,
+    queue_rejected: u64,
+    rpc: Option<RpcClient>,
+}
+
+// The metrics of one node, kept by whatever runs it (a loop, or the simulator
+// for each of its nodes) and handed to its output and queue. Messages sent
+// through an output without any, eg: by the workload runner, don't count.
+#[derive(Clone, Default)]
+pub(crate) struct NodeMetrics(Arc<Mutex<Metrics>>);
+
+impl NodeMetrics {
+    fn metrics(&self) -> std::sync::MutexGuard<'_, Metrics> {
+        self.0.lock().expect("metrics poisoned")
+    }
+
+    // `kind` is the type of the message's payload
+    pub(crate) fn received<P>(&self, msg: &Message<P>, kind: Option<&str>) {
+        let mut metrics = self.metrics();
+        let kind = kind.unwrap_or("unknown").to_string();
+        *metrics.received.entry(kind).or_default() += 1;
+
+        if let (Some(_), None) = (msg.body.id, msg.body.in_reply_to) {
+            if msg.src.starts_with('c') {
+                metrics.client_requests += 1;
+            }
+        }
+    }
+
+    pub(crate) fn sent<P>(&self, msg: &Message<P>, kind: Option<&str>) {
+        let mut metrics = self.metrics();
+        let kind = kind.unwrap_or("unknown").to_string();
+        *metrics.sent.entry(kind).or_default() += 1;
+        if msg.dst.starts_with('n') {
+            metrics.server_messages += 1;
+        }
+    }
+
+    // An event was queued for, or taken up by, the node
+    pub(crate) fn enqueued(&self) {
+        let mut metrics = self.metrics();
+        metrics.queue_depth += 1;
+        metrics.max_queue_depth = metrics.max_queue_depth.max(metrics.queue_depth);
+    }
+
+    pub(crate) fn dequeued(&self) {
+        let mut metrics = self.metrics();
+        metrics.queue_depth = metrics.queue_depth.saturating_sub(1);
+    }
+
+    pub(crate) fn queue_capacity(&self, capacity: usize) {
+        self.metrics().queue_capacity = Some(capacity);
+    }
+
+    pub(crate) fn queue_full(&self) {
+        self.metrics().queue_full += 1;
+    }
+
+    pub(crate) fn queue_dropped(&self) {
+        self.metrics().queue_dropped += 1;
+    }
+
+    pub(crate) fn queue_rejected(&self) {
+        self.metrics().queue_rejected += 1;
+    }
+
+    // Reports the requests `rpc` is waiting on
+    pub(crate) fn watch_rpc(&self, rpc: RpcClient) {
+        self.metrics().rpc = Some(rpc);
+    }
+
+    pub(crate) fn snapshot(&self) -> Value {
+        let metrics = self.metrics();
+        let latency: BTreeMap<_, _> = metrics
+            .latency
+            .iter()
+            .map(|(kind, histogram)| (kind.clone(), histogram.summary()))
+            .collect();
+        let per_request = metrics.server_messages as f64 / metrics.client_requests.max(1) as f64;
+        // How close the queue came to its capacity, 1 once it filled up
+        let saturation = metrics
+            .queue_capacity
+            .map(|capacity| metrics.max_queue_depth as f64 / capacity.max(1) as f64);
+
+        json!({
+            "sent": metrics.sent,
+            "received": metrics.received,
+            "latency": latency,
+            "server_messages": metrics.server_messages,
+            "client_requests": metrics.client_requests,
+            "messages_per_request": (per_request * 100.0).round() / 100.0,
+            "pending_rpcs": metrics.rpc.as_ref().map(RpcClient::pending).unwrap_or(0),
+            "queue_depth": metrics.queue_depth,
+            "max_queue_depth": metrics.max_queue_depth,
+            "queue_capacity": metrics.queue_capacity,
+            "queue_saturation": saturation.map(|s| (s * 100.0).round() / 100.0),
+            "queue_full": metrics.queue_full,
+            "queue_dropped": metrics.queue_dropped,
+            "queue_rejected": metrics.queue_rejected,
+        })
+    }
+}
+
+// Times the node's handler over a request, see `Handler::done`
+pub(crate) struct Handler {
+    metrics: Option<NodeMetrics>,
+    kind: Option<String>,
+    started: Instant,
+}
+
+// Looked up before the handler runs, as its reply takes the request off the table.
+// The type is only known for requests that came through the input.
+pub(crate) fn handling(metrics: Option<&NodeMetrics>, request: Option<&Message<()>>) -> Handler {
+    let kind = request.and_then(|request| trace::request_kind(&request.src, request.body.id?));
+    Handler {
+        metrics: metrics.cloned(),
+        kind,
+        started: Instant::now(),
+    }
+}
+
+impl Handler {
+    pub(crate) fn done(self) {
+        if let (Some(metrics), Some(kind)) = (self.metrics, self.kind) {
+            let took = self.started.elapsed();
+            metrics
+                .metrics()
+                .latency
+                .entry(kind)
+                .or_default()
+                .record(took);
+        }
+    }
+}
+
+// Body type of the request asking a node for its metrics
+const METRICS_REQUEST: &str = "metrics";
+
+pub(crate) fn is_request(msg: &RawMessage) -> bool {
+    msg.body.payload.get("type").and_then(Value::as_str) == Some(METRICS_REQUEST)
+}
+
+// The metrics_ok reply to a `metrics` request
+pub(crate) fn reply(metrics: &NodeMetrics, request: RawMessage) -> RawMessage {
+    let mut reply = request.into_reply(None);
+    let mut payload = metrics.snapshot();
+    payload["type"] = json!("metrics_ok");
+    reply.body.payload = payload;
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Body, Output};
+
+    fn micros(n: u64) -> Duration {
+        Duration::from_micros(n)
+    }
+
+    #[test]
+    fn quantiles_are_bucket_upper_bounds_capped_at_the_max() {
+        let mut histogram = Histogram::default();
+        // Buckets [1, 2), [2, 4) and [4, 8) microseconds, sub-microsecond in the first
+        for latency in [Duration::ZERO, micros(1), micros(2), micros(3), micros(4)] {
+            histogram.record(latency);
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.max(), micros(4));
+        assert_eq!(histogram.quantile(0.0), micros(2));
+        assert_eq!(histogram.quantile(0.4), micros(2));
+        assert_eq!(histogram.quantile(0.41), micros(4));
+        assert_eq!(histogram.quantile(0.8), micros(4));
+        assert_eq!(histogram.quantile(1.0), micros(4));
+
+        let mut histogram = Histogram::default();
+        histogram.record(micros(1023));
+        histogram.record(micros(1024));
+        assert_eq!(histogram.quantile(0.5), micros(1024));
+        assert_eq!(histogram.quantile(1.0), micros(1024));
+    }
+
+    #[test]
+    fn latencies_past_the_last_bucket_land_in_it() {
+        let mut histogram = Histogram::default();
+        let hour = Duration::from_secs(3600);
+        histogram.record(hour);
+        assert_eq!(histogram.buckets[BUCKETS - 1], 1);
+        assert_eq!(histogram.quantile(0.99), hour);
+        assert_eq!(Histogram::default().quantile(0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn means_hold_up_past_u32_max_samples() {
+        let mut histogram = Histogram::default();
+        histogram.record(micros(1));
+        histogram.record(micros(2));
+        assert_eq!(histogram.mean(), Duration::from_nanos(1500));
+
+        let count = u32::MAX as u64 + 2;
+        let histogram = Histogram {
+            count,
+            sum: Duration::from_millis(count),
+            ..Histogram::default()
+        };
+        assert_eq!(histogram.mean(), Duration::from_millis(1));
+        assert_eq!(Histogram::default().mean(), Duration::ZERO);
+    }
+
+    #[test]
+    fn only_outputs_of_a_node_count_what_they_send() -> anyhow::Result<()> {
+        let msg = Message {
+            src: "n1".to_string(),
+            dst: "n2".to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                clock: Default::default(),
+                payload: json!({"type": "gossip"}),
+            },
+        };
+        let metrics = NodeMetrics::default();
+        msg.send(&mut Output::new(std::io::sink()).with_metrics(metrics.clone()))?;
+        msg.send(&mut Output::new(std::io::sink()))?;
+
+        let other = NodeMetrics::default();
+        other.received(&msg, Some("gossip"));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot["sent"], json!({"gossip": 1}));
+        assert_eq!(snapshot["received"], json!({}));
+        assert_eq!(snapshot["server_messages"], 1);
+        assert_eq!(other.snapshot()["received"], json!({"gossip": 1}));
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use rand::Rng;
+use std::{collections::HashMap, time::Duration};
+
+// How long a message spends on the wire
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    Uniform(Duration, Duration),
+    // Mostly fast with a long tail, closer to what real networks do
+    Exponential { mean: Duration },
+}
+
+impl Latency {
+    pub(crate) fn sample(&self, rng: &mut impl Rng) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform(min, max) => rng.gen_range(min..=max.max(min)),
+            Latency::Exponential { mean } => {
+                let u: f64 = rng.gen();
+                mean.mul_f64(-(1.0 - u).ln())
+            }
+        }
+    }
+}
+
+// Faults applied to messages between nodes. Clients talk to the cluster over
+// a reliable network, like they do under Maelstrom's nemesis.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    pub drop_probability: f64,
+    pub duplicate_probability: f64,
+    // Chance of holding a message back by up to `reorder_delay` on top of its
+    // latency, so it overtakes or gets overtaken by its neighbours
+    pub reorder_probability: f64,
+    pub reorder_delay: Duration,
+}
+
+impl Faults {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn drop(mut self, probability: f64) -> Self {
+        self.drop_probability = probability;
+        self
+    }
+
+    pub fn duplicate(mut self, probability: f64) -> Self {
+        self.duplicate_probability = probability;
+        self
+    }
+
+    pub fn reorder(mut self, probability: f64, delay: Duration) -> Self {
+        self.reorder_probability = probability;
+        self.reorder_delay = delay;
+        self
+    }
+
+    // How many copies of a message go out: 0 when dropped, 2 when duplicated
+    pub(crate) fn copies(&self, rng: &mut impl Rng) -> usize {
+        if rng.gen_bool(self.drop_probability.clamp(0.0, 1.0)) {
+            0
+        } else if rng.gen_bool(self.duplicate_probability.clamp(0.0, 1.0)) {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub(crate) fn extra_delay(&self, rng: &mut impl Rng) -> Duration {
+        if !self.reorder_delay.is_zero() && rng.gen_bool(self.reorder_probability.clamp(0.0, 1.0)) {
+            rng.gen_range(Duration::ZERO..=self.reorder_delay)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+// Nodes split into groups that can only talk within themselves
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Partition {
+    groups: HashMap<String, usize>,
+}
+
+impl Partition {
+    // Nodes left out of every group end up alone in their own
+    pub(crate) fn new(groups: &[&[&str]]) -> Self {
+        let groups = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(i, group)| group.iter().map(move |node| (node.to_string(), i)))
+            .collect();
+        Self { groups }
+    }
+
+    pub(crate) fn allows(&self, src: &str, dst: &str) -> bool {
+        match (self.groups.get(src), self.groups.get(dst)) {
+            (Some(a), Some(b)) => a == b,
+            _ => src == dst,
+        }
+    }
+}
+
+// What the network did to node-to-node traffic during a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub sent: usize,
+    pub delivered: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub partitioned: usize,
+}
@@ -0,0 +1,422 @@
+use crate::{clock, metrics::NodeMetrics, trace, Body, Codec, Message, RawMessage};
+
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// When buffered output is handed to the sink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffering {
+    // Flush once this many bytes are waiting
+    pub max_bytes: usize,
+    // Flush once the oldest waiting message is this old
+    pub max_delay: Duration,
+}
+
+impl Default for Buffering {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024,
+            max_delay: Duration::from_millis(5),
+        }
+    }
+}
+
+// How much a node wrote and how many writes the sink saw for it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputStats {
+    pub messages: u64,
+    pub bytes: u64,
+    pub writes: u64,
+}
+
+impl OutputStats {
+    pub fn messages_per_write(&self) -> f64 {
+        self.messages as f64 / self.writes.max(1) as f64
+    }
+}
+
+// Where a node writes its messages to: stdout under main_loop, a buffer in the simulator
+//
+// Unbuffered, every message reaches the sink as it is sent. With `Buffering`
+// messages pile up and go out in one write once the buffer is big or old
+// enough, or when the event loop runs out of work and calls `flush`.
+pub struct Output {
+    sink: Box<dyn Write + Send>,
+    buffering: Option<Buffering>,
+    buffer: Vec<u8>,
+    oldest: Option<Instant>,
+    // Payloads queued with `batch`, per (src, dst)
+    batches: BTreeMap<(String, String), Vec<Value>>,
+    stats: OutputStats,
+    // What messages are encoded with, see `Message::send`
+    codec: Codec,
+    // Of the node writing here, if any
+    metrics: Option<NodeMetrics>,
+}
+
+impl Output {
+    pub fn new(sink: impl Write + Send + 'static) -> Self {
+        Self {
+            sink: Box::new(sink),
+            buffering: None,
+            buffer: Vec::new(),
+            oldest: None,
+            batches: BTreeMap::new(),
+            stats: OutputStats::default(),
+            codec: Codec::JsonLines,
+            metrics: None,
+        }
+    }
+
+    // For a sink whose other end speaks `codec`
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    // Counts what goes out as sent by the node `metrics` belong to
+    pub(crate) fn with_metrics(mut self, metrics: NodeMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub(crate) fn metrics(&self) -> Option<&NodeMetrics> {
+        self.metrics.as_ref()
+    }
+
+    pub fn buffered(sink: impl Write + Send + 'static, buffering: Buffering) -> Self {
+        let mut output = Self::new(sink);
+        output.set_buffering(Some(buffering));
+        output
+    }
+
+    pub fn set_buffering(&mut self, buffering: Option<Buffering>) {
+        self.buffering = buffering;
+    }
+
+    pub fn stats(&self) -> OutputStats {
+        self.stats
+    }
+
+    // Queues `msg` to go out with every other payload batched for the same
+    // destination, as a single `batch` message, the next time output is flushed.
+    // The receiving main_loop (or simulator) unpacks it, so only batch between
+    // nodes running this library, never to clients.
+    pub fn batch<Payload>(&mut self, msg: Message<Payload>) -> anyhow::Result<()>
+    where
+        Payload: Serialize,
+    {
+        let body = serde_json::to_value(&msg.body).context("serialize batched body")?;
+        self.batches
+            .entry((msg.src, msg.dst))
+            .or_default()
+            .push(body);
+        Ok(())
+    }
+
+    // Flushes if the buffer has waited long enough
+    pub fn flush_if_due(&mut self) -> std::io::Result<()> {
+        let due = match (self.buffering, self.oldest) {
+            (Some(buffering), Some(oldest)) => oldest.elapsed() >= buffering.max_delay,
+            _ => false,
+        };
+        if due {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn send_batches(&mut self) -> std::io::Result<()> {
+        for ((src, dst), mut bodies) in std::mem::take(&mut self.batches) {
+            let body = match bodies.len() {
+                1 => bodies.pop().expect("one body"),
+                _ => json!({"type": BATCH, "messages": bodies}),
+            };
+            let mut msg: RawMessage = Message {
+                src,
+                dst,
+                body: serde_json::from_value(body)?,
+            };
+            clock::sent(&mut msg);
+            let kind = msg.body.payload.get("type").and_then(Value::as_str);
+            if let Some(metrics) = &self.metrics {
+                metrics.sent(&msg, kind);
+            }
+            trace::sent(&msg, kind);
+            let mut frame = Vec::new();
+            self.codec
+                .encode(&msg, &mut frame)
+                .map_err(std::io::Error::other)?;
+            self.write_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    fn flush_buffer(&mut self) -> std::io::Result<()> {
+        self.oldest = None;
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.stats.writes += 1;
+        let result = self.sink.write_all(&self.buffer);
+        self.buffer.clear();
+        result
+    }
+}
+
+impl Output {
+    // Writes one whole message, as encoded by the output's codec
+    pub(crate) fn write_frame(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        self.stats.messages += 1;
+        self.stats.bytes += frame.len() as u64;
+
+        let Some(buffering) = self.buffering else {
+            self.stats.writes += 1;
+            return self.sink.write_all(frame);
+        };
+
+        self.buffer.extend_from_slice(frame);
+        let oldest = *self.oldest.get_or_insert_with(Instant::now);
+        if self.buffer.len() >= buffering.max_bytes || oldest.elapsed() >= buffering.max_delay {
+            self.flush_buffer()?;
+        }
+        Ok(())
+    }
+}
+
+// Whatever is written is taken for whole frames of the output's codec
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_frame(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_batches()?;
+        self.flush_buffer()?;
+        self.sink.flush()
+    }
+}
+
+// Body type of the message a batch goes out as
+const BATCH: &str = "batch";
+
+// Splits a `batch` message back into the messages that were batched
+pub(crate) fn unbatch(msg: RawMessage) -> Vec<RawMessage> {
+    if msg.body.payload.get("type").and_then(Value::as_str) != Some(BATCH) {
+        return vec![msg];
+    }
+    let Some(Value::Array(bodies)) = msg.body.payload.get("messages") else {
+        return vec![msg];
+    };
+
+    // The batch went out as one message, at one time
+    bodies
+        .iter()
+        .filter_map(|body| serde_json::from_value::<Body<Value>>(body.clone()).ok())
+        .map(|mut body| {
+            if body.clock.is_empty() {
+                body.clock = msg.body.clock.clone();
+            }
+            Message {
+                src: msg.src.clone(),
+                dst: msg.dst.clone(),
+                body,
+            }
+        })
+        .collect()
+}
+
+// Output shared between concurrently running handlers
+// Each message is written while holding the lock so lines never interleave
+#[derive(Clone)]
+pub struct SharedOutput {
+    inner: Arc<Mutex<Output>>,
+}
+
+impl SharedOutput {
+    pub fn new(output: impl Write + Send + 'static) -> Self {
+        Self::from(Output::new(output))
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(std::io::stdout())
+    }
+
+    pub fn with<T>(&self, f: impl FnOnce(&mut Output) -> T) -> T {
+        let mut output = self.inner.lock().expect("output lock poisoned");
+        f(&mut output)
+    }
+
+    pub fn send<Payload>(&self, msg: &Message<Payload>) -> anyhow::Result<()>
+    where
+        Payload: Serialize,
+    {
+        self.with(|output| msg.send(output))
+    }
+
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.with(|output| output.flush())
+    }
+}
+
+impl From<Output> for SharedOutput {
+    fn from(output: Output) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(output)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts the writes reaching the sink, as a node's syscalls on stdout
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Sink {
+        fn writes(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+
+        fn messages(&self) -> Vec<RawMessage> {
+            let written = self.0.lock().unwrap().concat();
+            let mut written = written.as_slice();
+            let mut messages = Vec::new();
+            while let Some(frame) = Codec::JsonLines.read_frame(&mut written).unwrap() {
+                messages.push(Codec::JsonLines.decode_frame(&frame).unwrap());
+            }
+            messages
+        }
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn gossip(dst: &str, seen: &[u64]) -> Message<Value> {
+        Message {
+            src: "n0".to_string(),
+            dst: dst.to_string(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                clock: Default::default(),
+                payload: json!({"type": "gossip", "seen": seen}),
+            },
+        }
+    }
+
+    #[test]
+    fn buffered_output_goes_out_once_big_enough() {
+        let sink = Sink::default();
+        let buffering = Buffering {
+            max_bytes: 200,
+            max_delay: Duration::from_secs(3600),
+        };
+        let mut output = Output::buffered(sink.clone(), buffering);
+
+        let mut sent = 0;
+        while sink.writes() == 0 {
+            gossip("n1", &[1, 2, 3]).send(&mut output).unwrap();
+            sent += 1;
+        }
+        assert!(sent > 1);
+        assert!(output.stats().bytes >= 200);
+        assert_eq!(sink.messages().len(), sent);
+    }
+
+    #[test]
+    fn buffered_output_goes_out_once_old_enough() {
+        let sink = Sink::default();
+        let buffering = Buffering {
+            max_bytes: usize::MAX,
+            max_delay: Duration::from_millis(20),
+        };
+        let mut output = Output::buffered(sink.clone(), buffering);
+
+        gossip("n1", &[1]).send(&mut output).unwrap();
+        output.flush_if_due().unwrap();
+        assert_eq!(sink.writes(), 0);
+
+        std::thread::sleep(Duration::from_millis(30));
+        output.flush_if_due().unwrap();
+        assert_eq!(sink.writes(), 1);
+        assert_eq!(sink.messages().len(), 1);
+    }
+
+    #[test]
+    fn batches_unpack_into_the_messages_batched() {
+        let sink = Sink::default();
+        let mut output = Output::new(sink.clone());
+        output.batch(gossip("n1", &[1])).unwrap();
+        output.batch(gossip("n2", &[2])).unwrap();
+        output.batch(gossip("n1", &[3])).unwrap();
+        output.flush().unwrap();
+
+        // One message per destination, a lone payload goes as it is
+        let written = sink.messages();
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0].body.payload["type"], "batch");
+        assert_eq!(written[1].body.payload, gossip("n2", &[2]).body.payload);
+
+        let unbatched = unbatch(written[0].clone());
+        assert_eq!(unbatched.len(), 2);
+        for (msg, seen) in unbatched.iter().zip([1, 3]) {
+            assert_eq!((msg.src.as_str(), msg.dst.as_str()), ("n0", "n1"));
+            assert_eq!(msg.body.payload, gossip("n1", &[seen]).body.payload);
+        }
+        assert_eq!(unbatch(written[1].clone()).len(), 1);
+    }
+
+    // A gossip round to every neighbour per tick, flushed when the node runs
+    // out of work like main_loop does
+    fn gossip_rounds(mut output: Output, sink: &Sink) -> OutputStats {
+        let neighbours = ["n1", "n2", "n3", "n4"];
+        for tick in 0..100 {
+            for neighbour in neighbours {
+                gossip(neighbour, &[tick]).send(&mut output).unwrap();
+            }
+            output.flush().unwrap();
+        }
+        let stats = output.stats();
+        assert_eq!(stats.writes as usize, sink.writes());
+        stats
+    }
+
+    #[test]
+    fn buffering_gossip_takes_fewer_writes_per_message() {
+        let sink = Sink::default();
+        let unbuffered = gossip_rounds(Output::new(sink.clone()), &sink);
+        assert_eq!(unbuffered.messages, 400);
+        assert_eq!(unbuffered.writes, 400);
+        assert_eq!(unbuffered.messages_per_write(), 1.0);
+
+        let sink = Sink::default();
+        let buffered = gossip_rounds(Output::buffered(sink.clone(), Buffering::default()), &sink);
+        assert_eq!(buffered.messages, 400);
+        assert_eq!(buffered.writes, 100);
+        assert_eq!(buffered.messages_per_write(), 4.0);
+        assert_eq!(buffered.bytes, unbuffered.bytes);
+    }
+}
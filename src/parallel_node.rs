@@ -0,0 +1,625 @@
+use crate::{
+    clock, connect,
+    journal::{self, Journal},
+    metrics::NodeMetrics,
+    next_input, reply_not_supported, step_with, trace, transport_from_env, Backpressure, Buffering,
+    Clocks, Codec, Connection, Event, Inbox, Init, Next, Node, Output, Reader, RpcClient,
+    Transport,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    io::Write,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+};
+
+// Counterpart of `Node` whose events are handled by a pool of worker threads.
+// Events sharing a partition key are handled one at a time in the order they
+// arrived, events without keys run alongside anything. State shared between
+// handlers needs interior mutability.
+pub trait ParallelNode<S, Payload, InjectedPayload = ()>: Sized + Send + Sync + 'static {
+    fn from_init(
+        state: S,
+        init: Init,
+        inject: mpsc::Sender<Event<Payload, InjectedPayload>>,
+    ) -> anyhow::Result<Self>;
+
+    // Keys of what `input` reads or writes, eg: the key of a Kafka log or every
+    // key of a transaction (see `partition_key`)
+    fn partition(&self, input: &Event<Payload, InjectedPayload>) -> Vec<u64>;
+
+    fn step(
+        &self,
+        input: Event<Payload, InjectedPayload>,
+        output: &mut Output,
+    ) -> anyhow::Result<()>;
+
+    fn rpc(&self) -> Option<RpcClient> {
+        None
+    }
+
+    // See `Node::buffering`, applies to what every worker writes
+    fn buffering(&self) -> Option<Buffering> {
+        None
+    }
+
+    // See `Node::backpressure`, counts the events no worker has taken up yet
+    fn backpressure(&self) -> Option<Backpressure> {
+        None
+    }
+
+    // See `Node::clocks`
+    fn clocks(&self) -> Option<Clocks> {
+        None
+    }
+
+    // See `Node::on_unknown`, runs on a worker without any partition key
+    fn on_unknown(&self, raw: Value, output: &mut Output) -> anyhow::Result<()> {
+        reply_not_supported(&raw, output)
+    }
+
+    // Runs once after `Event::EOF` has been stepped, when every worker is done
+    fn on_shutdown(&self, _output: &mut Output) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+// A ParallelNode stepped one event at a time, as a `Node`, eg: to run it in the
+// `Simulator`, where the order events are handled in is up to the seed
+pub struct Serial<N>(pub N);
+
+impl<S, P, IP, N> Node<S, P, IP> for Serial<N>
+where
+    N: ParallelNode<S, P, IP>,
+{
+    fn from_init(state: S, init: Init, inject: mpsc::Sender<Event<P, IP>>) -> anyhow::Result<Self> {
+        N::from_init(state, init, inject).map(Serial)
+    }
+
+    fn step(&mut self, input: Event<P, IP>, output: &mut Output) -> anyhow::Result<()> {
+        self.0.step(input, output)
+    }
+
+    fn rpc(&self) -> Option<RpcClient> {
+        self.0.rpc()
+    }
+
+    fn buffering(&self) -> Option<Buffering> {
+        self.0.buffering()
+    }
+
+    fn backpressure(&self) -> Option<Backpressure> {
+        self.0.backpressure()
+    }
+
+    fn on_unknown(&mut self, raw: Value, output: &mut Output) -> anyhow::Result<()> {
+        self.0.on_unknown(raw, output)
+    }
+
+    fn clocks(&self) -> Option<Clocks> {
+        self.0.clocks()
+    }
+
+    fn on_shutdown(&mut self, output: &mut Output) -> anyhow::Result<()> {
+        self.0.on_shutdown(output)
+    }
+}
+
+pub fn partition_key(key: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Same protocol as `main_loop`, with `workers` threads stepping the node,
+// or one per core when it's 0. Journals and replays like main_loop too,
+// replaying the events one at a time in the order they were dispatched.
+pub fn parallel_main_loop<S, N, P, IP>(init_state: S, workers: usize) -> anyhow::Result<()>
+where
+    P: Serialize + DeserializeOwned + Send + 'static,
+    N: ParallelNode<S, P, IP>,
+    IP: Serialize + DeserializeOwned + Send + 'static,
+{
+    if let Ok(path) = std::env::var("NODE_REPLAY") {
+        let realtime = std::env::var("NODE_REPLAY_REALTIME").is_ok_and(|v| v == "1");
+        return journal::replay::<S, Serial<N>, P, IP>(init_state, &path, realtime);
+    }
+    parallel_main_loop_with::<S, N, P, IP>(init_state, workers, transport_from_env()?)
+}
+
+// parallel_main_loop over any transport
+pub fn parallel_main_loop_with<S, N, P, IP>(
+    init_state: S,
+    workers: usize,
+    transport: Box<dyn Transport>,
+) -> anyhow::Result<()>
+where
+    P: Serialize + DeserializeOwned + Send + 'static,
+    N: ParallelNode<S, P, IP>,
+    IP: Serialize + Send + 'static,
+{
+    let workers = match workers {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let (inject_tx, inject_rx) = mpsc::channel();
+
+    let Connection {
+        input,
+        output,
+        codec,
+        metrics,
+        tracer,
+        init,
+        init_reply,
+    } = connect(transport)?;
+    let _tracer = trace::enter(Some(tracer.clone()));
+    let mut stdout = Output::new(output)
+        .with_codec(codec)
+        .with_metrics(metrics.clone());
+    let journal = Journal::from_env(&init)?.map(Arc::new);
+
+    let node =
+        Arc::new(N::from_init(init_state, init, inject_tx).context("node initialization failed")?);
+
+    init_reply
+        .send(&mut stdout)
+        .context("Serialize response to init")?;
+
+    let clock = clock::NodeClock::new(&init_reply.src, node.clocks());
+    let _clock = clock::enter(clock.clone());
+    stdout.set_buffering(node.buffering());
+    stdout.flush().context("flush init reply")?;
+
+    let rpc = node.rpc();
+    if let Some(rpc) = &rpc {
+        metrics.watch_rpc(rpc.clone());
+    }
+    let reader = Reader {
+        input,
+        codec,
+        metrics: metrics.clone(),
+        tracer: tracer.clone(),
+    };
+    let inbox = Inbox::start(
+        reader,
+        rpc.clone(),
+        journal.clone(),
+        node.backpressure(),
+        inject_rx,
+    );
+
+    // Workers hand every message they write to this thread, which owns the output
+    let (frames_tx, frames_rx) = mpsc::channel();
+    let scheduler = Arc::new(Scheduler::new(workers * SCHEDULED_PER_WORKER));
+    for _ in 0..workers {
+        let node = node.clone();
+        let scheduler = scheduler.clone();
+        let frames = frames_tx.clone();
+        let output = frame_output(frames.clone(), codec, &metrics);
+        let clock = clock.clone();
+        let tracer = tracer.clone();
+        thread::spawn(move || {
+            let _clock = clock::enter(clock);
+            let _tracer = trace::enter(Some(tracer));
+            work(&*node, &scheduler, frames, output)
+        });
+    }
+
+    // Takes events off the queue as fast as the workers take them up, so a
+    // full queue pushes back like main_loop's does
+    let dispatcher = {
+        let node = node.clone();
+        let scheduler = scheduler.clone();
+        let queue = inbox.queue.clone();
+        let journal = journal.clone();
+        let clock = clock.clone();
+        let tracer = tracer.clone();
+        let mut output = frame_output(frames_tx.clone(), codec, &metrics);
+        thread::spawn(move || {
+            let _clock = clock::enter(clock);
+            let _tracer = trace::enter(Some(tracer));
+            loop {
+                let next = match next_input(queue.pop(), journal.as_deref(), &mut output) {
+                    Ok(next) => next,
+                    Err(e) => {
+                        let _ = frames_tx.send(Frame::Failed(e));
+                        break;
+                    }
+                };
+                match next {
+                    // EOF is stepped once everything before it is done
+                    Some(Next::Event(Event::EOF)) => break,
+                    Some(Next::Event(event)) => {
+                        scheduler.submit(node.partition(&event), Next::Event(event))
+                    }
+                    Some(Next::Unknown(raw)) => scheduler.submit(Vec::new(), Next::Unknown(raw)),
+                    None => {}
+                }
+            }
+            scheduler.close();
+        })
+    };
+
+    loop {
+        // Running out of work is the moment to hand buffered output over
+        let frame = match frames_rx.try_recv() {
+            Ok(frame) => frame,
+            Err(mpsc::TryRecvError::Empty) => {
+                stdout.flush().context("flush output")?;
+                match frames_rx.recv() {
+                    Ok(frame) => frame,
+                    Err(_) => break,
+                }
+            }
+            Err(mpsc::TryRecvError::Disconnected) => break,
+        };
+        match frame {
+            Frame::Frame(frame) => stdout.write_frame(&frame).context("write output")?,
+            Frame::Failed(e) => return Err(e),
+        }
+        stdout.flush_if_due().context("flush output")?;
+    }
+    dispatcher.join().expect("dispatcher panicked");
+
+    // Shutdown sequence: nobody will answer outstanding requests anymore
+    if let Some(rpc) = &rpc {
+        rpc.fail_pending();
+    }
+    step_with(Event::EOF, &mut stdout, |event, output| {
+        node.step(event, output)
+    })?;
+    node.on_shutdown(&mut stdout)
+        .context("node shutdown failed")?;
+    stdout.flush().context("flush output")?;
+    trace::info("metrics", metrics.snapshot());
+
+    inbox.join()
+}
+
+// Events taken off the queue but not done yet, per worker, past which the
+// dispatcher leaves them queued
+const SCHEDULED_PER_WORKER: usize = 2;
+
+enum Frame {
+    Frame(Vec<u8>),
+    Failed(anyhow::Error),
+}
+
+fn frame_output(frames: mpsc::Sender<Frame>, codec: Codec, metrics: &NodeMetrics) -> Output {
+    Output::new(FrameWriter { frames })
+        .with_codec(codec)
+        .with_metrics(metrics.clone())
+}
+
+fn work<S, N, P, IP>(
+    node: &N,
+    scheduler: &Scheduler<Next<P, IP>>,
+    frames: mpsc::Sender<Frame>,
+    mut output: Output,
+) where
+    N: ParallelNode<S, P, IP>,
+{
+    while let Some((id, next)) = scheduler.take() {
+        let result = match next {
+            Next::Unknown(raw) => node
+                .on_unknown(raw, &mut output)
+                .context("Node on_unknown failed"),
+            Next::Event(event) => {
+                step_with(event, &mut output, |event, output| node.step(event, output))
+            }
+        };
+        // Batches go out with the step that made them, buffering is up to
+        // the thread owning the output
+        let result = result.and_then(|()| output.flush().context("flush output"));
+        scheduler.done(id);
+
+        if let Err(e) = result {
+            let _ = frames.send(Frame::Failed(e));
+            return;
+        }
+    }
+}
+
+// Hands each message to the thread that owns the output, which `Output`
+// writes whole
+struct FrameWriter {
+    frames: mpsc::Sender<Frame>,
+}
+
+impl Write for FrameWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.frames
+            .send(Frame::Frame(buf.to_vec()))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Orders jobs by key: every key has a queue of the jobs on it, and a job is
+// ready once it's at the front of all of its queues. Holds up to `capacity`
+// jobs, submitting more waits for one to be done.
+struct Scheduler<T> {
+    state: Mutex<SchedulerState<T>>,
+    ready: Condvar,
+    space: Condvar,
+    capacity: usize,
+}
+
+struct SchedulerState<T> {
+    next_id: u64,
+    // id -> (keys, keys it still waits on, job until it's taken)
+    jobs: HashMap<u64, (Vec<u64>, usize, Option<T>)>,
+    queues: HashMap<u64, VecDeque<u64>>,
+    ready: VecDeque<u64>,
+    closed: bool,
+}
+
+impl<T> Scheduler<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(SchedulerState {
+                next_id: 0,
+                jobs: HashMap::new(),
+                queues: HashMap::new(),
+                ready: VecDeque::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+            space: Condvar::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn submit(&self, mut keys: Vec<u64>, job: T) {
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut state = self.state.lock().expect("scheduler poisoned");
+        // The oldest job is always ready, so there is room again eventually
+        while state.jobs.len() >= self.capacity {
+            state = self.space.wait(state).expect("scheduler poisoned");
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+
+        let mut waiting = 0;
+        for &key in &keys {
+            let queue = state.queues.entry(key).or_default();
+            if !queue.is_empty() {
+                waiting += 1;
+            }
+            queue.push_back(id);
+        }
+        state.jobs.insert(id, (keys, waiting, Some(job)));
+        if waiting == 0 {
+            state.ready.push_back(id);
+            self.ready.notify_one();
+        }
+    }
+
+    // Blocks until a job is ready, or returns None once closed and drained
+    fn take(&self) -> Option<(u64, T)> {
+        let mut state = self.state.lock().expect("scheduler poisoned");
+        loop {
+            if let Some(id) = state.ready.pop_front() {
+                let job = state
+                    .jobs
+                    .get_mut(&id)
+                    .and_then(|(_, _, job)| job.take())
+                    .expect("ready job is queued");
+                return Some((id, job));
+            }
+            if state.closed && state.jobs.is_empty() {
+                return None;
+            }
+            state = self.ready.wait(state).expect("scheduler poisoned");
+        }
+    }
+
+    // Releases the keys of job `id`, readying whoever is next on them
+    fn done(&self, id: u64) {
+        let mut state = self.state.lock().expect("scheduler poisoned");
+        let (keys, _, _) = state.jobs.remove(&id).expect("done job is known");
+        self.space.notify_one();
+        let mut readied = 0;
+        for key in keys {
+            let queue = state.queues.get_mut(&key).expect("job key has a queue");
+            queue.pop_front();
+            let Some(&next) = queue.front() else {
+                state.queues.remove(&key);
+                continue;
+            };
+            let (_, waiting, _) = state.jobs.get_mut(&next).expect("queued job is known");
+            *waiting -= 1;
+            if *waiting == 0 {
+                state.ready.push_back(next);
+                readied += 1;
+            }
+        }
+
+        if state.closed && state.jobs.is_empty() {
+            // Idle workers can exit now
+            self.ready.notify_all();
+        } else {
+            for _ in 0..readied {
+                self.ready.notify_one();
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().expect("scheduler poisoned").closed = true;
+        self.ready.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transport::ChannelTransport, Overflow};
+
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::{io::BufRead, time::Duration};
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Payload {
+        Add { key: usize },
+        AddOk { total: usize },
+        Hold,
+        HoldOk,
+    }
+
+    // Counts adds per key, holds take a while without touching any key
+    struct Adding {
+        totals: Mutex<HashMap<usize, usize>>,
+        backpressure: Option<Backpressure>,
+    }
+
+    impl ParallelNode<Option<Backpressure>, Payload> for Adding {
+        fn from_init(
+            backpressure: Option<Backpressure>,
+            _init: Init,
+            _inject: mpsc::Sender<Event<Payload>>,
+        ) -> anyhow::Result<Self> {
+            Ok(Adding {
+                totals: Mutex::default(),
+                backpressure,
+            })
+        }
+
+        fn partition(&self, input: &Event<Payload>) -> Vec<u64> {
+            match input {
+                Event::Message(msg) => match msg.body.payload {
+                    Payload::Add { key } => vec![partition_key(key)],
+                    _ => Vec::new(),
+                },
+                _ => Vec::new(),
+            }
+        }
+
+        fn step(&self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            let mut reply = input.into_reply(None);
+            reply.body.payload = match reply.body.payload {
+                Payload::Add { key } => {
+                    let total = {
+                        let mut totals = self.totals.lock().expect("totals poisoned");
+                        let total = totals.entry(key).or_default();
+                        *total += 1;
+                        *total
+                    };
+                    // Long enough for adds on other keys to get in between
+                    thread::sleep(Duration::from_millis(1));
+                    Payload::AddOk { total }
+                }
+                Payload::Hold => {
+                    thread::sleep(Duration::from_millis(200));
+                    Payload::HoldOk
+                }
+                _ => return Ok(()),
+            };
+            reply.send(output)
+        }
+
+        fn backpressure(&self) -> Option<Backpressure> {
+            self.backpressure
+        }
+    }
+
+    // Runs the node on `workers` threads, sends it `requests` and returns every
+    // reply once it has shut down
+    fn run(backpressure: Option<Backpressure>, workers: usize, requests: Vec<Value>) -> Vec<Value> {
+        let (node_end, client_end) = ChannelTransport::pair();
+        let node = thread::spawn(move || {
+            parallel_main_loop_with::<_, Adding, Payload, ()>(
+                backpressure,
+                workers,
+                Box::new(node_end),
+            )
+        });
+
+        let (input, mut output) = client_end.into_parts();
+        let init = json!({"type": "init", "msg_id": 0, "node_id": "n1", "node_ids": ["n1"]});
+        for (id, body) in std::iter::once(init).chain(requests).enumerate() {
+            let mut body = body;
+            body["msg_id"] = json!(id);
+            let msg = json!({"src": "c1", "dest": "n1", "body": body});
+            writeln!(output, "{}", msg).expect("write to node");
+        }
+        drop(output);
+
+        node.join()
+            .expect("node panicked")
+            .expect("node shut down cleanly");
+        input
+            .lines()
+            .map(|line| serde_json::from_str(&line.expect("line")).expect("node wrote a message"))
+            .filter(|msg: &Value| msg["body"]["type"] != "init_ok")
+            .collect()
+    }
+
+    #[test]
+    fn events_on_a_key_run_in_the_order_they_arrived() {
+        let requests = (0..200).map(|i| json!({"type": "add", "key": i % 3}));
+        let replies = run(None, 4, requests.collect());
+        assert_eq!(replies.len(), 200);
+
+        for reply in replies {
+            // Request i (msg_id i + 1) is add number i / 3 + 1 on its key
+            let i = reply["body"]["in_reply_to"].as_u64().expect("a reply") - 1;
+            assert_eq!(reply["body"]["total"], i / 3 + 1, "{}", reply);
+        }
+    }
+
+    #[test]
+    fn a_full_queue_pushes_back_while_workers_are_busy() {
+        let backpressure = Backpressure {
+            capacity: 1,
+            overflow: Overflow::Reject,
+        };
+        let requests = (0..6).map(|_| json!({"type": "hold"})).collect();
+        let replies = run(Some(backpressure), 1, requests);
+        assert_eq!(replies.len(), 6);
+
+        let rejected = replies
+            .iter()
+            .filter(|reply| reply["body"]["type"] == "error")
+            .inspect(|reply| assert_eq!(reply["body"]["code"], 11))
+            .count();
+        // At most one running, one scheduled behind it, one on its way to the
+        // scheduler and one queued, while the first hold takes its time
+        assert!(rejected >= 2, "{:?}", replies);
+    }
+
+    #[test]
+    fn a_blocked_reader_loses_no_input() {
+        let backpressure = Backpressure {
+            capacity: 1,
+            overflow: Overflow::Block,
+        };
+        let requests = (0..6).map(|_| json!({"type": "hold"})).collect();
+        let replies = run(Some(backpressure), 1, requests);
+
+        let mut answered: Vec<_> = replies
+            .iter()
+            .inspect(|reply| assert_eq!(reply["body"]["type"], "hold_ok"))
+            .map(|reply| reply["body"]["in_reply_to"].as_u64().expect("a reply"))
+            .collect();
+        answered.sort();
+        assert_eq!(answered, [1, 2, 3, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{metrics::NodeMetrics, Message};
+
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex},
+};
+
+// What happens to an event arriving while main_loop's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    // The input thread waits for room, injected events line up behind it. A node
+    // blocking on an RPC inside `step` waits out its timeout, as the reply can't
+    // be read meanwhile.
+    Block,
+    // Injected events are dropped, and queued ones make room for messages
+    DropInjected,
+    // Requests are answered with `temporarily-unavailable` right away
+    Reject,
+}
+
+// Bound on the events waiting for the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backpressure {
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+// Rejected requests waiting for their reply, past that clients time out instead
+const MAX_REJECTED: usize = 64 * 1024;
+
+pub(crate) enum Popped<T> {
+    Event(T),
+    // A request turned away, owed a temporarily-unavailable reply
+    Rejected(Message<()>),
+}
+
+// main_loop's event queue, unbounded without backpressure
+pub(crate) struct EventQueue<T> {
+    backpressure: Option<Backpressure>,
+    metrics: NodeMetrics,
+    state: Mutex<QueueState<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct QueueState<T> {
+    // (event, whether it was injected)
+    events: VecDeque<(T, bool)>,
+    rejected: VecDeque<Message<()>>,
+}
+
+impl<T> EventQueue<T> {
+    pub(crate) fn new(backpressure: Option<Backpressure>, metrics: NodeMetrics) -> Self {
+        if let Some(backpressure) = backpressure {
+            metrics.queue_capacity(backpressure.capacity);
+        }
+        Self {
+            backpressure,
+            metrics,
+            state: Mutex::new(QueueState {
+                events: VecDeque::new(),
+                rejected: VecDeque::new(),
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    // Queues `event`, `request` being the envelope of the request it carries
+    pub(crate) fn push(&self, event: T, injected: bool, request: Option<Message<()>>) {
+        let mut state = self.state.lock().expect("queue poisoned");
+
+        if let Some(Backpressure { capacity, overflow }) = self.backpressure {
+            let capacity = capacity.max(1);
+            if state.events.len() >= capacity {
+                self.metrics.queue_full();
+                match (overflow, injected, request) {
+                    (Overflow::DropInjected, true, _) => {
+                        self.metrics.queue_dropped();
+                        return;
+                    }
+                    (Overflow::DropInjected, false, _) => {
+                        if let Some(i) = state.events.iter().position(|(_, injected)| *injected) {
+                            state.events.remove(i);
+                            self.metrics.dequeued();
+                            self.metrics.queue_dropped();
+                        }
+                    }
+                    (Overflow::Reject, false, Some(request)) => {
+                        self.metrics.queue_rejected();
+                        if state.rejected.len() < MAX_REJECTED {
+                            state.rejected.push_back(request);
+                            self.not_empty.notify_one();
+                        }
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+            while state.events.len() >= capacity {
+                state = self.not_full.wait(state).expect("queue poisoned");
+            }
+        }
+
+        self.metrics.enqueued();
+        state.events.push_back((event, injected));
+        self.not_empty.notify_one();
+    }
+
+    pub(crate) fn try_pop(&self) -> Option<Popped<T>> {
+        let mut state = self.state.lock().expect("queue poisoned");
+        self.take(&mut state)
+    }
+
+    pub(crate) fn pop(&self) -> Popped<T> {
+        let mut state = self.state.lock().expect("queue poisoned");
+        loop {
+            if let Some(popped) = self.take(&mut state) {
+                return popped;
+            }
+            state = self.not_empty.wait(state).expect("queue poisoned");
+        }
+    }
+
+    fn take(&self, state: &mut QueueState<T>) -> Option<Popped<T>> {
+        if let Some(request) = state.rejected.pop_front() {
+            return Some(Popped::Rejected(request));
+        }
+        let (event, _) = state.events.pop_front()?;
+        self.metrics.dequeued();
+        self.not_full.notify_one();
+        Some(Popped::Event(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{sync::Arc, thread, time::Duration};
+
+    fn queue(capacity: usize, overflow: Overflow) -> Arc<EventQueue<&'static str>> {
+        Arc::new(EventQueue::new(
+            Some(Backpressure { capacity, overflow }),
+            NodeMetrics::default(),
+        ))
+    }
+
+    fn popped(queue: &EventQueue<&'static str>) -> Vec<&'static str> {
+        std::iter::from_fn(|| match queue.try_pop()? {
+            Popped::Event(event) => Some(event),
+            Popped::Rejected(_) => panic!("nothing is rejected"),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn blocking_holds_the_reader_until_there_is_room() {
+        let queue = queue(1, Overflow::Block);
+        queue.push("m1", false, None);
+
+        let reader = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                queue.push("m2", false, None);
+                queue.push("tick", true, None);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!reader.is_finished());
+
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            match queue.pop() {
+                Popped::Event(event) => events.push(event),
+                Popped::Rejected(_) => panic!("nothing is rejected"),
+            }
+        }
+        reader.join().expect("reader panicked");
+        assert_eq!(events, ["m1", "m2", "tick"]);
+    }
+
+    #[test]
+    fn dropping_injected_events_makes_room_for_messages() {
+        let queue = queue(2, Overflow::DropInjected);
+        queue.push("tick1", true, None);
+        queue.push("m1", false, None);
+        // Full, so the tick goes
+        queue.push("tick2", true, None);
+        // Full, so the queued tick goes instead
+        queue.push("m2", false, None);
+        assert_eq!(popped(&queue), ["m1", "m2"]);
+
+        queue.push("tick3", true, None);
+        queue.push("m3", false, None);
+        assert_eq!(popped(&queue), ["tick3", "m3"]);
+    }
+}
@@ -0,0 +1,372 @@
+use crate::{clock, Body, MaelstromError, Message, Output};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        mpsc::{self, RecvTimeoutError, TryRecvError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+// Replies are kept as raw JSON until the caller asks for them, since the reply
+// payload does not have to be the node's own Payload type (eg: KV services)
+pub type RawMessage = Message<serde_json::Value>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    Timeout { dst: String, msg_id: usize },
+    Disconnected { dst: String, msg_id: usize },
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout { dst, msg_id } => {
+                write!(f, "rpc {} to {} timed out", msg_id, dst)
+            }
+            RpcError::Disconnected { dst, msg_id } => {
+                write!(f, "rpc {} to {} was dropped before a reply", msg_id, dst)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+// Blocking nodes wait on a std channel, async nodes on a oneshot they can await
+enum Waiter {
+    Blocking(mpsc::Sender<RawMessage>),
+    Async(oneshot::Sender<RawMessage>),
+}
+
+// Stands in for blocking on a reply under the simulator, which hands control
+// back once the reply with that msg_id is in or the timeout passed in virtual
+// time. Tells whether the reply arrived.
+pub(crate) type Parking = Arc<dyn Fn(usize, Duration) -> bool + Send + Sync>;
+
+struct RpcState {
+    next_id: usize,
+    pending: HashMap<usize, Waiter>,
+    parking: Option<Parking>,
+}
+
+// Issues requests from a node and correlates the replies through `in_reply_to`
+// The client is cheap to clone: main_loop keeps a handle so the stdin thread can
+// resolve pending requests while the node is blocked waiting inside `step`
+#[derive(Clone)]
+pub struct RpcClient {
+    node: String,
+    state: Arc<Mutex<RpcState>>,
+}
+
+impl RpcClient {
+    pub fn new(node: impl Into<String>) -> Self {
+        Self {
+            node: node.into(),
+            state: Arc::new(Mutex::new(RpcState {
+                next_id: 1,
+                pending: HashMap::new(),
+                parking: None,
+            })),
+        }
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    // Takes a msg_id from the same sequence requests are numbered from, for the
+    // node's other messages, as replies may only match the request they answer
+    pub fn next_msg_id(&self) -> usize {
+        let mut state = self.state.lock().expect("rpc state poisoned");
+        state.next_id += 1;
+        state.next_id - 1
+    }
+
+    // Sends `payload` to `dst` with a fresh msg_id and registers it as pending
+    pub fn call<P>(
+        &self,
+        dst: impl Into<String>,
+        payload: P,
+        output: &mut Output,
+    ) -> anyhow::Result<PendingReply>
+    where
+        P: Serialize,
+    {
+        let dst = dst.into();
+        let (tx, rx) = mpsc::channel();
+        let msg_id = self.send_request(&dst, payload, Waiter::Blocking(tx), output)?;
+
+        Ok(PendingReply {
+            client: self.clone(),
+            dst,
+            msg_id,
+            rx,
+        })
+    }
+
+    // Same as `call`, but the reply is awaited instead of blocking the thread
+    pub fn call_async<P>(
+        &self,
+        dst: impl Into<String>,
+        payload: P,
+        output: &mut Output,
+    ) -> anyhow::Result<AsyncPendingReply>
+    where
+        P: Serialize,
+    {
+        let dst = dst.into();
+        let (tx, rx) = oneshot::channel();
+        let msg_id = self.send_request(&dst, payload, Waiter::Async(tx), output)?;
+
+        Ok(AsyncPendingReply {
+            client: self.clone(),
+            dst,
+            msg_id,
+            rx,
+        })
+    }
+
+    fn send_request<P>(
+        &self,
+        dst: &str,
+        payload: P,
+        waiter: Waiter,
+        output: &mut Output,
+    ) -> anyhow::Result<usize>
+    where
+        P: Serialize,
+    {
+        let msg_id = {
+            let mut state = self.state.lock().expect("rpc state poisoned");
+            let msg_id = state.next_id;
+            state.next_id += 1;
+            state.pending.insert(msg_id, waiter);
+            msg_id
+        };
+
+        let request = Message {
+            src: self.node.clone(),
+            dst: dst.to_string(),
+            body: Body {
+                id: Some(msg_id),
+                in_reply_to: None,
+                clock: Default::default(),
+                payload,
+            },
+        };
+
+        if let Err(e) = request.send(output) {
+            self.forget(msg_id);
+            return Err(e).with_context(|| format!("send rpc {} to {}", msg_id, dst));
+        }
+
+        Ok(msg_id)
+    }
+
+    // Hands `msg` to the request waiting on it, if any
+    // Gives the message back when it isn't a reply to one of our pending requests
+    pub fn resolve(&self, msg: RawMessage) -> Option<RawMessage> {
+        let Some(in_reply_to) = msg.body.in_reply_to else {
+            return Some(msg);
+        };
+
+        let waiter = self
+            .state
+            .lock()
+            .expect("rpc state poisoned")
+            .pending
+            .remove(&in_reply_to);
+
+        // The caller may have given up on the reply already
+        match waiter {
+            Some(Waiter::Blocking(tx)) => {
+                let _ = tx.send(msg);
+                None
+            }
+            Some(Waiter::Async(tx)) => {
+                let _ = tx.send(msg);
+                None
+            }
+            None => Some(msg),
+        }
+    }
+
+    // Fails every outstanding request with `RpcError::Disconnected`, used on
+    // shutdown when no more replies can arrive. Returns how many were pending.
+    pub fn fail_pending(&self) -> usize {
+        let mut state = self.state.lock().expect("rpc state poisoned");
+        let pending = state.pending.len();
+        state.pending.clear();
+        pending
+    }
+
+    // Whether a request is waiting on the reply to `msg_id`
+    pub(crate) fn awaits(&self, msg_id: usize) -> bool {
+        let state = self.state.lock().expect("rpc state poisoned");
+        state.pending.contains_key(&msg_id)
+    }
+
+    pub fn pending(&self) -> usize {
+        self.state.lock().expect("rpc state poisoned").pending.len()
+    }
+
+    // Makes `PendingReply::wait` park instead of blocking, for every clone of the client
+    pub(crate) fn park_with(&self, parking: Parking) {
+        self.state.lock().expect("rpc state poisoned").parking = Some(parking);
+    }
+
+    fn parking(&self) -> Option<Parking> {
+        self.state
+            .lock()
+            .expect("rpc state poisoned")
+            .parking
+            .clone()
+    }
+
+    fn forget(&self, msg_id: usize) {
+        self.state
+            .lock()
+            .expect("rpc state poisoned")
+            .pending
+            .remove(&msg_id);
+    }
+}
+
+// Handle for a request sent with `RpcClient::call`
+pub struct PendingReply {
+    client: RpcClient,
+    dst: String,
+    msg_id: usize,
+    rx: mpsc::Receiver<RawMessage>,
+}
+
+impl PendingReply {
+    pub fn msg_id(&self) -> usize {
+        self.msg_id
+    }
+
+    // Blocks until the reply arrives, failing with `RpcError::Timeout` after `timeout`
+    pub fn wait<R>(self, timeout: Duration) -> anyhow::Result<Message<R>>
+    where
+        R: DeserializeOwned,
+    {
+        let received = match self.client.parking() {
+            None => self.rx.recv_timeout(timeout),
+            Some(park) => match self.rx.try_recv() {
+                Ok(reply) => Ok(reply),
+                Err(TryRecvError::Empty) if park(self.msg_id, timeout) => self
+                    .rx
+                    .try_recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => Err(RecvTimeoutError::Timeout),
+                Err(TryRecvError::Disconnected) => Err(RecvTimeoutError::Disconnected),
+            },
+        };
+        match received {
+            Ok(reply) => decode_reply(reply),
+            Err(RecvTimeoutError::Timeout) => Err(RpcError::Timeout {
+                dst: self.dst.clone(),
+                msg_id: self.msg_id,
+            }
+            .into()),
+            Err(RecvTimeoutError::Disconnected) => Err(RpcError::Disconnected {
+                dst: self.dst.clone(),
+                msg_id: self.msg_id,
+            }
+            .into()),
+        }
+    }
+
+    // Non-blocking check, for nodes that poll their requests from `step`
+    pub fn try_recv<R>(&self) -> anyhow::Result<Option<Message<R>>>
+    where
+        R: DeserializeOwned,
+    {
+        match self.rx.try_recv() {
+            Ok(reply) => decode_reply(reply).map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(RpcError::Disconnected {
+                dst: self.dst.clone(),
+                msg_id: self.msg_id,
+            }
+            .into()),
+        }
+    }
+}
+
+// Handle for a request sent with `RpcClient::call_async`
+pub struct AsyncPendingReply {
+    client: RpcClient,
+    dst: String,
+    msg_id: usize,
+    rx: oneshot::Receiver<RawMessage>,
+}
+
+impl AsyncPendingReply {
+    pub fn msg_id(&self) -> usize {
+        self.msg_id
+    }
+
+    pub async fn wait<R>(mut self, timeout: Duration) -> anyhow::Result<Message<R>>
+    where
+        R: DeserializeOwned,
+    {
+        // None once timed out, Some(None) if the client went away
+        let received = match self.client.parking() {
+            None => tokio::time::timeout(timeout, &mut self.rx)
+                .await
+                .ok()
+                .map(Result::ok),
+            // Parks the way `PendingReply::wait` does, eg: replaying a journal
+            Some(park) => match self.rx.try_recv() {
+                Err(oneshot::error::TryRecvError::Empty) if !park(self.msg_id, timeout) => None,
+                Err(oneshot::error::TryRecvError::Empty) => Some(self.rx.try_recv().ok()),
+                received => Some(received.ok()),
+            },
+        };
+        match received {
+            Some(Some(reply)) => decode_reply(reply),
+            Some(None) => Err(RpcError::Disconnected {
+                dst: self.dst.clone(),
+                msg_id: self.msg_id,
+            }
+            .into()),
+            None => Err(RpcError::Timeout {
+                dst: self.dst.clone(),
+                msg_id: self.msg_id,
+            }
+            .into()),
+        }
+    }
+}
+
+impl Drop for AsyncPendingReply {
+    fn drop(&mut self) {
+        self.client.forget(self.msg_id);
+    }
+}
+
+// Error replies fail the call with the `MaelstromError` the peer sent us
+fn decode_reply<R>(reply: RawMessage) -> anyhow::Result<Message<R>>
+where
+    R: DeserializeOwned,
+{
+    clock::received(&reply.body.clock);
+    if let Some(error) = MaelstromError::from_reply(&reply) {
+        return Err(error.into());
+    }
+    reply.decode().context("deserialize rpc reply")
+}
+
+// A reply that is no longer awaited must not linger in the pending table
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        self.client.forget(self.msg_id);
+    }
+}
@@ -0,0 +1,1079 @@
+use crate::{
+    clock::{self, NodeClock},
+    metrics::NodeMetrics,
+    nemesis::Partition,
+    output::unbatch,
+    step_node,
+    trace::{self, NodeTracer},
+    Body, Codec, Driver, Event, Faults, History, Init, KvService, KvStore, Latency, Message,
+    NetworkStats, Node, Output, RawMessage, RpcClient, Trace,
+};
+
+use anyhow::Context;
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap, VecDeque},
+    io::Write,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+// What nodes encode their output with, as they would for Maelstrom
+const CODEC: Codec = Codec::JsonLines;
+
+// Node output captured in memory, drained by the simulator after every step
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .expect("capture poisoned")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct SimNode<N, P, IP> {
+    // Taken by the thread of a step for as long as it runs
+    node: Option<(N, Output)>,
+    rpc: Option<RpcClient>,
+    clock: Option<NodeClock>,
+    metrics: NodeMetrics,
+    tracer: NodeTracer,
+    capture: Capture,
+    // Only nodes with an rpc client can block on replies, their steps run on threads
+    lockstep: Option<Lockstep<N>>,
+    // What the node gets to once the step waiting on a reply is done, in order
+    backlog: VecDeque<Work<P, IP>>,
+}
+
+// What the simulator has a node do
+enum Work<P, IP> {
+    Event(Event<P, IP>),
+    Unknown(Value),
+    Shutdown,
+}
+
+// The simulator and the thread stepping a node take turns: a step parked on a
+// reply signals `Waiting` and blocks until resumed, telling it whether the
+// reply arrived, and a step that returned signals `Done`
+struct Lockstep<N> {
+    signals: mpsc::Receiver<Signal>,
+    done: mpsc::Sender<Signal>,
+    resume: mpsc::Sender<bool>,
+    step: Option<thread::JoinHandle<(N, Output, anyhow::Result<()>)>>,
+    // msg_id of the reply the step is parked on
+    waiting: Option<usize>,
+}
+
+enum Signal {
+    Waiting { msg_id: usize, timeout: Duration },
+    Done,
+}
+
+// How far a step got before handing control back
+enum Stepped {
+    Done(anyhow::Result<()>),
+    Waiting { msg_id: usize, timeout: Duration },
+}
+
+impl<N> Lockstep<N> {
+    fn new(rpc: &RpcClient) -> Self {
+        let (done, signals) = mpsc::channel();
+        let (resume, resumed) = mpsc::channel();
+        let (parked, resumed) = (done.clone(), Mutex::new(resumed));
+        rpc.park_with(Arc::new(move |msg_id, timeout| {
+            let resumed = resumed.lock().expect("lockstep poisoned");
+            parked.send(Signal::Waiting { msg_id, timeout }).is_ok()
+                && resumed.recv().unwrap_or(false)
+        }));
+
+        Self {
+            signals,
+            done,
+            resume,
+            step: None,
+            waiting: None,
+        }
+    }
+}
+
+impl<N, P, IP> SimNode<N, P, IP>
+where
+    N: Send + 'static,
+    P: Send + 'static,
+    IP: Send + 'static,
+{
+    fn waiting(&self) -> Option<usize> {
+        self.lockstep.as_ref().and_then(|lockstep| lockstep.waiting)
+    }
+
+    // Runs `work` to completion, unless it parks on a reply
+    fn start<S>(&mut self, work: Work<P, IP>) -> Stepped
+    where
+        S: 'static,
+        N: Node<S, P, IP>,
+    {
+        let (mut node, mut output) = self.node.take().expect("node is idle");
+        let clock = self.clock.clone();
+        let tracer = self.tracer.clone();
+        let Some(lockstep) = &mut self.lockstep else {
+            let result = perform(&mut node, &mut output, clock, tracer, work);
+            self.node = Some((node, output));
+            return Stepped::Done(result);
+        };
+
+        let done = lockstep.done.clone();
+        lockstep.step = Some(thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                perform(&mut node, &mut output, clock, tracer, work)
+            }));
+            let _ = done.send(Signal::Done);
+            match result {
+                Ok(result) => (node, output, result),
+                Err(panic) => panic::resume_unwind(panic),
+            }
+        }));
+        self.next_signal()
+    }
+
+    // Hands control back to the parked step
+    fn resume(&mut self, arrived: bool) -> Stepped {
+        let lockstep = self
+            .lockstep
+            .as_mut()
+            .expect("parked steps run in lockstep");
+        lockstep.waiting = None;
+        let _ = lockstep.resume.send(arrived);
+        self.next_signal()
+    }
+
+    fn next_signal(&mut self) -> Stepped {
+        let lockstep = self
+            .lockstep
+            .as_mut()
+            .expect("steps on threads run in lockstep");
+        match lockstep.signals.recv().expect("lockstep holds a sender") {
+            Signal::Waiting { msg_id, timeout } => {
+                lockstep.waiting = Some(msg_id);
+                Stepped::Waiting { msg_id, timeout }
+            }
+            Signal::Done => {
+                let step = lockstep.step.take().expect("a step is running");
+                let (node, output, result) = step
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic));
+                self.node = Some((node, output));
+                Stepped::Done(result)
+            }
+        }
+    }
+}
+
+fn perform<S, N, P, IP>(
+    node: &mut N,
+    output: &mut Output,
+    clock: Option<NodeClock>,
+    tracer: NodeTracer,
+    work: Work<P, IP>,
+) -> anyhow::Result<()>
+where
+    N: Node<S, P, IP>,
+{
+    let _clock = clock::enter(clock);
+    let _tracer = trace::enter(Some(tracer));
+    match work {
+        Work::Event(event) => step_node(node, event, output),
+        Work::Unknown(raw) => node
+            .on_unknown(raw, output)
+            .context("Node on_unknown failed"),
+        Work::Shutdown => node.on_shutdown(output).context("node shutdown failed"),
+    }?;
+    output.flush().context("flush node output")
+}
+
+enum Scheduled<InjectedPayload> {
+    // With the message's index in the trace, when recording one
+    Deliver(RawMessage, Option<usize>),
+    Inject {
+        node: String,
+        payload: InjectedPayload,
+        every: Option<Duration>,
+    },
+    // Gives up on a reply a step is parked on
+    RpcTimeout {
+        node: String,
+        msg_id: usize,
+    },
+}
+
+// Ordered by delivery time, ties broken by scheduling order
+struct Entry<InjectedPayload> {
+    at: Duration,
+    seq: u64,
+    scheduled: Scheduled<InjectedPayload>,
+}
+
+impl<IP> PartialEq for Entry<IP> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl<IP> Eq for Entry<IP> {}
+
+impl<IP> PartialOrd for Entry<IP> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<IP> Ord for Entry<IP> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+// Runs a cluster of nodes in one process on a virtual clock, routing their
+// messages through a simulated network whose latencies come from a seeded RNG,
+// so the same seed always replays the same run, faults included.
+//
+// Nodes are driven exactly like under main_loop, with one caveat: events a node
+// injects through its sender (eg: `Timers`) run on wall-clock threads, so the
+// sender handed to `from_init` is disconnected. Drive them in virtual time with
+// `inject` and `inject_every` instead.
+//
+// Nodes returning their client from `Node::rpc` may block on replies: their
+// steps run on a thread of their own, and one waiting on a reply hands control
+// back to the simulator until the reply arrives or times out in virtual time.
+// Messages reaching the node meanwhile wait for the step to finish, as they
+// would in main_loop's queue. seq-kv, lin-kv and lww-kv are served by a
+// `KvStore` each, and a `ParallelNode` runs wrapped in `Serial`.
+pub struct Simulator<S, N, Payload, InjectedPayload = ()> {
+    rng: StdRng,
+    now: Duration,
+    seq: u64,
+    latency: Latency,
+    faults: Faults,
+    partition: Option<Partition>,
+    stats: NetworkStats,
+    nodes: BTreeMap<String, SimNode<N, Payload, InjectedPayload>>,
+    services: HashMap<&'static str, KvStore>,
+    queue: BinaryHeap<Reverse<Entry<InjectedPayload>>>,
+    clients: HashMap<String, Vec<RawMessage>>,
+    next_client_msg_id: usize,
+    trace: Option<Trace>,
+    _state: PhantomData<fn() -> S>,
+}
+
+impl<S, N, P, IP> Simulator<S, N, P, IP>
+where
+    S: Clone + 'static,
+    N: Node<S, P, IP> + Send + 'static,
+    P: DeserializeOwned + Send + 'static,
+    IP: Clone + Send + 'static,
+{
+    // Initializes one node per id from the same initial state, as Maelstrom's init would
+    pub fn new(seed: u64, node_ids: &[&str], state: S) -> anyhow::Result<Self> {
+        let node_ids: Vec<String> = node_ids.iter().map(|id| id.to_string()).collect();
+        let mut nodes = BTreeMap::new();
+
+        for node_id in &node_ids {
+            let (tx, _) = std::sync::mpsc::channel();
+            let init = Init {
+                node_id: node_id.clone(),
+                node_ids: node_ids.clone(),
+            };
+            let node = N::from_init(state.clone(), init, tx)
+                .with_context(|| format!("initialize node {}", node_id))?;
+            let capture = Capture::default();
+            let metrics = NodeMetrics::default();
+            let output = Output::new(capture.clone())
+                .with_codec(CODEC)
+                .with_metrics(metrics.clone());
+            let rpc = node.rpc();
+            if let Some(rpc) = &rpc {
+                metrics.watch_rpc(rpc.clone());
+            }
+
+            nodes.insert(
+                node_id.clone(),
+                SimNode {
+                    clock: clock::NodeClock::new(node_id, node.clocks()),
+                    node: Some((node, output)),
+                    metrics,
+                    tracer: NodeTracer::new(node_id),
+                    lockstep: rpc.as_ref().map(Lockstep::new),
+                    rpc,
+                    capture,
+                    backlog: VecDeque::new(),
+                },
+            );
+        }
+
+        Ok(Self {
+            rng: StdRng::seed_from_u64(seed),
+            now: Duration::ZERO,
+            seq: 0,
+            latency: Latency::Uniform(Duration::from_millis(1), Duration::from_millis(10)),
+            faults: Faults::none(),
+            partition: None,
+            stats: NetworkStats::default(),
+            nodes,
+            services: KvService::ALL
+                .into_iter()
+                .map(|service| (service.node_id(), KvStore::new()))
+                .collect(),
+            queue: BinaryHeap::new(),
+            clients: HashMap::new(),
+            next_client_msg_id: 1,
+            trace: None,
+            _state: PhantomData,
+        })
+    }
+
+    // Every message takes a uniformly random time within `min..=max` to arrive
+    pub fn with_latency(mut self, min: Duration, max: Duration) -> Self {
+        self.latency = Latency::Uniform(min, max);
+        self
+    }
+
+    // Records every message sent from now on, for `trace`
+    pub fn with_trace(mut self) -> Self {
+        self.trace = Some(Trace::new());
+        self
+    }
+
+    // Messages sent so far, if recording, eg: to draw with `Trace::to_svg`
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    pub fn set_latency(&mut self, latency: Latency) {
+        self.latency = latency;
+    }
+
+    // Applies to messages sent from now on
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.faults = faults;
+    }
+
+    // Cuts the cluster into `groups`, dropping node-to-node messages across them,
+    // including the ones already in flight. Unlisted nodes are isolated.
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        self.partition = Some(Partition::new(groups));
+    }
+
+    pub fn isolate(&mut self, node: &str) {
+        let others: Vec<&str> = self
+            .nodes
+            .keys()
+            .map(String::as_str)
+            .filter(|&n| n != node)
+            .collect();
+        self.partition = Some(Partition::new(&[&others]));
+    }
+
+    pub fn heal(&mut self) {
+        self.partition = None;
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = &str> {
+        self.nodes.keys().map(String::as_str)
+    }
+
+    // None while the node is in the middle of a step waiting on a reply
+    pub fn node(&self, id: &str) -> Option<&N> {
+        let (node, _) = self.nodes.get(id)?.node.as_ref()?;
+        Some(node)
+    }
+
+    // What node `id` would answer a `metrics` request with
+    pub fn metrics(&self, id: &str) -> Option<Value> {
+        Some(self.nodes.get(id)?.metrics.snapshot())
+    }
+
+    // Sends a request from `client` (any id that isn't a node) and returns its msg_id
+    pub fn request<Q>(&mut self, client: &str, node: &str, payload: Q) -> anyhow::Result<usize>
+    where
+        Q: Serialize,
+    {
+        let msg_id = self.next_client_msg_id;
+        self.next_client_msg_id += 1;
+
+        let msg = Message {
+            src: client.to_string(),
+            dst: node.to_string(),
+            body: Body {
+                id: Some(msg_id),
+                in_reply_to: None,
+                clock: Default::default(),
+                payload: serde_json::to_value(payload).context("serialize client request")?,
+            },
+        };
+        self.transmit(msg);
+
+        Ok(msg_id)
+    }
+
+    pub fn inject(&mut self, node: &str, payload: IP) {
+        self.schedule(
+            self.now,
+            Scheduled::Inject {
+                node: node.to_string(),
+                payload,
+                every: None,
+            },
+        );
+    }
+
+    // Virtual-time replacement for `Timers::periodic`
+    pub fn inject_every(&mut self, node: &str, every: Duration, payload: IP) {
+        self.schedule(
+            self.now + every,
+            Scheduled::Inject {
+                node: node.to_string(),
+                payload,
+                every: Some(every),
+            },
+        );
+    }
+
+    // Processes everything scheduled up to `now + duration`
+    pub fn run_for(&mut self, duration: Duration) -> anyhow::Result<()> {
+        let until = self.now + duration;
+        while let Some(Reverse(entry)) = self.queue.peek() {
+            if entry.at > until {
+                break;
+            }
+            let Reverse(entry) = self.queue.pop().expect("peeked entry");
+            self.now = entry.at;
+            self.process(entry.scheduled)?;
+        }
+        self.now = until;
+        Ok(())
+    }
+
+    // Runs until only periodic injections are left, or `limit` of virtual time has passed
+    pub fn run_until_quiet(&mut self, limit: Duration) -> anyhow::Result<()> {
+        let until = self.now + limit;
+        while let Some(Reverse(entry)) = self.queue.pop() {
+            if entry.at > until {
+                self.queue.push(Reverse(entry));
+                break;
+            }
+            self.now = entry.at;
+            self.process(entry.scheduled)?;
+
+            let quiet = self
+                .queue
+                .iter()
+                .all(|Reverse(entry)| match &entry.scheduled {
+                    Scheduled::Inject { every, .. } => every.is_some(),
+                    Scheduled::RpcTimeout { node, msg_id } => {
+                        self.nodes[node].waiting() != Some(*msg_id)
+                    }
+                    Scheduled::Deliver(..) => false,
+                });
+            if quiet {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Takes all messages delivered to `client` so far
+    pub fn take_replies(&mut self, client: &str) -> Vec<RawMessage> {
+        self.clients.remove(client).unwrap_or_default()
+    }
+
+    // Takes the reply to a given request, if it has arrived
+    pub fn take_reply<R>(
+        &mut self,
+        client: &str,
+        msg_id: usize,
+    ) -> anyhow::Result<Option<Message<R>>>
+    where
+        R: DeserializeOwned,
+    {
+        let Some(inbox) = self.clients.get_mut(client) else {
+            return Ok(None);
+        };
+        let Some(at) = inbox
+            .iter()
+            .position(|msg| msg.body.in_reply_to == Some(msg_id))
+        else {
+            return Ok(None);
+        };
+        inbox.remove(at).decode().map(Some)
+    }
+
+    // Drives a workload against the cluster in virtual time until its final
+    // requests are answered, and returns the recorded history
+    pub fn run_workload(&mut self, mut driver: Driver) -> anyhow::Result<History> {
+        let start = self.now;
+        let tick = Duration::from_millis(1);
+        let clients: Vec<String> = driver.client_ids().map(String::from).collect();
+
+        while !driver.is_done() {
+            for msg in driver.poll(self.now - start) {
+                self.transmit(msg);
+            }
+            self.run_for(tick)?;
+            for client in &clients {
+                for reply in self.take_replies(client) {
+                    driver.receive(self.now - start, &reply);
+                }
+            }
+        }
+
+        Ok(driver.into_history())
+    }
+
+    // Delivers `Event::EOF` and runs `on_shutdown` on every node, after the
+    // steps waiting on replies got them or timed out
+    pub fn shutdown(&mut self) -> anyhow::Result<()> {
+        let ids: Vec<String> = self.nodes.keys().cloned().collect();
+        for id in &ids {
+            self.run(id, Work::Event(Event::EOF))?;
+            self.run(id, Work::Shutdown)?;
+        }
+
+        while self.nodes.values().any(|node| node.waiting().is_some()) {
+            let Some(Reverse(entry)) = self.queue.pop() else {
+                break;
+            };
+            self.now = entry.at;
+            // The nodes' timers are stopped by now
+            if !matches!(entry.scheduled, Scheduled::Inject { .. }) {
+                self.process(entry.scheduled)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn process(&mut self, scheduled: Scheduled<IP>) -> anyhow::Result<()> {
+        match scheduled {
+            Scheduled::Deliver(msg, hop) => {
+                if self.between_nodes(&msg) {
+                    let cut = matches!(&self.partition, Some(p) if !p.allows(&msg.src, &msg.dst));
+                    if cut {
+                        self.stats.partitioned += 1;
+                        return Ok(());
+                    }
+                    self.stats.delivered += 1;
+                }
+                if let (Some(trace), Some(hop)) = (&mut self.trace, hop) {
+                    trace.received(hop, self.now);
+                }
+
+                if let Some(service) = self.services.get_mut(msg.dst.as_str()) {
+                    let reply = service.serve(msg);
+                    self.transmit(reply);
+                    return Ok(());
+                }
+                let Some(node) = self.nodes.get(&msg.dst) else {
+                    self.clients.entry(msg.dst.clone()).or_default().push(msg);
+                    return Ok(());
+                };
+                let rpc = node.rpc.clone();
+                let (metrics, tracer) = (node.metrics.clone(), node.tracer.clone());
+                let dst = msg.dst.clone();
+
+                for msg in unbatch(msg) {
+                    let kind = msg.body.payload.get("type").and_then(Value::as_str);
+                    metrics.received(&msg, kind);
+                    tracer.received(&msg, kind);
+                    // Replies to a node's own requests resolve its pending calls,
+                    // and get the step parked on one going again
+                    let msg = match &rpc {
+                        Some(rpc) => {
+                            let in_reply_to = msg.body.in_reply_to;
+                            match rpc.resolve(msg) {
+                                Some(msg) => msg,
+                                None => {
+                                    if in_reply_to.is_some()
+                                        && self.nodes[&dst].waiting() == in_reply_to
+                                    {
+                                        self.drive(&dst, Some(true))?;
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+                        None => msg,
+                    };
+
+                    let work = match msg.try_decode() {
+                        Ok(msg) => Work::Event(Event::Message(msg)),
+                        Err(raw) => Work::Unknown(
+                            serde_json::to_value(&*raw).context("serialize unknown message")?,
+                        ),
+                    };
+                    self.run(&dst, work)?;
+                }
+                Ok(())
+            }
+            Scheduled::Inject {
+                node,
+                payload,
+                every,
+            } => {
+                if let Some(every) = every {
+                    self.schedule(
+                        self.now + every,
+                        Scheduled::Inject {
+                            node: node.clone(),
+                            payload: payload.clone(),
+                            every: Some(every),
+                        },
+                    );
+                }
+                self.run(&node, Work::Event(Event::Injected(payload)))
+            }
+            Scheduled::RpcTimeout { node, msg_id } => {
+                if self.nodes[&node].waiting() == Some(msg_id) {
+                    self.drive(&node, Some(false))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Node `id` gets to `work` once it's done with everything before it
+    fn run(&mut self, id: &str, work: Work<P, IP>) -> anyhow::Result<()> {
+        self.nodes
+            .get_mut(id)
+            .with_context(|| format!("no node {} in the simulation", id))?
+            .backlog
+            .push_back(work);
+        self.drive(id, None)
+    }
+
+    // Works through the backlog of node `id`, first resuming its parked step
+    // with whether the reply arrived, until it's empty or a step parks
+    fn drive(&mut self, id: &str, mut resume: Option<bool>) -> anyhow::Result<()> {
+        loop {
+            let node = self.nodes.get_mut(id).expect("driving a node");
+            let stepped = match resume.take() {
+                Some(arrived) => node.resume(arrived),
+                None if node.waiting().is_some() => return Ok(()),
+                None => match node.backlog.pop_front() {
+                    Some(work) => node.start::<S>(work),
+                    None => return Ok(()),
+                },
+            };
+
+            // Including the request a parked step waits on
+            self.flush(id)?;
+            match stepped {
+                Stepped::Done(result) => result.with_context(|| format!("step node {}", id))?,
+                Stepped::Waiting { msg_id, timeout } => {
+                    let node = id.to_string();
+                    self.schedule(self.now + timeout, Scheduled::RpcTimeout { node, msg_id });
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Puts everything the node wrote on the wire
+    fn flush(&mut self, id: &str) -> anyhow::Result<()> {
+        let written =
+            std::mem::take(&mut *self.nodes[id].capture.0.lock().expect("capture poisoned"));
+
+        let mut written = written.as_slice();
+        while let Some(frame) = CODEC.read_frame(&mut written)? {
+            let msg: RawMessage = CODEC
+                .decode_frame(&frame)
+                .with_context(|| format!("node {} wrote an invalid message", id))?;
+            self.transmit(msg);
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self, msg: RawMessage) {
+        if !self.between_nodes(&msg) {
+            let latency = self.latency.sample(&mut self.rng);
+            let hop = self.record(&msg);
+            self.schedule(self.now + latency, Scheduled::Deliver(msg, hop));
+            return;
+        }
+
+        self.stats.sent += 1;
+        let copies = self.faults.copies(&mut self.rng);
+        match copies {
+            0 => {
+                self.stats.dropped += 1;
+                self.record(&msg);
+            }
+            2 => self.stats.duplicated += 1,
+            _ => {}
+        }
+
+        for _ in 0..copies {
+            let latency =
+                self.latency.sample(&mut self.rng) + self.faults.extra_delay(&mut self.rng);
+            let hop = self.record(&msg);
+            self.schedule(self.now + latency, Scheduled::Deliver(msg.clone(), hop));
+        }
+    }
+
+    // Every copy of a message is a hop of its own
+    fn record(&mut self, msg: &RawMessage) -> Option<usize> {
+        let trace = self.trace.as_mut()?;
+        let body = serde_json::to_value(&msg.body).unwrap_or_default();
+        Some(trace.sent(msg.src.clone(), msg.dst.clone(), body, self.now))
+    }
+
+    fn between_nodes(&self, msg: &RawMessage) -> bool {
+        self.nodes.contains_key(&msg.src) && self.nodes.contains_key(&msg.dst)
+    }
+
+    fn schedule(&mut self, at: Duration, scheduled: Scheduled<IP>) {
+        self.seq += 1;
+        self.queue.push(Reverse(Entry {
+            at,
+            seq: self.seq,
+            scheduled,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clocks, ErrorCode, KvClient, MaelstromError, Stamp, VectorClock};
+
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Payload {
+        Add { delta: u64 },
+        AddOk { value: u64 },
+        Ask { peer: String },
+        AskOk,
+        Ping,
+        PingOk,
+    }
+
+    // Keeps a counter on lin-kv and pings peers, blocking on every reply
+    struct Blocking {
+        rpc: RpcClient,
+        kv: KvClient,
+    }
+
+    impl Node<(), Payload> for Blocking {
+        fn from_init(
+            _state: (),
+            init: Init,
+            _inject: mpsc::Sender<Event<Payload>>,
+        ) -> anyhow::Result<Self> {
+            let rpc = RpcClient::new(init.node_id);
+            let kv = KvClient::new(rpc.clone(), KvService::Lin);
+            Ok(Self { rpc, kv })
+        }
+
+        fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            let mut reply = input.into_reply(None);
+            reply.body.payload = match reply.body.payload {
+                Payload::Add { delta } => {
+                    let value = self
+                        .kv
+                        .update("counter", 0, &mut *output, |value: u64| value + delta)?;
+                    Payload::AddOk { value }
+                }
+                Payload::Ask { ref peer } => {
+                    self.rpc
+                        .call(peer.clone(), Payload::Ping, &mut *output)?
+                        .wait::<Payload>(Duration::from_secs(1))?;
+                    Payload::AskOk
+                }
+                Payload::Ping => Payload::PingOk,
+                _ => return Ok(()),
+            };
+            reply.send(output)
+        }
+
+        fn rpc(&self) -> Option<RpcClient> {
+            Some(self.rpc.clone())
+        }
+    }
+
+    fn simulator(nodes: &[&str]) -> Simulator<(), Blocking, Payload> {
+        Simulator::new(1, nodes, ()).expect("nodes start")
+    }
+
+    // Pings the peers it's asked to, noting the clocks it steps pings at
+    struct Clocked {
+        pings: Vec<(Stamp, Option<u64>, Option<VectorClock>)>,
+    }
+
+    impl Node<(), Payload> for Clocked {
+        fn from_init(
+            _state: (),
+            _init: Init,
+            _inject: mpsc::Sender<Event<Payload>>,
+        ) -> anyhow::Result<Self> {
+            Ok(Self { pings: Vec::new() })
+        }
+
+        fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            match &input.body.payload {
+                Payload::Ask { peer } => Message {
+                    src: input.dst.clone(),
+                    dst: peer.clone(),
+                    body: Body {
+                        id: None,
+                        in_reply_to: None,
+                        clock: Default::default(),
+                        payload: Payload::Ping,
+                    },
+                }
+                .send(output),
+                Payload::Ping => {
+                    let stepped_at = (clock::lamport(), clock::vector());
+                    self.pings
+                        .push((input.body.clock.clone(), stepped_at.0, stepped_at.1));
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn clocks(&self) -> Option<Clocks> {
+            Some(Clocks::default())
+        }
+    }
+
+    #[test]
+    fn each_node_ticks_its_own_clocks_as_it_steps() -> anyhow::Result<()> {
+        let mut sim = Simulator::<(), Clocked, Payload>::new(1, &["n1", "n2"], ())?;
+        sim.request("c1", "n1", json!({"type": "ask", "peer": "n2"}))?;
+        sim.run_until_quiet(Duration::from_secs(10))?;
+
+        // n1 stepped the ask (1) and sent the ping (2), n2 stepped the ping
+        let (stamp, lamport, vector) = sim.node("n2").expect("n2 is idle").pings[0].clone();
+        assert_eq!(stamp.lamport, Some(2));
+        assert_eq!(lamport, Some(3));
+        let vector = vector.expect("n2 keeps a vector clock");
+        assert_eq!((vector.get("n1"), vector.get("n2")), (2, 1));
+        assert!(stamp.vclock.expect("the ping is stamped") < vector);
+        sim.shutdown()
+    }
+
+    #[test]
+    fn steps_block_on_kv_requests() -> anyhow::Result<()> {
+        let mut sim = simulator(&["n1", "n2", "n3"]);
+        for (delta, node) in [(1, "n1"), (2, "n2"), (3, "n3")] {
+            sim.request("c1", node, json!({"type": "add", "delta": delta}))?;
+        }
+        sim.run_until_quiet(Duration::from_secs(10))?;
+        assert_eq!(sim.take_replies("c1").len(), 3);
+
+        let msg_id = sim.request("c1", "n2", json!({"type": "add", "delta": 0}))?;
+        sim.run_until_quiet(Duration::from_secs(10))?;
+        let reply = sim.take_reply::<Payload>("c1", msg_id)?.expect("add_ok");
+        assert!(matches!(reply.body.payload, Payload::AddOk { value: 6 }));
+        sim.shutdown()
+    }
+
+    #[test]
+    fn parked_steps_resume_on_replies_from_peers() -> anyhow::Result<()> {
+        let mut sim = simulator(&["n1", "n2"]);
+        let msg_id = sim.request("c1", "n1", json!({"type": "ask", "peer": "n2"}))?;
+        sim.run_until_quiet(Duration::from_secs(10))?;
+
+        let reply = sim.take_reply::<Payload>("c1", msg_id)?.expect("ask_ok");
+        assert!(matches!(reply.body.payload, Payload::AskOk));
+        assert!(sim.node("n1").is_some());
+        sim.shutdown()
+    }
+
+    #[test]
+    fn rpc_timeouts_pass_in_virtual_time() -> anyhow::Result<()> {
+        let mut sim = simulator(&["n1"]);
+        // Nobody answers c9, and the ping queues up behind the step waiting on it
+        let ask = sim.request("c1", "n1", json!({"type": "ask", "peer": "c9"}))?;
+        let ping = sim.request("c1", "n1", json!({"type": "ping"}))?;
+
+        sim.run_for(Duration::from_millis(500))?;
+        assert!(sim.take_replies("c1").is_empty());
+        assert!(sim.node("n1").is_none());
+
+        sim.run_for(Duration::from_secs(1))?;
+        let error = sim.take_reply::<MaelstromError>("c1", ask)?.expect("error");
+        assert_eq!(error.body.payload.code, ErrorCode::Timeout);
+        let pong = sim.take_reply::<Payload>("c1", ping)?.expect("ping_ok");
+        assert!(matches!(pong.body.payload, Payload::PingOk));
+        assert_eq!(sim.take_replies("c9").len(), 1);
+        sim.shutdown()
+    }
+
+    #[test]
+    fn traces_record_every_hop_in_virtual_time() -> anyhow::Result<()> {
+        let mut sim = Simulator::<(), Blocking, Payload>::new(1, &["n1", "n2"], ())?
+            .with_latency(Duration::from_millis(5), Duration::from_millis(5))
+            .with_trace();
+        sim.request("c1", "n1", json!({"type": "ask", "peer": "n2"}))?;
+        sim.run_until_quiet(Duration::from_secs(10))?;
+
+        let trace = sim.trace().expect("recording");
+        let hops: Vec<_> = trace
+            .hops()
+            .iter()
+            .map(|hop| {
+                (
+                    hop.src.as_str(),
+                    hop.dest.as_str(),
+                    hop.body["type"].as_str().unwrap_or_default(),
+                    hop.sent.as_millis(),
+                    hop.received.map(|at| at.as_millis()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            hops,
+            [
+                ("c1", "n1", "ask", 0, Some(5)),
+                ("n1", "n2", "ping", 5, Some(10)),
+                ("n2", "n1", "ping_ok", 10, Some(15)),
+                ("n1", "c1", "ask_ok", 15, Some(20)),
+            ]
+        );
+        sim.shutdown()
+    }
+
+    #[test]
+    fn each_node_counts_its_own_messages() -> anyhow::Result<()> {
+        let mut sim = Simulator::<(), Clocked, Payload>::new(1, &["n1", "n2"], ())?;
+        sim.request("c1", "n1", json!({"type": "ask", "peer": "n2"}))?;
+        sim.run_until_quiet(Duration::from_secs(10))?;
+
+        let n1 = sim.metrics("n1").expect("n1 is simulated");
+        assert_eq!(n1["received"], json!({"ask": 1}));
+        assert_eq!(n1["sent"], json!({"ping": 1}));
+        assert_eq!(n1["client_requests"], 1);
+        // Timed by n1's own tracer, which knew the ask was one
+        assert_eq!(n1["latency"]["ask"]["count"], 1);
+        let n2 = sim.metrics("n2").expect("n2 is simulated");
+        assert_eq!(n2["received"], json!({"ping": 1}));
+        assert_eq!(n2["sent"], json!({}));
+        assert_eq!(sim.metrics("c1"), None);
+        sim.shutdown()
+    }
+
+    // Has n1 ping n2 once per ask, and returns the lamport stamps of the pings
+    // n2 got, in the order they arrived
+    fn pings(sim: &mut Simulator<(), Clocked, Payload>, asks: usize) -> anyhow::Result<Vec<u64>> {
+        for _ in 0..asks {
+            sim.request("c1", "n1", json!({"type": "ask", "peer": "n2"}))?;
+        }
+        sim.run_until_quiet(Duration::from_secs(10))?;
+        let n2 = sim.node("n2").expect("n2 is idle");
+        Ok(n2
+            .pings
+            .iter()
+            .filter_map(|(stamp, ..)| stamp.lamport)
+            .collect())
+    }
+
+    #[test]
+    fn dropped_messages_never_arrive() -> anyhow::Result<()> {
+        let mut sim = Simulator::<(), Clocked, Payload>::new(1, &["n1", "n2"], ())?;
+        sim.set_faults(Faults::none().drop(1.0));
+        assert!(pings(&mut sim, 5)?.is_empty());
+
+        let stats = sim.stats();
+        assert_eq!((stats.sent, stats.dropped, stats.delivered), (5, 5, 0));
+        sim.shutdown()
+    }
+
+    #[test]
+    fn duplicated_messages_arrive_twice() -> anyhow::Result<()> {
+        let mut sim = Simulator::<(), Clocked, Payload>::new(1, &["n1", "n2"], ())?;
+        sim.set_faults(Faults::none().duplicate(1.0));
+        let mut pings = pings(&mut sim, 3)?;
+        assert_eq!(pings.len(), 6);
+        pings.sort();
+        pings.dedup();
+        assert_eq!(pings.len(), 3);
+
+        let stats = sim.stats();
+        assert_eq!((stats.sent, stats.duplicated, stats.delivered), (3, 3, 6));
+        sim.shutdown()
+    }
+
+    #[test]
+    fn reordered_messages_overtake_each_other() -> anyhow::Result<()> {
+        let mut sim = Simulator::<(), Clocked, Payload>::new(1, &["n1", "n2"], ())?;
+        sim.set_latency(Latency::Fixed(Duration::from_millis(5)));
+        let in_order = pings(&mut sim, 20)?;
+        assert!(in_order.is_sorted());
+        sim.shutdown()?;
+
+        let mut sim = Simulator::<(), Clocked, Payload>::new(1, &["n1", "n2"], ())?;
+        sim.set_latency(Latency::Fixed(Duration::from_millis(5)));
+        sim.set_faults(Faults::none().reorder(0.5, Duration::from_millis(50)));
+        let reordered = pings(&mut sim, 20)?;
+        assert!(!reordered.is_sorted());
+
+        let mut sorted = reordered.clone();
+        sorted.sort();
+        assert_eq!(sorted, in_order);
+        sim.shutdown()
+    }
+
+    #[test]
+    fn the_same_seed_replays_the_same_run() -> anyhow::Result<()> {
+        let run = |seed| -> anyhow::Result<_> {
+            let mut sim = Simulator::<(), Clocked, Payload>::new(seed, &["n1", "n2"], ())?
+                .with_latency(Duration::from_millis(1), Duration::from_millis(20))
+                .with_trace();
+            sim.set_faults(
+                Faults::none()
+                    .drop(0.2)
+                    .duplicate(0.2)
+                    .reorder(0.3, Duration::from_millis(30)),
+            );
+            let pings = pings(&mut sim, 50)?;
+            let run = (pings, sim.stats(), sim.trace().cloned(), sim.now());
+            sim.shutdown()?;
+            Ok(run)
+        };
+
+        let (pings, stats, trace, now) = run(3)?;
+        assert!(stats.dropped > 0 && stats.duplicated > 0);
+        assert_eq!(run(3)?, (pings.clone(), stats, trace, now));
+        assert_ne!(run(4)?.0, pings);
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use crate::Event;
+
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+// Dropping the handle wakes the timer thread up and stops it
+struct TimerHandle {
+    _cancel: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+// Named timers that feed `Event::Injected` values back into the node
+// Each timer runs on its own thread and stops when cancelled, when the node
+// calls `shutdown` (typically on `Event::EOF`) or when the event loop is gone.
+// They keep wall-clock time with unseeded jitter, so under a `Simulator` they
+// never fire: drive such events with `Simulator::inject_every` instead.
+pub struct Timers<Payload, InjectedPayload = ()> {
+    inject: Option<Sender<Event<Payload, InjectedPayload>>>,
+    timers: HashMap<String, TimerHandle>,
+}
+
+impl<Payload, InjectedPayload> Timers<Payload, InjectedPayload>
+where
+    Payload: Send + 'static,
+    InjectedPayload: Send + 'static,
+{
+    pub fn new(inject: Sender<Event<Payload, InjectedPayload>>) -> Self {
+        Self {
+            inject: Some(inject),
+            timers: HashMap::new(),
+        }
+    }
+
+    // Injects `payload` every `period`, plus a random delay of up to `jitter` so
+    // nodes started together don't fire in lockstep. Replaces any timer of the same name.
+    pub fn periodic(
+        &mut self,
+        name: impl Into<String>,
+        period: Duration,
+        jitter: Duration,
+        payload: InjectedPayload,
+    ) where
+        InjectedPayload: Clone,
+    {
+        self.spawn(name.into(), period, jitter, true, payload);
+    }
+
+    // Injects `payload` once after `delay`. Replaces any timer of the same name.
+    pub fn once(&mut self, name: impl Into<String>, delay: Duration, payload: InjectedPayload)
+    where
+        InjectedPayload: Clone,
+    {
+        self.spawn(name.into(), delay, Duration::ZERO, false, payload);
+    }
+
+    // Returns whether a timer with that name was running, ie: not done firing
+    pub fn cancel(&mut self, name: &str) -> bool {
+        self.timers
+            .remove(name)
+            .is_some_and(|timer| !timer.thread.is_finished())
+    }
+
+    // Stops every timer and releases our event sender, so main_loop can finish
+    pub fn shutdown(&mut self) {
+        self.timers.clear();
+        self.inject = None;
+    }
+
+    fn spawn(
+        &mut self,
+        name: String,
+        delay: Duration,
+        jitter: Duration,
+        repeat: bool,
+        payload: InjectedPayload,
+    ) where
+        InjectedPayload: Clone,
+    {
+        let Some(inject) = self.inject.clone() else {
+            return;
+        };
+        let (cancel, cancelled) = mpsc::channel::<()>();
+
+        let thread = std::thread::spawn(move || {
+            let mut rng = rand::thread_rng();
+            loop {
+                let jitter = if jitter.is_zero() {
+                    Duration::ZERO
+                } else {
+                    rng.gen_range(Duration::ZERO..=jitter)
+                };
+
+                match cancelled.recv_timeout(delay + jitter) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // Cancelled, or the Timers were dropped
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+                }
+
+                if inject.send(Event::Injected(payload.clone())).is_err() || !repeat {
+                    break;
+                }
+            }
+        });
+
+        self.timers.insert(
+            name,
+            TimerHandle {
+                _cancel: cancel,
+                thread,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{sync::mpsc::Receiver, thread, time::Instant};
+
+    const TICK: Duration = Duration::from_millis(10);
+    // Long enough for a timer that should have fired to have done so
+    const WAIT: Duration = Duration::from_secs(1);
+
+    fn timers() -> (Timers<(), u32>, Receiver<Event<(), u32>>) {
+        let (tx, rx) = mpsc::channel();
+        (Timers::new(tx), rx)
+    }
+
+    fn injected(rx: &Receiver<Event<(), u32>>, timeout: Duration) -> Option<u32> {
+        match rx.recv_timeout(timeout) {
+            Ok(Event::Injected(payload)) => Some(payload),
+            Ok(_) => panic!("only injected events expected"),
+            Err(_) => None,
+        }
+    }
+
+    #[test]
+    fn once_fires_a_single_time() {
+        let (mut timers, rx) = timers();
+        timers.once("t", TICK, 1);
+        assert_eq!(injected(&rx, WAIT), Some(1));
+        assert_eq!(injected(&rx, TICK * 10), None);
+        assert!(!timers.cancel("t"), "it is done firing");
+    }
+
+    #[test]
+    fn periodic_fires_until_cancelled() {
+        let (mut timers, rx) = timers();
+        timers.periodic("t", TICK, TICK, 1);
+        for _ in 0..3 {
+            assert_eq!(injected(&rx, WAIT), Some(1));
+        }
+
+        assert!(timers.cancel("t"));
+        assert!(!timers.cancel("t"));
+        // One may have been on its way as we cancelled
+        thread::sleep(TICK * 3);
+        while rx.try_recv().is_ok() {}
+        assert_eq!(injected(&rx, TICK * 10), None);
+    }
+
+    #[test]
+    fn a_timer_replaces_any_of_the_same_name() {
+        let (mut timers, rx) = timers();
+        timers.once("t", TICK * 5, 1);
+        timers.once("t", TICK, 2);
+        timers.periodic("other", TICK * 100, Duration::ZERO, 3);
+        assert_eq!(injected(&rx, WAIT), Some(2));
+        assert_eq!(injected(&rx, TICK * 15), None);
+    }
+
+    #[test]
+    fn shutdown_stops_every_timer_and_lets_go_of_the_sender() {
+        let (mut timers, rx) = timers();
+        timers.periodic("a", TICK, Duration::ZERO, 1);
+        timers.once("b", TICK * 100, 2);
+        timers.shutdown();
+        timers.once("c", TICK, 3);
+
+        // The event loop sees its input end, with at most a tick of `a` before
+        let deadline = Instant::now() + WAIT;
+        loop {
+            match rx.recv_timeout(WAIT) {
+                Ok(Event::Injected(1)) if Instant::now() < deadline => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                other => panic!("expected the sender to be gone, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use crate::Message;
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    io::Write,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Diagnostics of a node, always on stderr since stdout carries the protocol.
+//
+// NODE_LOG picks the level (error, warn, info, debug, trace; info by default),
+// NODE_LOG_FORMAT picks `human` (default) or `json` lines. Every message the
+// library receives or sends is logged at debug, with its body at trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "off" => Level::Off,
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => anyhow::bail!("unknown log level {}", s),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "human" => Format::Human,
+            "json" => Format::Json,
+            _ => anyhow::bail!("unknown log format {}", s),
+        })
+    }
+}
+
+// Requests we haven't seen the reply to, kept for the reply's latency and
+// the metrics of the handler taking them up
+const MAX_PENDING: usize = 100_000;
+const PENDING_TTL: Duration = Duration::from_secs(60);
+
+// (peer, msg_id, whether we sent it) -> (type, when)
+type Pending = HashMap<(String, usize, bool), (String, Instant)>;
+
+struct Config {
+    level: Level,
+    format: Format,
+}
+
+lazy_static::lazy_static! {
+    static ref CONFIG: Config = Config {
+        level: from_env("NODE_LOG", Level::Info),
+        format: from_env("NODE_LOG_FORMAT", Format::Human),
+    };
+}
+
+fn from_env<T: FromStr>(var: &str, default: T) -> T {
+    std::env::var(var)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// The log context of one node: its name, and the requests in flight to and
+// from it. Kept by whatever runs the node (a loop, or the simulator for each
+// of its nodes) and entered by the threads stepping it, like its clocks.
+#[derive(Clone)]
+pub(crate) struct NodeTracer(Arc<TracerState>);
+
+struct TracerState {
+    node: String,
+    pending: Mutex<Pending>,
+}
+
+impl NodeTracer {
+    pub(crate) fn new(node: &str) -> Self {
+        Self(Arc::new(TracerState {
+            node: node.to_string(),
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    // `kind` is the type of the message's payload
+    pub(crate) fn received<P: Serialize>(&self, msg: &Message<P>, kind: Option<&str>) {
+        self.message("recv", msg, kind, &msg.src, false);
+    }
+
+    pub(crate) fn sent<P: Serialize>(&self, msg: &Message<P>, kind: Option<&str>) {
+        self.message("send", msg, kind, &msg.dst, true);
+    }
+
+    // Type of a request received from `src` that hasn't been replied to yet
+    pub(crate) fn request_kind(&self, src: &str, msg_id: usize) -> Option<String> {
+        let pending = self.0.pending.lock().expect("tracer poisoned");
+        let (kind, _) = pending.get(&(src.to_string(), msg_id, false))?;
+        Some(kind.clone())
+    }
+
+    // How long ago the request `msg` replies to went the other way, if it
+    // does. Requests are noted down for their replies.
+    fn latency<P>(
+        &self,
+        msg: &Message<P>,
+        kind: Option<&str>,
+        peer: &str,
+        outbound: bool,
+    ) -> Option<Duration> {
+        let mut pending = self.0.pending.lock().expect("tracer poisoned");
+        let now = Instant::now();
+        match (msg.body.in_reply_to, msg.body.id) {
+            // A reply, to a request that went the other way
+            (Some(in_reply_to), _) => pending
+                .remove(&(peer.to_string(), in_reply_to, !outbound))
+                .map(|(_, at)| now.duration_since(at)),
+            (None, Some(id)) => {
+                if pending.len() >= MAX_PENDING {
+                    // Requests lost on the way never see a reply
+                    pending.retain(|_, (_, at)| now.duration_since(*at) < PENDING_TTL);
+                }
+                let kind = kind.unwrap_or("unknown").to_string();
+                pending.insert((peer.to_string(), id, outbound), (kind, now));
+                None
+            }
+            (None, None) => None,
+        }
+    }
+
+    fn message<P: Serialize>(
+        &self,
+        direction: &str,
+        msg: &Message<P>,
+        kind: Option<&str>,
+        peer: &str,
+        outbound: bool,
+    ) {
+        let latency = self.latency(msg, kind, peer, outbound);
+        if !enabled(Level::Debug) {
+            return;
+        }
+
+        let mut fields = Map::new();
+        fields.insert("src".into(), json!(msg.src));
+        fields.insert("dest".into(), json!(msg.dst));
+        fields.insert("type".into(), json!(kind));
+        if let Some(id) = msg.body.id {
+            fields.insert("msg_id".into(), json!(id));
+        }
+        if let Some(lamport) = msg.body.clock.lamport {
+            fields.insert("lamport".into(), json!(lamport));
+        }
+        if let Some(in_reply_to) = msg.body.in_reply_to {
+            fields.insert("in_reply_to".into(), json!(in_reply_to));
+        }
+
+        if let Some(latency) = latency {
+            let latency = latency.as_secs_f64() * 1000.0;
+            fields.insert(
+                "latency_ms".into(),
+                json!((latency * 1000.0).round() / 1000.0),
+            );
+        }
+
+        if enabled(Level::Trace) {
+            let body = serde_json::to_value(&msg.body.payload).unwrap_or_default();
+            fields.insert("body".into(), body);
+        }
+        write(Some(&self.0.node), Level::Debug, direction, fields);
+    }
+}
+
+thread_local! {
+    static ENTERED: RefCell<Option<NodeTracer>> = const { RefCell::new(None) };
+}
+
+tokio::task_local! {
+    // Async handlers move between threads
+    static TASK_TRACER: Option<NodeTracer>;
+}
+
+// Tracer of the node stepped on this thread until dropped, see `enter`
+pub(crate) struct Entered {
+    outer: Option<NodeTracer>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        ENTERED.with(|current| current.replace(self.outer.take()));
+    }
+}
+
+// Makes `tracer` the one lines logged and messages sent on this thread go to
+pub(crate) fn enter(tracer: Option<NodeTracer>) -> Entered {
+    Entered {
+        outer: ENTERED.with(|current| current.replace(tracer)),
+    }
+}
+
+// Same as `enter`, for the future of an async handler
+pub(crate) async fn scope<T>(tracer: Option<NodeTracer>, f: impl Future<Output = T>) -> T {
+    TASK_TRACER.scope(tracer, f).await
+}
+
+fn current() -> Option<NodeTracer> {
+    TASK_TRACER
+        .try_with(Clone::clone)
+        .unwrap_or_else(|_| ENTERED.with(|current| current.borrow().clone()))
+}
+
+pub fn enabled(level: Level) -> bool {
+    passes(level, CONFIG.level)
+}
+
+fn passes(level: Level, threshold: Level) -> bool {
+    level != Level::Off && level <= threshold
+}
+
+// Logs `message` with the fields of the `fields` object, naming the node
+// being stepped
+pub fn log(level: Level, message: impl Display, fields: Value) {
+    if !enabled(level) {
+        return;
+    }
+    let fields = match fields {
+        Value::Object(fields) => fields,
+        Value::Null => Map::new(),
+        other => Map::from_iter([("value".to_string(), other)]),
+    };
+    let tracer = current();
+    write(
+        tracer.as_ref().map(|t| t.0.node.as_str()),
+        level,
+        message,
+        fields,
+    );
+}
+
+fn write(node: Option<&str>, level: Level, message: impl Display, fields: Map<String, Value>) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let line = line(CONFIG.format, time, level, node, message, fields);
+    let mut stderr = std::io::stderr().lock();
+    let _ = writeln!(stderr, "{}", line);
+}
+
+fn line(
+    format: Format,
+    time: f64,
+    level: Level,
+    node: Option<&str>,
+    message: impl Display,
+    fields: Map<String, Value>,
+) -> String {
+    match format {
+        Format::Json => {
+            let mut line = Map::new();
+            line.insert("time".into(), json!(time));
+            line.insert("level".into(), json!(level.name()));
+            if let Some(node) = node {
+                line.insert("node".into(), json!(node));
+            }
+            line.insert("message".into(), json!(message.to_string()));
+            line.extend(fields);
+            Value::Object(line).to_string()
+        }
+        Format::Human => {
+            let mut line = format!(
+                "{:.3} {:>5} {} {}",
+                time,
+                level.name().to_ascii_uppercase(),
+                node.unwrap_or("-"),
+                message
+            );
+            for (key, value) in fields {
+                match value {
+                    Value::String(value) => line.push_str(&format!(" {}={}", key, value)),
+                    value => line.push_str(&format!(" {}={}", key, value)),
+                }
+            }
+            line
+        }
+    }
+}
+
+pub fn error(message: impl Display, fields: Value) {
+    log(Level::Error, message, fields)
+}
+
+pub fn warn(message: impl Display, fields: Value) {
+    log(Level::Warn, message, fields)
+}
+
+pub fn info(message: impl Display, fields: Value) {
+    log(Level::Info, message, fields)
+}
+
+pub fn debug(message: impl Display, fields: Value) {
+    log(Level::Debug, message, fields)
+}
+
+// Hooks for the node being stepped, if any
+pub(crate) fn sent<P: Serialize>(msg: &Message<P>, kind: Option<&str>) {
+    if let Some(tracer) = current() {
+        tracer.sent(msg, kind);
+    }
+}
+
+pub(crate) fn request_kind(src: &str, msg_id: usize) -> Option<String> {
+    current()?.request_kind(src, msg_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Body;
+
+    fn message(src: &str, dst: &str, id: Option<usize>, in_reply_to: Option<usize>) -> Message<()> {
+        Message {
+            src: src.to_string(),
+            dst: dst.to_string(),
+            body: Body {
+                id,
+                in_reply_to,
+                clock: Default::default(),
+                payload: (),
+            },
+        }
+    }
+
+    #[test]
+    fn levels_up_to_the_threshold_pass() -> anyhow::Result<()> {
+        assert!(passes(Level::Error, Level::Info));
+        assert!(passes(Level::Info, Level::Info));
+        assert!(!passes(Level::Debug, Level::Info));
+        assert!(passes(Level::Trace, Level::Trace));
+        assert!(!passes(Level::Error, Level::Off));
+        assert!(!passes(Level::Off, Level::Trace));
+
+        assert_eq!("DEBUG".parse::<Level>()?, Level::Debug);
+        assert!("loud".parse::<Level>().is_err());
+        assert_eq!("json".parse::<Format>()?, Format::Json);
+        Ok(())
+    }
+
+    #[test]
+    fn lines_are_json_or_human() -> anyhow::Result<()> {
+        let fields = || Map::from_iter([("src".into(), json!("c1")), ("n".into(), json!(2))]);
+
+        let json_line = line(Format::Json, 1.5, Level::Info, Some("n1"), "recv", fields());
+        assert_eq!(
+            serde_json::from_str::<Value>(&json_line)?,
+            json!({"time": 1.5, "level": "info", "node": "n1", "message": "recv", "src": "c1", "n": 2})
+        );
+        let human = line(
+            Format::Human,
+            1.5,
+            Level::Info,
+            Some("n1"),
+            "recv",
+            fields(),
+        );
+        assert_eq!(human, "1.500  INFO n1 recv n=2 src=c1");
+        let human = line(Format::Human, 1.5, Level::Warn, None, "oops", Map::new());
+        assert_eq!(human, "1.500  WARN - oops");
+        Ok(())
+    }
+
+    #[test]
+    fn replies_are_matched_to_requests_of_their_own_node() {
+        let (n1, n3) = (NodeTracer::new("n1"), NodeTracer::new("n3"));
+        let request = |src: &str| message(src, "n2", Some(1), None);
+        let reply = |from: &str, dst: &str| message(from, dst, None, Some(1));
+
+        // Both ask n2 with the same msg_id
+        assert_eq!(n1.latency(&request("n1"), Some("ping"), "n2", true), None);
+        assert_eq!(n3.latency(&request("n3"), Some("ping"), "n2", true), None);
+
+        assert_eq!(n1.latency(&reply("n4", "n1"), None, "n4", false), None);
+        assert!(n1.latency(&reply("n2", "n1"), None, "n2", false).is_some());
+        assert_eq!(n1.latency(&reply("n2", "n1"), None, "n2", false), None);
+        assert!(n3.latency(&reply("n2", "n3"), None, "n2", false).is_some());
+    }
+
+    #[test]
+    fn requests_taken_up_are_known_until_replied_to() {
+        let (n1, n3) = (NodeTracer::new("n1"), NodeTracer::new("n3"));
+        n1.received(&message("c1", "n1", Some(5), None), Some("read"));
+        assert_eq!(n1.request_kind("c1", 5).as_deref(), Some("read"));
+        assert_eq!(n3.request_kind("c1", 5), None);
+
+        let _entered = enter(Some(n1.clone()));
+        assert_eq!(request_kind("c1", 5).as_deref(), Some("read"));
+        sent(&message("n1", "c1", None, Some(5)), Some("read_ok"));
+        assert_eq!(n1.request_kind("c1", 5), None);
+    }
+
+    #[test]
+    fn the_innermost_entered_tracer_names_the_node() {
+        let node = || current().map(|tracer| tracer.0.node.clone());
+        assert_eq!(node(), None);
+        let outer = enter(Some(NodeTracer::new("n1")));
+        {
+            let _inner = enter(Some(NodeTracer::new("n2")));
+            assert_eq!(node().as_deref(), Some("n2"));
+        }
+        assert_eq!(node().as_deref(), Some("n1"));
+        drop(outer);
+        assert_eq!(node(), None);
+    }
+}
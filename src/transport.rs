@@ -0,0 +1,423 @@
+use crate::{codec, Codec};
+
+use anyhow::Context;
+use std::{
+    io::{BufRead, BufReader, LineWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+};
+
+// Where a node's messages come from and go to, framed by the transport's codec.
+// The reader moves to the input thread, the writer stays with the node.
+pub trait Transport {
+    fn split(self: Box<Self>) -> anyhow::Result<(Box<dyn BufRead + Send>, Box<dyn Write + Send>)>;
+
+    // How messages are encoded on this transport, JSON lines unless told otherwise
+    fn codec(&self) -> Codec {
+        Codec::JsonLines
+    }
+}
+
+// Picks a transport from an address:
+// - stdio
+// - tcp://host:port, connecting to whoever routes the messages
+// - tcp-listen://host:port, waiting for them to connect
+// - unix:///path/to.sock
+//
+// Socket transports take a codec both ends agree on, eg: tcp://host:port?codec=cbor
+// (see codec::by_name). Stdio is what Maelstrom speaks, so it stays JSON lines.
+pub fn connect(address: &str) -> anyhow::Result<Box<dyn Transport>> {
+    let (address, codec) = match address.split_once("?codec=") {
+        Some((address, codec)) => (address, codec::by_name(codec)?),
+        None => (address, Codec::JsonLines),
+    };
+    let (scheme, rest) = address.split_once("://").unwrap_or((address, ""));
+    Ok(match scheme {
+        "stdio" => {
+            anyhow::ensure!(codec.is_line_delimited(), "stdio only speaks JSON lines");
+            Box::new(Stdio)
+        }
+        "tcp" => Box::new(TcpTransport::connect(rest)?.with_codec(codec)),
+        "tcp-listen" => Box::new(TcpTransport::listen(rest)?.with_codec(codec)),
+        #[cfg(unix)]
+        "unix" => Box::new(UnixTransport::connect(rest)?.with_codec(codec)),
+        _ => anyhow::bail!("unsupported transport {}", address),
+    })
+}
+
+// Line delimited frames are handed over at their newline. Binary frames are
+// written whole by `Message::send`, so they go out unbuffered.
+fn frame_writer<W: Write + Send + 'static>(stream: W, codec: Codec) -> Box<dyn Write + Send> {
+    if codec.is_line_delimited() {
+        Box::new(LineWriter::new(stream))
+    } else {
+        Box::new(stream)
+    }
+}
+
+// What Maelstrom speaks: stdin and stdout of the node process
+pub struct Stdio;
+
+impl Transport for Stdio {
+    fn split(self: Box<Self>) -> anyhow::Result<(Box<dyn BufRead + Send>, Box<dyn Write + Send>)> {
+        Ok((
+            Box::new(BufReader::new(std::io::stdin())),
+            Box::new(std::io::stdout()),
+        ))
+    }
+}
+
+pub struct TcpTransport {
+    stream: TcpStream,
+    codec: Codec,
+}
+
+impl TcpTransport {
+    pub fn connect(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr).context("connect tcp transport")?;
+        Ok(Self::new(stream))
+    }
+
+    // Binds `addr` and waits for a single connection
+    pub fn listen(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).context("bind tcp transport")?;
+        let (stream, _) = listener.accept().context("accept tcp transport")?;
+        Ok(Self::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> Self {
+        // Messages are small and latency matters more than throughput
+        let _ = stream.set_nodelay(true);
+        Self {
+            stream,
+            codec: Codec::JsonLines,
+        }
+    }
+
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+}
+
+impl Transport for TcpTransport {
+    fn split(self: Box<Self>) -> anyhow::Result<(Box<dyn BufRead + Send>, Box<dyn Write + Send>)> {
+        let reader = self.stream.try_clone().context("clone tcp stream")?;
+        let writer = frame_writer(self.stream, self.codec);
+        Ok((Box::new(BufReader::new(reader)), writer))
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+}
+
+#[cfg(unix)]
+pub struct UnixTransport {
+    stream: std::os::unix::net::UnixStream,
+    codec: Codec,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    pub fn connect(path: impl AsRef<std::path::Path>) -> anyhow::Result<Self> {
+        let stream = std::os::unix::net::UnixStream::connect(path)
+            .context("connect unix socket transport")?;
+        Ok(Self::new(stream))
+    }
+
+    pub fn new(stream: std::os::unix::net::UnixStream) -> Self {
+        Self {
+            stream,
+            codec: Codec::JsonLines,
+        }
+    }
+
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    fn split(self: Box<Self>) -> anyhow::Result<(Box<dyn BufRead + Send>, Box<dyn Write + Send>)> {
+        let reader = self.stream.try_clone().context("clone unix stream")?;
+        let writer = frame_writer(self.stream, self.codec);
+        Ok((Box::new(BufReader::new(reader)), writer))
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+}
+
+// One end of an in-memory connection, each line written on one end is read
+// on the other. Dropping an end is seen as EOF by its peer.
+pub struct ChannelTransport {
+    rx: mpsc::Receiver<Vec<u8>>,
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl ChannelTransport {
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, a_rx) = mpsc::channel();
+        let (b_tx, b_rx) = mpsc::channel();
+        (Self { rx: a_rx, tx: b_tx }, Self { rx: b_rx, tx: a_tx })
+    }
+
+    // The two halves, for whoever drives the other end
+    pub fn into_parts(self) -> (ChannelReader, ChannelWriter) {
+        (
+            ChannelReader::new(self.rx),
+            ChannelWriter {
+                tx: self.tx,
+                line: Vec::new(),
+            },
+        )
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn split(self: Box<Self>) -> anyhow::Result<(Box<dyn BufRead + Send>, Box<dyn Write + Send>)> {
+        let (reader, writer) = self.into_parts();
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+pub struct ChannelReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    pub(crate) fn new(rx: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            rx,
+            line: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for ChannelReader {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.pos == self.line.len() {
+            // A disconnected peer leaves an empty buffer, which reads as EOF
+            self.line = self.rx.recv().unwrap_or_default();
+            self.pos = 0;
+        }
+        Ok(&self.line[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.line.len());
+    }
+}
+
+// Hands complete lines to the peer
+pub struct ChannelWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+    line: Vec<u8>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &b in buf {
+            self.line.push(b);
+            if b == b'\n' {
+                self.flush()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.line.is_empty() {
+            return Ok(());
+        }
+        self.tx
+            .send(std::mem::take(&mut self.line))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codec::Decoded, main_loop_with, Body, Event, Init, Message, Node, Output};
+
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::{net::Shutdown, thread};
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Payload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct Echo;
+
+    impl Node<(), Payload> for Echo {
+        fn from_init(
+            _state: (),
+            _init: Init,
+            _inject: mpsc::Sender<Event<Payload>>,
+        ) -> anyhow::Result<Self> {
+            Ok(Echo)
+        }
+
+        fn step(&mut self, input: Event<Payload>, output: &mut Output) -> anyhow::Result<()> {
+            let Event::Message(input) = input else {
+                return Ok(());
+            };
+            let mut reply = input.into_reply(None);
+            let Payload::Echo { echo } = reply.body.payload else {
+                return Ok(());
+            };
+            reply.body.payload = Payload::EchoOk { echo };
+            reply.send(output)
+        }
+    }
+
+    fn request(msg_id: usize, payload: Value) -> Message<Value> {
+        Message {
+            src: "c1".to_string(),
+            dst: "n1".to_string(),
+            body: Body {
+                id: Some(msg_id),
+                in_reply_to: None,
+                clock: Default::default(),
+                payload,
+            },
+        }
+    }
+
+    // Runs an echo node on the transport `node` makes, asks it to echo over
+    // `client` and returns the replies once `close` has ended the input
+    fn echo_over<C: Read + Write>(
+        node: impl FnOnce() -> anyhow::Result<Box<dyn Transport>> + Send + 'static,
+        mut client: C,
+        codec: Codec,
+        close: impl FnOnce(&C),
+    ) -> anyhow::Result<Vec<Message<Value>>> {
+        let node = thread::spawn(move || main_loop_with::<_, Echo, Payload, ()>((), node()?));
+
+        let init = json!({"type": "init", "node_id": "n1", "node_ids": ["n1"]});
+        let echo = json!({"type": "echo", "echo": "hi"});
+        for msg in [request(1, init), request(2, echo)] {
+            let mut frame = Vec::new();
+            codec.encode(&msg, &mut frame)?;
+            client.write_all(&frame)?;
+        }
+        close(&client);
+
+        let mut replies = Vec::new();
+        let mut reader = BufReader::new(client);
+        while let Some(decoded) = codec.decode::<Value>(&mut reader)? {
+            match decoded {
+                Decoded::Message(msg) | Decoded::Raw(msg) => replies.push(msg),
+                Decoded::Unknown(unknown) => anyhow::bail!("not a message: {}", unknown),
+            }
+        }
+        node.join().expect("node panicked")?;
+        Ok(replies)
+    }
+
+    fn assert_echoed(replies: &[Message<Value>]) {
+        let bodies: Vec<_> = replies
+            .iter()
+            .map(|reply| {
+                (
+                    reply.dst.as_str(),
+                    reply.body.in_reply_to,
+                    &reply.body.payload,
+                )
+            })
+            .collect();
+        assert_eq!(
+            bodies,
+            [
+                ("c1", Some(1), &json!({"type": "init_ok"})),
+                ("c1", Some(2), &json!({"type": "echo_ok", "echo": "hi"})),
+            ]
+        );
+    }
+
+    #[test]
+    fn echo_nodes_serve_over_tcp() -> anyhow::Result<()> {
+        for codec in [Codec::JsonLines, Codec::Cbor] {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let address = listener.local_addr()?;
+            let node = move || -> anyhow::Result<Box<dyn Transport>> {
+                let (stream, _) = listener.accept()?;
+                Ok(Box::new(TcpTransport::new(stream).with_codec(codec)))
+            };
+            let client = TcpStream::connect(address)?;
+            let replies = echo_over(node, client, codec, |client| {
+                let _ = client.shutdown(Shutdown::Write);
+            })?;
+            assert_echoed(&replies);
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn echo_nodes_serve_over_unix_sockets() -> anyhow::Result<()> {
+        for codec in [Codec::MessagePack, Codec::Bincode] {
+            let (node_end, client) = std::os::unix::net::UnixStream::pair()?;
+            let node = move || -> anyhow::Result<Box<dyn Transport>> {
+                Ok(Box::new(UnixTransport::new(node_end).with_codec(codec)))
+            };
+            let replies = echo_over(node, client, codec, |client| {
+                let _ = client.shutdown(Shutdown::Write);
+            })?;
+            assert_echoed(&replies);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn addresses_pick_the_transport_and_its_codec() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+
+        let tcp = connect(&format!("tcp://{}", address))?;
+        assert_eq!(tcp.codec(), Codec::JsonLines);
+        let tcp = connect(&format!("tcp://{}?codec=bincode", address))?;
+        assert_eq!(tcp.codec(), Codec::Bincode);
+        assert!(connect(&format!("tcp://{}?codec=xml", address)).is_err());
+
+        assert_eq!(connect("stdio")?.codec(), Codec::JsonLines);
+        assert_eq!(connect("stdio?codec=json")?.codec(), Codec::JsonLines);
+        let error = connect("stdio?codec=cbor").err().expect("stdio is JSON");
+        assert!(error.to_string().contains("JSON lines"), "{}", error);
+
+        assert!(connect("pigeon://n1").is_err());
+
+        #[cfg(unix)]
+        {
+            let path = std::env::temp_dir().join(format!("transport-{}.sock", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let _listener = std::os::unix::net::UnixListener::bind(&path)?;
+            let unix = connect(&format!("unix://{}?codec=msgpack", path.display()))?;
+            assert_eq!(unix.codec(), Codec::MessagePack);
+            std::fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,557 @@
+use crate::{Body, History, MaelstromError, Message, OpKind, RawMessage};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::{collections::HashMap, time::Duration};
+
+// The client workloads of the challenges, named like Maelstrom's
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Workload {
+    Echo,
+    UniqueIds,
+    Broadcast,
+    Counter,
+    Kafka,
+    Txn,
+}
+
+impl Workload {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Workload::Echo => "echo",
+            Workload::UniqueIds => "unique-ids",
+            Workload::Broadcast => "broadcast",
+            Workload::Counter => "g-counter",
+            Workload::Kafka => "kafka",
+            Workload::Txn => "txn-rw-register",
+        }
+    }
+}
+
+impl std::str::FromStr for Workload {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "echo" => Ok(Workload::Echo),
+            "unique-ids" => Ok(Workload::UniqueIds),
+            "broadcast" => Ok(Workload::Broadcast),
+            "g-counter" | "counter" => Ok(Workload::Counter),
+            "kafka" => Ok(Workload::Kafka),
+            "txn-rw-register" | "txn" => Ok(Workload::Txn),
+            _ => anyhow::bail!("unknown workload {}", s),
+        }
+    }
+}
+
+// A client operation: `f` and `value` go in the history, `payload` on the wire
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub f: String,
+    pub value: Value,
+    pub payload: Value,
+}
+
+impl Request {
+    fn new(f: &str, value: Value, payload: Value) -> Self {
+        Self {
+            f: f.to_string(),
+            value,
+            payload,
+        }
+    }
+
+    // Setup requests aren't client operations and stay out of the history
+    pub fn is_operation(&self) -> bool {
+        self.f != "topology"
+    }
+
+    // Turns the reply into the completion recorded in the history
+    pub fn complete(&self, reply: &RawMessage) -> (OpKind, Value) {
+        if let Some(error) = MaelstromError::from_reply(reply) {
+            let kind = if error.code.is_definite() {
+                OpKind::Fail
+            } else {
+                OpKind::Info
+            };
+            return (kind, self.value.clone());
+        }
+
+        let body = &reply.body.payload;
+        let value = match self.f.as_str() {
+            "echo" => body["echo"].clone(),
+            "generate" => body["id"].clone(),
+            "read" if body.get("messages").is_some() => body["messages"].clone(),
+            "read" => body["value"].clone(),
+            // [key, msg] completes as [key, [offset, msg]]
+            "send" => json!([self.value[0], [body["offset"], self.value[1]]]),
+            "poll" => body["msgs"].clone(),
+            "list_committed_offsets" => body["offsets"].clone(),
+            "txn" => body["txn"].clone(),
+            _ => self.value.clone(),
+        };
+        (OpKind::Ok, value)
+    }
+}
+
+// Produces random client operations shaped like Maelstrom's for a workload
+pub struct Generator {
+    workload: Workload,
+    rng: StdRng,
+    node_ids: Vec<String>,
+    next_message: usize,
+    // Kafka offsets seen per key, so polls and commits ask for plausible ones
+    offsets: HashMap<String, usize>,
+    key_count: usize,
+}
+
+impl Generator {
+    pub fn new(workload: Workload, seed: u64, node_ids: &[String]) -> Self {
+        Self {
+            workload,
+            rng: StdRng::seed_from_u64(seed),
+            node_ids: node_ids.to_vec(),
+            next_message: 0,
+            offsets: HashMap::new(),
+            key_count: 8,
+        }
+    }
+
+    pub fn workload(&self) -> Workload {
+        self.workload
+    }
+
+    // How many keys kafka and txn operations spread over
+    pub fn with_key_count(mut self, key_count: usize) -> Self {
+        self.key_count = key_count.max(1);
+        self
+    }
+
+    // Requests every node needs before the workload starts, eg: broadcast topology
+    pub fn setup(&mut self) -> Vec<(String, Request)> {
+        if self.workload != Workload::Broadcast {
+            return Vec::new();
+        }
+
+        let topology = grid_topology(&self.node_ids);
+        let request = Request::new(
+            "topology",
+            Value::Null,
+            json!({"type": "topology", "topology": topology}),
+        );
+
+        self.node_ids
+            .iter()
+            .map(|node| (node.clone(), request.clone()))
+            .collect()
+    }
+
+    pub fn next_request(&mut self) -> Request {
+        match self.workload {
+            Workload::Echo => {
+                let echo = format!("Please echo {}", self.rng.gen_range(0..128));
+                Request::new("echo", json!(echo), json!({"type": "echo", "echo": echo}))
+            }
+            Workload::UniqueIds => {
+                Request::new("generate", Value::Null, json!({"type": "generate"}))
+            }
+            Workload::Broadcast => {
+                if self.rng.gen_bool(0.5) {
+                    return self.read();
+                }
+                let message = self.next_message;
+                self.next_message += 1;
+                Request::new(
+                    "broadcast",
+                    json!(message),
+                    json!({"type": "broadcast", "message": message}),
+                )
+            }
+            Workload::Counter => {
+                if self.rng.gen_bool(0.5) {
+                    return self.read();
+                }
+                let delta = self.rng.gen_range(1..=5);
+                Request::new("add", json!(delta), json!({"type": "add", "delta": delta}))
+            }
+            Workload::Kafka => self.kafka(),
+            Workload::Txn => self.txn(),
+        }
+    }
+
+    // Requests sent to every node once the workload is over and the cluster
+    // had time to recover, so checkers can see where it converged
+    pub fn final_requests(&mut self) -> Vec<(String, Request)> {
+        let request = match self.workload {
+            Workload::Broadcast | Workload::Counter => self.read(),
+            Workload::Kafka => {
+                let offsets: HashMap<String, usize> =
+                    self.keys().into_iter().map(|key| (key, 0)).collect();
+                Request::new(
+                    "poll",
+                    json!(offsets),
+                    json!({"type": "poll", "offsets": offsets}),
+                )
+            }
+            Workload::Echo | Workload::UniqueIds | Workload::Txn => return Vec::new(),
+        };
+
+        self.node_ids
+            .iter()
+            .map(|node| (node.clone(), request.clone()))
+            .collect()
+    }
+
+    // Remembers the offsets kafka replies hand out
+    pub fn observe(&mut self, request: &Request, value: &Value) {
+        if request.f == "send" {
+            if let (Some(key), Some(offset)) = (value[0].as_str(), value[1][0].as_u64()) {
+                let seen = self.offsets.entry(key.to_string()).or_default();
+                *seen = (*seen).max(offset as usize);
+            }
+        }
+    }
+
+    fn read(&self) -> Request {
+        Request::new("read", Value::Null, json!({"type": "read"}))
+    }
+
+    fn keys(&self) -> Vec<String> {
+        (0..self.key_count).map(|k| k.to_string()).collect()
+    }
+
+    fn kafka(&mut self) -> Request {
+        let keys = self.keys();
+        let key = keys
+            .choose(&mut self.rng)
+            .expect("at least one key")
+            .clone();
+        let seen = |key: &String| self.offsets.get(key).copied().unwrap_or(0);
+
+        match self.rng.gen_range(0..10) {
+            0..=4 => {
+                let msg = self.next_message;
+                self.next_message += 1;
+                Request::new(
+                    "send",
+                    json!([key, msg]),
+                    json!({"type": "send", "key": key, "msg": msg}),
+                )
+            }
+            5..=7 => {
+                let offsets = HashMap::from([(key.clone(), self.rng.gen_range(0..=seen(&key)))]);
+                Request::new(
+                    "poll",
+                    json!(offsets),
+                    json!({"type": "poll", "offsets": offsets}),
+                )
+            }
+            8 => {
+                let offsets = HashMap::from([(key.clone(), seen(&key))]);
+                Request::new(
+                    "commit_offsets",
+                    json!(offsets),
+                    json!({"type": "commit_offsets", "offsets": offsets}),
+                )
+            }
+            _ => Request::new(
+                "list_committed_offsets",
+                json!([key]),
+                json!({"type": "list_committed_offsets", "keys": [key]}),
+            ),
+        }
+    }
+
+    // Up to four micro-ops, writes get values unique across the whole run
+    fn txn(&mut self) -> Request {
+        let len = self.rng.gen_range(1..=4);
+        let txn: Vec<Value> = (0..len)
+            .map(|_| {
+                let key = self.rng.gen_range(0..self.key_count);
+                if self.rng.gen_bool(0.5) {
+                    json!(["r", key, null])
+                } else {
+                    self.next_message += 1;
+                    json!(["w", key, self.next_message])
+                }
+            })
+            .collect();
+        Request::new("txn", json!(txn), json!({"type": "txn", "txn": txn}))
+    }
+}
+
+// Maelstrom's default topology: nodes laid out on a square grid, each
+// neighbouring the ones next to it
+pub(crate) fn grid_topology(node_ids: &[String]) -> HashMap<String, Vec<String>> {
+    let width = (node_ids.len() as f64).sqrt().ceil().max(1.0) as usize;
+    node_ids
+        .iter()
+        .enumerate()
+        .map(|(i, node)| {
+            let neighbours = node_ids
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| {
+                    let (row, col) = (i / width, i % width);
+                    let (other_row, other_col) = (j / width, j % width);
+                    row.abs_diff(other_row) + col.abs_diff(other_col) == 1
+                })
+                .map(|(_, other)| other.clone())
+                .collect();
+            (node.clone(), neighbours)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorkloadOptions {
+    // Operations per second, across all clients
+    pub rate: f64,
+    // Clients issuing operations, each with at most one in flight
+    pub concurrency: usize,
+    pub time_limit: Duration,
+    // Ops without a reply by then are recorded as info
+    pub timeout: Duration,
+    // Pause between the end of the workload and the final requests
+    pub recovery: Duration,
+}
+
+impl Default for WorkloadOptions {
+    fn default() -> Self {
+        Self {
+            rate: 10.0,
+            concurrency: 2,
+            time_limit: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+            recovery: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Setup,
+    Main,
+    // Waiting for the ops still in flight
+    Draining,
+    Recovering(Duration),
+    Final,
+    Done,
+}
+
+struct InFlight {
+    msg_id: usize,
+    request: Request,
+    sent_at: Duration,
+}
+
+struct Client {
+    id: String,
+    // History process; it changes after an op times out, as the client might
+    // still be waiting on it
+    process: usize,
+    in_flight: Option<InFlight>,
+    // Requests this client still has to send
+    queue: Vec<(String, Request)>,
+}
+
+// Runs a workload against a cluster, whatever the transport: it is told what
+// time it is and which replies arrived, and hands back the requests to send.
+// Clients are named c1, c2.. and client i talks to node i modulo the cluster size.
+pub struct Driver {
+    generator: Generator,
+    options: WorkloadOptions,
+    node_ids: Vec<String>,
+    clients: Vec<Client>,
+    history: History,
+    phase: Phase,
+    next_msg_id: usize,
+    next_invoke: Duration,
+    next_client: usize,
+}
+
+impl Driver {
+    pub fn new(generator: Generator, node_ids: &[String], options: WorkloadOptions) -> Self {
+        let concurrency = options.concurrency.max(1);
+        let clients = (0..concurrency)
+            .map(|i| Client {
+                id: format!("c{}", i + 1),
+                process: i,
+                in_flight: None,
+                queue: Vec::new(),
+            })
+            .collect();
+
+        let mut driver = Self {
+            generator,
+            options: WorkloadOptions {
+                concurrency,
+                ..options
+            },
+            node_ids: node_ids.to_vec(),
+            clients,
+            history: History::new(),
+            phase: Phase::Setup,
+            next_msg_id: 1,
+            next_invoke: Duration::ZERO,
+            next_client: 0,
+        };
+        let setup = driver.generator.setup();
+        driver.assign(setup);
+        driver
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn into_history(self) -> History {
+        self.history
+    }
+
+    pub fn client_ids(&self) -> impl Iterator<Item = &str> {
+        self.clients.iter().map(|c| c.id.as_str())
+    }
+
+    // Advances to `now`: times out stale ops and returns the requests to send.
+    // `now` is measured from the start of the run.
+    pub fn poll(&mut self, now: Duration) -> Vec<Message<Value>> {
+        for client in &mut self.clients {
+            let Some(op) = &client.in_flight else {
+                continue;
+            };
+            if now.saturating_sub(op.sent_at) < self.options.timeout {
+                continue;
+            }
+            let op = client.in_flight.take().expect("op in flight");
+            if op.request.is_operation() {
+                self.history
+                    .info(client.process, &op.request.f, op.request.value, now);
+                client.process += self.options.concurrency;
+            }
+        }
+
+        let settled = self
+            .clients
+            .iter()
+            .all(|c| c.in_flight.is_none() && c.queue.is_empty());
+
+        match self.phase {
+            Phase::Setup if settled => {
+                self.phase = Phase::Main;
+                self.next_invoke = now;
+            }
+            Phase::Main if now >= self.options.time_limit => self.phase = Phase::Draining,
+            Phase::Main => {
+                let interval = Duration::from_secs_f64(1.0 / self.options.rate.max(f64::EPSILON));
+                while self.next_invoke <= now {
+                    self.next_invoke += interval;
+                    if let Some(c) = self.idle_client() {
+                        let node = self.node_ids[c % self.node_ids.len()].clone();
+                        let request = self.generator.next_request();
+                        self.clients[c].queue.push((node, request));
+                    }
+                }
+            }
+            Phase::Draining if settled => {
+                self.phase = Phase::Recovering(now + self.options.recovery);
+            }
+            Phase::Recovering(until) if now >= until => {
+                let requests = self.generator.final_requests();
+                self.assign(requests);
+                self.phase = Phase::Final;
+            }
+            Phase::Final if settled => self.phase = Phase::Done,
+            _ => {}
+        }
+
+        self.send_queued(now)
+    }
+
+    // Records the completion of a request; anything else is ignored
+    pub fn receive(&mut self, now: Duration, reply: &RawMessage) {
+        let Some(client) = self.clients.iter_mut().find(|c| c.id == reply.dst) else {
+            return;
+        };
+        let Some(op) = &client.in_flight else {
+            return;
+        };
+        if reply.body.in_reply_to != Some(op.msg_id) {
+            return;
+        }
+        let op = client.in_flight.take().expect("op in flight");
+
+        if !op.request.is_operation() {
+            return;
+        }
+        let (kind, value) = op.request.complete(reply);
+        self.generator.observe(&op.request, &value);
+        let process = client.process;
+        match kind {
+            OpKind::Ok => self.history.ok(process, &op.request.f, value, now),
+            OpKind::Fail => self.history.fail(process, &op.request.f, value, now),
+            _ => {
+                self.history.info(process, &op.request.f, value, now);
+                client.process += self.options.concurrency;
+            }
+        }
+    }
+
+    // Spreads requests over the clients, which send them one at a time
+    fn assign(&mut self, requests: Vec<(String, Request)>) {
+        for (i, request) in requests.into_iter().enumerate() {
+            let c = i % self.clients.len();
+            self.clients[c].queue.push(request);
+        }
+    }
+
+    fn idle_client(&mut self) -> Option<usize> {
+        let n = self.clients.len();
+        let c = (0..n)
+            .map(|i| (self.next_client + i) % n)
+            .find(|&c| self.clients[c].in_flight.is_none() && self.clients[c].queue.is_empty())?;
+        self.next_client = (c + 1) % n;
+        Some(c)
+    }
+
+    fn send_queued(&mut self, now: Duration) -> Vec<Message<Value>> {
+        let is_final = self.phase == Phase::Final;
+        let mut messages = Vec::new();
+        for client in &mut self.clients {
+            if client.in_flight.is_some() || client.queue.is_empty() {
+                continue;
+            }
+            let (node, request) = client.queue.remove(0);
+            let msg_id = self.next_msg_id;
+            self.next_msg_id += 1;
+
+            if is_final {
+                self.history
+                    .invoke_final(client.process, &request.f, request.value.clone(), now);
+            } else if request.is_operation() {
+                self.history
+                    .invoke(client.process, &request.f, request.value.clone(), now);
+            }
+            messages.push(Message {
+                src: client.id.clone(),
+                dst: node,
+                body: Body {
+                    id: Some(msg_id),
+                    in_reply_to: None,
+                    clock: Default::default(),
+                    payload: request.payload.clone(),
+                },
+            });
+            client.in_flight = Some(InFlight {
+                msg_id,
+                request,
+                sent_at: now,
+            });
+        }
+        messages
+    }
+}